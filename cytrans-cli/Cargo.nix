@@ -735,6 +735,10 @@ rec {
             packageId = "serde";
             features = [ "derive" ];
           }
+          {
+            name = "serde_json";
+            packageId = "serde_json";
+          }
           {
             name = "strum";
             packageId = "strum 0.24.1";
@@ -776,6 +780,10 @@ rec {
             packageId = "isahc";
             optional = true;
           }
+          {
+            name = "libc";
+            packageId = "libc";
+          }
           {
             name = "rustyline";
             packageId = "rustyline";
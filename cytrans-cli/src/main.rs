@@ -4,18 +4,139 @@
 #[cfg(feature="jellyfin")]
 mod jellyfin;
 
-use std::{ffi::OsString, fmt::Display, os::unix::process::CommandExt as _, path::PathBuf, str::FromStr};
+use std::{ffi::OsString, fmt::Display, os::unix::process::CommandExt as _, path::{Path, PathBuf}, str::FromStr};
 
 use clap::Parser;
 use console_menu::{Menu, MenuOption, MenuProps};
-use cytrans::{codecs::get_capabilities, ffprobe::{ffprobe, Track, TrackType}, options::{AudioCodec, TrackOptions, TranscodeArgs, VideoCodec}, transcode::build_ffmpeg_command};
+use cytrans::{codecs::{check_feature_gates, check_input, check_track_options, get_capabilities, Capabilities, InputReport}, config::{parse_env_var, FfmpegConfig}, filenames::FilenameTemplates, cytube_structs::CytubeVideo, metadata::{scan_output_directory, ManifestError, MetadataManifest, ToRemove, CYTUBE_MANIFEST_FILENAME}, staging::StagedOutput, shell_words, ffprobe::{ffprobe, ffprobe_with_input_args, Track, TrackType}, options::{parse_language, parse_timestamp, AudioCodec, MetadataOverrides, AudioVisual, VisualBackground, SubtitleOptions, TrackOptions, TranscodeArgs, Trim, TrimEnd, VideoCodec}, concat::{concat_probe, write_concat_list}, transcode::{build_concat_command, build_ffmpeg_command, fallback_video_encoder, default_subtitle_track, generate_thumbnail, get_defaults}, defaults::{find_strategy, DefaultsPolicy, DefaultsStrategy, Standard, STRATEGIES}, sidecars::find_sidecars};
 
 #[derive(clap::Parser)]
-#[command(version, about)]
+#[command(version, about, subcommand_negates_reqs=true, args_conflicts_with_subcommands=true)]
 struct Args {
-    input_path_or_url: OsString,
-    output_directory: PathBuf,
-    url_prefix: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(required=true)]
+    input_path_or_url: Option<OsString>,
+    #[arg(required=true)]
+    output_directory: Option<PathBuf>,
+    #[arg(required=true)]
+    url_prefix: Option<String>,
+    /// Path to the ffmpeg binary to use
+    #[arg(long, global=true, default_value="ffmpeg")]
+    ffmpeg: PathBuf,
+    /// Path to the ffprobe binary to use
+    #[arg(long, global=true, default_value="ffprobe")]
+    ffprobe: PathBuf,
+    /// Extra environment variable to set when running ffmpeg and ffprobe, as KEY=VALUE.  May be
+    /// specified more than once.
+    #[arg(long="ffmpeg-env", global=true, value_name="KEY=VALUE", value_parser=parse_env_var)]
+    ffmpeg_env: Vec<(OsString, OsString)>,
+    /// Number of threads ffmpeg should use
+    #[arg(long, global=true)]
+    threads: Option<u32>,
+    /// Write straight into the output directory rather than staging everything and moving it
+    /// into place once ffmpeg finishes.  People can start watching before the transcode is done,
+    /// but a failed transcode leaves half-finished files behind.
+    #[arg(long)]
+    unstaged: bool,
+    /// Another file to play after the input, e.g. the second half of a movie that comes on two
+    /// CDs.  May be specified more than once.  Every file needs the same tracks as the input.
+    #[arg(long, value_name="FILE")]
+    concat: Vec<PathBuf>,
+    /// Only keep the part of the input from here on, e.g. 90, 1:30 or 1:01:30.5
+    #[arg(long, value_name="TIMESTAMP", value_parser=parse_timestamp)]
+    start: Option<f32>,
+    /// Only keep the part of the input up to here
+    #[arg(long, value_name="TIMESTAMP", value_parser=parse_timestamp, conflicts_with="duration")]
+    end: Option<f32>,
+    /// Only keep this much of the input, counting from --start
+    #[arg(long, value_name="TIMESTAMP", value_parser=parse_timestamp)]
+    duration: Option<f32>,
+    /// For inputs with no video: make a video out of the cover art embedded in the input
+    #[arg(long, conflicts_with="background_image")]
+    cover_art: bool,
+    /// For inputs with no video: make a video out of this image
+    #[arg(long, value_name="FILE")]
+    background_image: Option<PathBuf>,
+    /// For inputs with no video: draw the audio's waveform over the cover art or background
+    /// image, or over black if there isn't one
+    #[arg(long)]
+    waveform: bool,
+    /// JSON file of track preferences (languages, commentary, how many tracks) to pick the
+    /// starting audio and subtitle tracks with.  Without one (or --defaults-strategy), no audio
+    /// tracks start out selected.
+    #[arg(long, value_name="FILE")]
+    defaults_policy: Option<PathBuf>,
+    /// How to pick codecs for the starting tracks: standard, av1, copy or compatible.  With one
+    /// of these you don't get asked about the video encoder either.
+    #[arg(long, value_name="NAME", value_parser=parse_strategy)]
+    defaults_strategy: Option<&'static dyn DefaultsStrategy>,
+    /// Extra global argument for ffmpeg, e.g. --ffmpeg-arg=-loglevel --ffmpeg-arg=warning.  May
+    /// be specified more than once.
+    #[arg(long, value_name="ARG", allow_hyphen_values=true)]
+    ffmpeg_arg: Vec<OsString>,
+    /// Extra argument for the input file, e.g. --input-arg=-analyzeduration --input-arg=100M.
+    /// May be specified more than once.
+    #[arg(long, value_name="ARG", allow_hyphen_values=true)]
+    input_arg: Vec<OsString>,
+    /// Extra argument for every output file.  May be specified more than once.
+    #[arg(long, value_name="ARG", allow_hyphen_values=true)]
+    output_arg: Vec<OsString>,
+    /// Template for naming video files.  Can use {title}, {index}, {quality}, {codec} and {lang}.
+    #[arg(long, value_name="TEMPLATE")]
+    video_filename: Option<String>,
+    /// Template for naming audio files.  Can use {title}, {index}, {codec} and {lang}.
+    #[arg(long, value_name="TEMPLATE")]
+    audio_filename: Option<String>,
+    /// Template for naming subtitle files.  Can use {title}, {index}, {codec} and {lang}.
+    #[arg(long, value_name="TEMPLATE")]
+    subtitle_filename: Option<String>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Check the manifest in an output directory, or a Cytube manifest that has already been
+    /// published, against the rules Cytube enforces on custom media
+    Validate {
+        /// An output directory, or a Cytube manifest JSON file
+        path: PathBuf,
+        /// The URL prefix the output directory's files are served under.  Not needed when
+        /// checking a JSON file.
+        url_prefix: Option<String>,
+        /// Also run ffprobe on every file to check it matches what the manifest says
+        #[arg(long)]
+        probe_files: bool,
+    },
+    /// Delete files from an output directory and update its manifests to match
+    Remove {
+        output_directory: PathBuf,
+        url_prefix: String,
+        /// Names of the files to delete, relative to the output directory
+        #[arg(required=true)]
+        files: Vec<String>,
+    },
+}
+
+fn parse_strategy(s: &str) -> Result<&'static dyn DefaultsStrategy, String> {
+    find_strategy(s).ok_or_else(|| {
+        let names = STRATEGIES.iter().map(|x| x.name()).intersperse(", ").collect::<String>();
+        format!("expected one of {}", names)
+    })
+}
+
+impl Args {
+    fn ffmpeg_config(&self) -> FfmpegConfig {
+        let mut config = FfmpegConfig {
+            ffmpeg: self.ffmpeg.clone(),
+            ffprobe: self.ffprobe.clone(),
+            env: self.ffmpeg_env.clone(),
+            global_args: Vec::new(),
+        };
+        if let Some(threads) = self.threads {
+            config.set_threads(threads);
+        }
+        config
+    }
 }
 
 #[derive(strum::EnumMessage, strum::EnumIter)]
@@ -24,6 +145,12 @@ enum MainMenuAction {
     VideoTracks,
     #[strum(message="Audio tracks")]
     AudioTracks,
+    #[strum(message="Subtitle tracks")]
+    SubtitleTracks,
+    #[strum(message="Burn in subtitles")]
+    BurnSubtitles,
+    #[strum(message="Extra ffmpeg args")]
+    FfmpegArgs,
     #[strum(message="Title")]
     Title,
     #[strum(message="Done, launch ffmpeg")]
@@ -47,14 +174,58 @@ impl<T> EnumMenu for T where T: strum::EnumMessage + strum::IntoEnumIterator {
 
 fn main() {
     let args = Args::parse();
+    let ffmpeg_config = args.ffmpeg_config();
 
-    if !args.output_directory.is_dir() {
-        std::fs::create_dir(&args.output_directory).expect("Error creating output directory");
+    match args.command {
+        Some(Command::Validate { path, url_prefix, probe_files }) => validate(&ffmpeg_config, &path, url_prefix.as_deref(), probe_files),
+        Some(Command::Remove { output_directory, url_prefix, files }) => remove(&ffmpeg_config, &output_directory, &url_prefix, files),
+        None => {},
+    }
+    let (Some(input_path_or_url), Some(output_directory), Some(url_prefix)) = (args.input_path_or_url, args.output_directory, args.url_prefix) else {
+        unreachable!("clap makes these mandatory when there's no subcommand");
+    };
+
+    let filenames = FilenameTemplates::with_overrides(args.video_filename, args.audio_filename, args.subtitle_filename).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+
+    let policy = args.defaults_policy.as_deref().map(|path| DefaultsPolicy::load(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path.display(), e);
+        std::process::exit(2);
+    }));
+
+    let trim = Trim {
+        start: args.start,
+        end: args.end.map(TrimEnd::At).or(args.duration.map(TrimEnd::Duration)),
+    };
+
+    let capabilities = match get_capabilities(&ffmpeg_config) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error detecting ffmpeg capabilities: {}", e);
+            std::process::exit(1);
+        },
+    };
+    let mut ffprobe_result = ffprobe_with_input_args(&ffmpeg_config, &input_path_or_url, &args.input_arg).expect("Error running ffprobe");
+    // the thumbnail comes from the first file even if there are more
+    let first_duration = ffprobe_result.duration;
+    let mut concat_inputs = vec![(PathBuf::from(&input_path_or_url), ffprobe_result.duration)];
+    if !args.concat.is_empty() {
+        let mut results = vec![ffprobe_result];
+        for path in args.concat.iter() {
+            let result = ffprobe_with_input_args(&ffmpeg_config, path, &args.input_arg).expect("Error running ffprobe");
+            concat_inputs.push((path.clone(), result.duration));
+            results.push(result);
+        }
+        ffprobe_result = concat_probe(&results).unwrap_or_else(|e| {
+            eprintln!("Can't join these files: {}", e);
+            std::process::exit(1);
+        });
     }
-    let mut ffprobe_result = ffprobe(&args.input_path_or_url).expect("Error running ffprobe");
 
     #[cfg(feature="jellyfin")]
-    if let Some(jf_title) = jellyfin::get_jellyfin_title(&args.input_path_or_url) {
+    if let Some(jf_title) = jellyfin::get_jellyfin_title(&input_path_or_url) {
         if let Some(ff_title) = &ffprobe_result.title {
             let mut menu = console_menu::Menu::new(
                 vec![
@@ -80,7 +251,17 @@ fn main() {
         }
     }
 
-    let video_tracks = ffprobe_result.tracks.iter().filter(|track| track.kind == TrackType::Video).collect::<Vec<_>>();
+    let input_report = check_input(&ffprobe_result, &capabilities);
+    if let Some(problem) = input_report.demux_problem() {
+        eprintln!("Can't transcode {}: {}", input_path_or_url.to_string_lossy(), problem);
+        std::process::exit(1);
+    }
+    if let Err(e) = trim.validate(ffprobe_result.duration) {
+        eprintln!("Can't trim {}: {}", input_path_or_url.to_string_lossy(), e);
+        std::process::exit(2);
+    }
+
+    let video_tracks = ffprobe_result.tracks.iter().filter(|track| track.is_valid_video_track()).collect::<Vec<_>>();
 
     let video_track = match video_tracks.len() {
         0 => None,
@@ -113,19 +294,17 @@ fn main() {
 
 
     if let Some(ref video) = video_track {
-        if let Some((codec, encoder)) = choose_encoder("Choose video encoder", cytrans::codecs::get_video_encoders(), Some(&video.codec)) {
-            video_tracks.push(TrackOptions {
-                track: video,
-                codec,
-                encoder: encoder.into(),
-                extra_ffmpeg_args: Vec::new(),
-                bitrate: None,
-            });
+        let choice = match args.defaults_strategy {
+            Some(strategy) => Some(strategy.video_encoder(video, &capabilities)),
+            None => choose_encoder("Choose video encoder", &capabilities.video_encoders, Some(&video.codec)).map(|(codec, encoder)| (codec, encoder.to_string())),
+        };
+        if let Some((codec, encoder)) = choice {
+            video_tracks.push(TrackOptions::new(video, codec, encoder));
         }
     }
 
     let mut title = ffprobe_result.title.clone().unwrap_or_else(|| {
-        let filename = args.input_path_or_url.to_string_lossy();
+        let filename = input_path_or_url.to_string_lossy();
         let mut out = &filename[filename.rfind('/').map(|x|x+1).unwrap_or(0)..];
         if filename.starts_with("http") {
             if let Some(pos) = out.find('?') {
@@ -134,7 +313,61 @@ fn main() {
         }
         out.to_string()
     });
-    let extra_ffmpeg_args = Vec::new(); // TODO add a way to specify extra ffmpeg args
+
+    // subtitle files next to the input.  not when joining files, since they'd only line up with
+    // the first one.
+    let sidecars = if args.concat.is_empty() { find_sidecars(Path::new(&input_path_or_url)).unwrap_or_default() } else { Vec::new() };
+    let sidecar_probes = sidecars.into_iter().filter_map(|sidecar| match ffprobe(&ffmpeg_config, &sidecar.path) {
+        Ok(mut result) => {
+            result.tracks.retain(|x| x.is_valid_subtitle_track());
+            result.tracks.truncate(1);
+            if let Some(track) = result.tracks.first_mut() {
+                track.language = track.language.or(sidecar.language);
+            }
+            Some((sidecar.path, result))
+        },
+        Err(e) => {
+            eprintln!("Warning: can't read {}: {}", sidecar.path.display(), e);
+            None
+        },
+    }).collect::<Vec<_>>();
+
+    // every text subtitle track there is, from the input and from sidecars.  they all start out
+    // included.  bitmap ones can't be turned into WebVTT, so they can only be burned in.
+    let subtitle_sources = ffprobe_result.tracks.iter()
+        .filter(|x| x.is_valid_subtitle_track())
+        .map(SubtitleOptions::new)
+        .chain(sidecar_probes.iter().filter_map(|(path, result)| result.tracks.first().map(|track| SubtitleOptions {
+            file: Some(path.clone()),
+            ..SubtitleOptions::new(track)
+        })))
+        .collect::<Vec<_>>();
+    let bitmap_subtitles = ffprobe_result.tracks.iter()
+        .filter(|x| x.kind == TrackType::Subtitle && !x.is_valid_subtitle_track())
+        .collect::<Vec<_>>();
+    let mut subtitles = subtitle_sources.clone();
+    let mut burn_subtitles = None;
+
+    if policy.is_some() || args.defaults_strategy.is_some() {
+        let policy = policy.unwrap_or_default();
+        let strategy = args.defaults_strategy.unwrap_or(&Standard);
+        let defaults = get_defaults(&ffprobe_result, Path::new(&input_path_or_url), &capabilities, &policy, strategy);
+        audio_tracks = defaults.audio_tracks;
+        // get_defaults() doesn't know about sidecars, so they stay as they are.  the embedded
+        // tracks it picked go first, in its order, and it's already picked the default one to go
+        // with the audio it picked.
+        subtitles.retain(|x| x.file.is_some());
+        for (n, chosen) in defaults.subtitle_tracks.into_iter().enumerate() {
+            subtitles.insert(n, chosen);
+        }
+    } else {
+        pick_default_subtitle(&mut subtitles, &audio_tracks);
+    }
+    // once someone picks the default subtitles themselves, changing the audio leaves them be
+    let mut default_subtitle_by_hand = false;
+    let mut extra_ffmpeg_args = args.ffmpeg_arg;
+    let mut input_ffmpeg_args = args.input_arg;
+    let mut output_ffmpeg_args = args.output_arg;
     
 
     let mut main_menu = Menu::new(
@@ -150,17 +383,36 @@ fn main() {
     loop {
         match main_menu.show() {
             Some(MainMenuAction::VideoTracks) => {
-                show_tracks_menu(&mut video_tracks, video_track.as_slice(), &mut line_editor);
+                show_tracks_menu(&mut video_tracks, video_track.as_slice(), &capabilities, &mut line_editor);
             },
             Some(MainMenuAction::AudioTracks) => {
-                show_tracks_menu(&mut audio_tracks, &input_audio_tracks, &mut line_editor);
+                show_tracks_menu(&mut audio_tracks, &input_audio_tracks, &capabilities, &mut line_editor);
+                if !default_subtitle_by_hand {
+                    pick_default_subtitle(&mut subtitles, &audio_tracks);
+                }
+            },
+            Some(MainMenuAction::SubtitleTracks) => {
+                let default_of = |subtitles: &[SubtitleOptions]| subtitles.iter().find(|x| x.default).map(|x| (x.track.index, x.file.clone()));
+                let before = default_of(&subtitles);
+                show_tracks_menu(&mut subtitles, &subtitle_sources, &capabilities, &mut line_editor);
+                default_subtitle_by_hand |= default_of(&subtitles) != before;
+            },
+            Some(MainMenuAction::BurnSubtitles) => {
+                choose_burn_subtitles(&bitmap_subtitles, &mut burn_subtitles);
+            },
+            Some(MainMenuAction::FfmpegArgs) => {
+                job_args_menu(&mut extra_ffmpeg_args, &mut input_ffmpeg_args, &mut output_ffmpeg_args, &mut line_editor);
             },
             Some(MainMenuAction::Title) => {
                 if let Ok(new_title) = line_editor.readline_with_initial("Title: ", (&title,"")) {
                     title = new_title;
                 }
             },
-            Some(MainMenuAction::Go) => break,
+            Some(MainMenuAction::Go) => {
+                if check_tracks(&ffmpeg_config, &capabilities, &input_report, &video_tracks, &audio_tracks, &[&extra_ffmpeg_args, &input_ffmpeg_args, &output_ffmpeg_args], burn_subtitles) {
+                    break;
+                }
+            },
             None => {
                 println!("User exited from main menu, not running ffmpeg.");
                 return;
@@ -168,30 +420,278 @@ fn main() {
         }
     }
 
+    let visual = if !video_tracks.is_empty() || !(args.cover_art || args.background_image.is_some() || args.waveform) {
+        None
+    } else {
+        let background = if let Some(path) = args.background_image {
+            VisualBackground::Image(path)
+        } else if args.cover_art {
+            match ffprobe_result.tracks.iter().find(|x| x.attached_pic) {
+                Some(track) => VisualBackground::CoverArt(track.index),
+                None => {
+                    eprintln!("{} has no cover art.", input_path_or_url.to_string_lossy());
+                    std::process::exit(2);
+                },
+            }
+        } else {
+            VisualBackground::Black
+        };
+        let (codec, encoder) = fallback_video_encoder(&capabilities);
+        Some(AudioVisual { background, waveform: args.waveform, codec, encoder })
+    };
+
     let transcode_args = TranscodeArgs {
         video_tracks, audio_tracks, title,
-        subtitle_tracks: ffprobe_result.tracks.iter().filter(|x| x.is_valid_subtitle_track()).collect(),
+        subtitle_tracks: subtitles,
+        burn_subtitles,
         extra_ffmpeg_args,
+        input_ffmpeg_args,
+        output_ffmpeg_args,
         duration: ffprobe_result.duration,
         force_demux_audio: false,
+        filenames,
         add_muxed_silence: false,
+        trim,
+        visual,
+    };
+
+    // done asking questions, so now it's safe to make a mess: the early exits above would have
+    // left the staging directory behind
+    let staged = if args.unstaged {
+        if !output_directory.is_dir() {
+            std::fs::create_dir(&output_directory).expect("Error creating output directory");
+        }
+        None
+    } else {
+        Some(StagedOutput::new(&output_directory).expect("Error creating staging directory"))
+    };
+    let work_directory = staged.as_ref().map_or(output_directory.as_path(), |x| x.path()).to_owned();
+
+    let thumbnail_track = transcode_args.video_tracks.first().map(|x| x.track.index);
+    let thumbnail_input_args = transcode_args.input_ffmpeg_args.clone();
+    let concat_list = if concat_inputs.len() > 1 {
+        // not in the work directory, or it'd get published along with everything else
+        let list_file = std::env::temp_dir().join(format!("cytrans-concat-{}.txt", std::process::id()));
+        let inputs = concat_inputs.iter().map(|(path, duration)| (path.as_path(), *duration)).collect::<Vec<_>>();
+        if let Err(e) = write_concat_list(&inputs, &list_file) {
+            eprintln!("Error writing the list of files to join: {}", e);
+            std::process::exit(1);
+        }
+        Some(list_file)
+    } else {
+        None
+    };
+    let (mut command, mut metadata_manifest, _did_demux) = match &concat_list {
+        Some(list_file) => build_concat_command(&ffmpeg_config, list_file, transcode_args, &work_directory),
+        None => build_ffmpeg_command(&ffmpeg_config, &input_path_or_url, transcode_args, &work_directory),
+    };
+
+    // with joined files the trim might not even land in the first one, so don't bother
+    let thumbnail_trim = if concat_list.is_some() { Trim::default() } else { trim };
+    if let Some(track) = thumbnail_track {
+        match generate_thumbnail(&ffmpeg_config, &input_path_or_url, &thumbnail_input_args, track, first_duration, &thumbnail_trim, &work_directory) {
+            Ok(filename) => metadata_manifest.thumbnail = Some(filename),
+            // not worth giving up the whole transcode over
+            Err(e) => eprintln!("Warning: {}", e),
+        }
+    }
+
+    let Some(staged) = staged else {
+        metadata_manifest.save_cytube(&output_directory, &url_prefix).expect("Error writing the manifest JSON file");
+
+        // keep our own manifest too, so the output can be demuxed or edited later
+        metadata_manifest.save(&output_directory).expect("Error writing the metadata manifest");
+
+        println!("{}", shell_words::render_command(&command));
+        if let Some(list_file) = concat_list {
+            // exec() would never come back to clean up the list, so wait for ffmpeg instead
+            let status = run_ignoring_sigint(&mut command).expect("Error invoking ffmpeg");
+            let _ = std::fs::remove_file(list_file);
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        let error = command.exec();
+
+        panic!("Error invoking ffmpeg: {}", error);
     };
 
-    let (mut command, metadata_manifest, _did_demux) = build_ffmpeg_command(&args.input_path_or_url, transcode_args, &args.output_directory);
+    println!("{}", shell_words::render_command(&command));
+    let status = run_ignoring_sigint(&mut command).expect("Error invoking ffmpeg");
+    if let Some(list_file) = concat_list {
+        let _ = std::fs::remove_file(list_file);
+    }
+    if !status.success() {
+        eprintln!("ffmpeg failed ({}), throwing away its output.", status);
+        // exit() doesn't run destructors, so clean up the staging directory by hand
+        drop(staged);
+        std::process::exit(1);
+    }
+    match staged.publish(&ffmpeg_config, &metadata_manifest, &url_prefix) {
+        Ok(()) => println!("Done!  The manifest is at {}{}", url_prefix, CYTUBE_MANIFEST_FILENAME),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    }
+}
 
-    serde_json::to_writer(
-        std::fs::File::create(args.output_directory.join("manifest.json")).expect("error creating the manifest JSON file"),
-        &metadata_manifest.to_cytube(&args.url_prefix),
-    ).expect("Error writing the manifest JSON file");
+/// Runs a command to completion with Ctrl-C ignored here, so that it only stops ffmpeg (which
+/// finishes up and exits non-zero) and we get to clean up the staging directory and the concat
+/// list afterwards.
+/// Being killed any other way, or hitting Ctrl-C before ffmpeg starts, still leaves the staging
+/// directory behind.
+fn run_ignoring_sigint(command: &mut std::process::Command) -> std::io::Result<std::process::ExitStatus> {
+    let mut child = command.spawn()?;
+    // only once ffmpeg is running, because ignored signals stay ignored across exec()
+    let previous = unsafe { libc::signal(libc::SIGINT, libc::SIG_IGN) };
+    let status = child.wait();
+    unsafe { libc::signal(libc::SIGINT, previous) };
+    status
+}
+
+fn load_or_scan(config: &FfmpegConfig, output_directory: &Path) -> MetadataManifest {
+    match MetadataManifest::load(output_directory) {
+        Ok(x) => x,
+        Err(ManifestError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("No metadata manifest in {}, rebuilding one from the files there", output_directory.display());
+            let (manifest, skipped) = scan_output_directory(config, output_directory).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            });
+            for e in skipped {
+                eprintln!("Warning: {}, leaving it out", e);
+            }
+            manifest
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        },
+    }
+}
 
-    let error = command.exec();
+fn remove(config: &FfmpegConfig, output_directory: &Path, url_prefix: &str, files: Vec<String>) -> ! {
+    let mut manifest = load_or_scan(config, output_directory);
+    // sort the files into the lists ToRemove wants.  anything we can't find gets left in
+    // video_files for apply_removal() to complain about.
+    let mut to_remove = ToRemove::default();
+    for file in files {
+        if manifest.audio_files.iter().any(|x| x.filename == file) {
+            to_remove.audio_files.push(file);
+        } else if manifest.text_files.iter().any(|x| x.filename == file) {
+            to_remove.text_files.push(file);
+        } else {
+            to_remove.video_files.push(file);
+        }
+    }
+    match manifest.apply_removal(output_directory, &to_remove, url_prefix) {
+        Ok(()) => std::process::exit(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    }
+}
 
-    panic!("Error invoking ffmpeg: {}", error);
+fn validate(config: &FfmpegConfig, path: &Path, url_prefix: Option<&str>, probe_files: bool) -> ! {
+    if path.is_file() {
+        let video: CytubeVideo = std::fs::File::open(path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| serde_json::from_reader(std::io::BufReader::new(file)).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                eprintln!("Error reading {}: {}", path.display(), e);
+                std::process::exit(2);
+            });
+        if probe_files {
+            eprintln!("--probe-files only works on output directories, ignoring it");
+        }
+        report_problems(video.validate().iter().map(ToString::to_string).collect());
+    }
+    let output_directory = path;
+    let Some(url_prefix) = url_prefix else {
+        eprintln!("A URL prefix is needed to check an output directory");
+        std::process::exit(2);
+    };
+    let manifest = load_or_scan(config, output_directory);
+    let mut problems = manifest.to_cytube(url_prefix).validate().iter().map(ToString::to_string).collect::<Vec<_>>();
+    if probe_files {
+        problems.extend(manifest.validate(config, output_directory).iter().map(ToString::to_string));
+    }
+    report_problems(problems);
+}
 
-    // TODO: implement invoking ffmpeg
+fn report_problems(problems: Vec<String>) -> ! {
+    if problems.is_empty() {
+        println!("Looks good to me.");
+        std::process::exit(0);
+    }
+    for problem in problems {
+        println!("{}", problem);
+    }
+    std::process::exit(1);
 }
 
-fn choose_encoder<T: Copy + Display + FromStr + Into<&'static str>>(title: &str, choices: &'static [(T, Vec<String>)], origin_codec: Option<&str>) -> Option<(T, &'static str)> {
+/// Asks ffmpeg whether it will accept the chosen encoders and their arguments.  Returns true if
+/// everything checks out or the user wants to go ahead anyway.
+fn check_tracks(config: &FfmpegConfig, capabilities: &Capabilities, input_report: &InputReport, video_tracks: &[TrackOptions<VideoCodec>], audio_tracks: &[TrackOptions<AudioCodec>], job_args: &[&[OsString]], burn_subtitles: Option<u16>) -> bool {
+    let mut problems = Vec::new();
+    // things ffmpeg will probably accept anyway, like options we just don't know about
+    let mut warnings = Vec::new();
+    if burn_subtitles.is_some() && !video_tracks.iter().any(|x| x.encoder != "copy") {
+        problems.push("subtitles can only be burned into a video track that's being re-encoded, not copied".to_string());
+    }
+    for args in job_args {
+        problems.extend(check_feature_gates(args, &capabilities.info).into_iter().map(|p| p.to_string()));
+    }
+    problems.extend(
+        video_tracks.iter().map(|x| (x.track.index, x.encoder == "copy"))
+        .chain(audio_tracks.iter().map(|x| (x.track.index, x.encoder == "copy")))
+        .filter_map(|(index, copied)| input_report.check_track(index, copied))
+        .map(|problem| problem.to_string())
+    );
+    for track in video_tracks {
+        match check_track_options(config, track, &capabilities.video_encoders) {
+            Ok(p) => for p in p {
+                let message = format!("video track #{}: {}", track.track.index, p);
+                if p.is_warning() { warnings.push(message) } else { problems.push(message) }
+            },
+            Err(e) => problems.push(format!("video track #{}: error asking ffmpeg about the encoder: {}", track.track.index, e)),
+        }
+        problems.extend(check_feature_gates(&track.extra_ffmpeg_args, &capabilities.info).into_iter().map(|p| format!("video track #{}: {}", track.track.index, p)));
+    }
+    for track in audio_tracks {
+        match check_track_options(config, track, &capabilities.audio_encoders) {
+            Ok(p) => for p in p {
+                let message = format!("audio track #{}: {}", track.track.index, p);
+                if p.is_warning() { warnings.push(message) } else { problems.push(message) }
+            },
+            Err(e) => problems.push(format!("audio track #{}: error asking ffmpeg about the encoder: {}", track.track.index, e)),
+        }
+        problems.extend(check_feature_gates(&track.extra_ffmpeg_args, &capabilities.info).into_iter().map(|p| format!("audio track #{}: {}", track.track.index, p)));
+    }
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    if problems.is_empty() {
+        return true;
+    }
+    let message = problems.join("\n");
+    Menu::new(
+        vec![
+        MenuOption {label: "No, go back".into(), value: false},
+        MenuOption {label: "Yes".into(), value: true},
+        ],
+        MenuProps {
+            title: "ffmpeg is likely to reject this job.  Launch it anyway?",
+            message: &message,
+            ..MenuProps::default()
+        }
+    )
+        .show()
+        .copied()
+        .unwrap_or(false)
+}
+
+fn choose_encoder<'c, T: Copy + Display + FromStr + Into<&'static str>>(title: &str, choices: &'c [(T, Vec<String>)], origin_codec: Option<&str>) -> Option<(T, &'c str)> {
     let mut v = Vec::new();
     if let Some(origin_codec) = origin_codec {
         if let Ok(codec) = T::from_str(origin_codec) {
@@ -215,15 +715,37 @@ fn choose_encoder<T: Copy + Display + FromStr + Into<&'static str>>(title: &str,
     menu.show().copied()
 }
 
+/// An output track, as far as show_tracks_menu() is concerned.
 trait Menuable<'ff>: Sized {
+    /// What an output track gets made from.  Video and audio come from the input's tracks,
+    /// subtitles can come from sidecar files too.
+    type Source;
+    const MENU_NAME: &'static str;
+    fn source_label(source: &Self::Source) -> String;
+    /// Makes an output track out of `source`, asking for whatever else it needs.  `others` are the
+    /// output tracks there already are.
+    fn from_source(source: &Self::Source, others: &[Self], capabilities: &Capabilities) -> Option<Self>;
+    fn set_source(&mut self, source: &Self::Source);
+    fn label(&self) -> String;
+    fn track(&self) -> &'ff Track;
+    fn overrides_mut(&mut self) -> &mut MetadataOverrides;
+    /// Whether to show an option in the "Modify Track" menu.  Not every kind of track has codecs
+    /// or defaults.
+    fn offers(option: &ModifyEntryMenu) -> bool;
+    /// Handles the options that only some kinds of track have.
+    fn modify(tracks: &mut [Self], idx: usize, option: ModifyEntryMenu, capabilities: &Capabilities, editor: &mut rustyline::Editor<(), rustyline::history::DefaultHistory>);
+}
+
+/// The codec side of video and audio tracks' Menuable impl.
+trait MenuCodec<'ff>: Sized {
     const TRACK_TYPE: TrackType;
     const MENU_NAME: &'static str;
     const ENCODER_LIST_NAME: &'static str;
     fn label_for(options: &TrackOptions<'ff, Self>) -> String;
-    fn get_encoders() -> &'static [(Self, Vec<String>)];
+    fn get_encoders(capabilities: &Capabilities) -> &[(Self, Vec<String>)];
 }
 
-#[derive(strum::EnumMessage,strum::EnumIter)]
+#[derive(Clone,Copy,strum::EnumMessage,strum::EnumIter)]
 enum ModifyEntryMenu {
     #[strum(message="Change source track")]
     ChangeTrack,
@@ -231,6 +753,16 @@ enum ModifyEntryMenu {
     ChangeCodec,
     #[strum(message="Change ffmpeg args")]
     ChangeFfmpegArgs,
+    #[strum(message="Show by default (or stop)")]
+    ToggleDefault,
+    #[strum(message="Change language")]
+    ChangeLanguage,
+    #[strum(message="Change title")]
+    ChangeTitle,
+    #[strum(message="Move up")]
+    MoveUp,
+    #[strum(message="Move down")]
+    MoveDown,
     #[strum(message="Remove this output track")]
     DeleteTrack,
     #[strum(message="Done, go back")]
@@ -253,10 +785,10 @@ fn ask_if_sure(message: &str) -> bool {
         .unwrap_or(false)
 }
 
-fn choose_track<'ff>(title: &str, input_tracks: &[&'ff Track]) -> Option<&'ff Track> {
+fn choose_track<'a, 'ff, T: Menuable<'ff>>(title: &str, input_tracks: &'a [T::Source]) -> Option<&'a T::Source> {
     let mut menu = Menu::new(
         input_tracks.iter()
-        .map(|track| MenuOption {label: track.to_string(), value: *track})
+        .map(|source| MenuOption {label: T::source_label(source), value: source})
         .collect(),
         MenuProps {
             title: "Select track to source from",
@@ -266,7 +798,7 @@ fn choose_track<'ff>(title: &str, input_tracks: &[&'ff Track]) -> Option<&'ff Tr
     menu.show().map(|v|&**v)
 }
 
-fn show_tracks_menu<'ff, T: Menuable<'ff> + Copy + Display + FromStr + Into<&'static str> + 'static>(output_tracks: &mut Vec<TrackOptions<'ff, T>>, input_tracks: &[&'ff Track], editor: &mut rustyline::Editor<(), rustyline::history::DefaultHistory>) {
+fn show_tracks_menu<'ff, T: Menuable<'ff>>(output_tracks: &mut Vec<T>, input_tracks: &[T::Source], capabilities: &Capabilities, editor: &mut rustyline::Editor<(), rustyline::history::DefaultHistory>) {
     if input_tracks.is_empty() {
         Menu::new(vec![MenuOption {label: "Back".into(), value: ()}], MenuProps {title: "-- no tracks available --", ..MenuProps::default()}).show();
         return;
@@ -279,7 +811,7 @@ fn show_tracks_menu<'ff, T: Menuable<'ff> + Copy + Display + FromStr + Into<&'st
         v.extend(
             output_tracks.iter()
             .enumerate()
-            .map(|(i, entry)| MenuOption {value: Some(i), label: T::label_for(entry)})
+            .map(|(i, entry)| MenuOption {value: Some(i), label: entry.label()})
         );
         let mut menu = Menu::new(v, MenuProps {
             title: T::MENU_NAME,
@@ -290,60 +822,48 @@ fn show_tracks_menu<'ff, T: Menuable<'ff> + Copy + Display + FromStr + Into<&'st
             None => return,
             Some(None) => {
                 let chosen_track = if input_tracks.len() == 1 {
-                    Some(input_tracks[0])
+                    Some(&input_tracks[0])
                 } else {
-                    choose_track("Select an input track to source from", input_tracks)
+                    choose_track::<T>("Select an input track to source from", input_tracks)
                 };
 
-                if let Some(track) = chosen_track {
-
-                    let origin_codec = if output_tracks.iter().any(|stream| stream.track.index == track.index && stream.encoder=="copy") {
-                        None
-                    } else {
-                        Some(track.codec.as_str())
-                    };
-
-                    if let Some((codec, encoder)) = choose_encoder(T::ENCODER_LIST_NAME, T::get_encoders(), origin_codec) {
-                        output_tracks.push(TrackOptions {
-                            track, codec,
-                            encoder: encoder.into(),
-                            bitrate: None,
-                            extra_ffmpeg_args: vec![],
-                        });
-                    }
+                if let Some(new_track) = chosen_track.and_then(|source| T::from_source(source, output_tracks, capabilities)) {
+                    output_tracks.push(new_track);
                 }
-                    
             },
             Some(Some(idx)) => {
-                let mut modify_entry_menu = Menu::new(ModifyEntryMenu::into_menu(), MenuProps {
+                let mut options = ModifyEntryMenu::into_menu();
+                options.retain(|x| T::offers(&x.value));
+                let mut modify_entry_menu = Menu::new(options, MenuProps {
                     title: "Modify Track",
                     ..MenuProps::default()
                 });
                 if let Some(option) = modify_entry_menu.show() {
                     match option {
                         ModifyEntryMenu::ChangeTrack => {
-                            if let Some(new_track) = choose_track("Choose a new track", input_tracks) {
-                                output_tracks[*idx].track = new_track;
+                            if let Some(new_track) = choose_track::<T>("Choose a new track", input_tracks) {
+                                output_tracks[*idx].set_source(new_track);
                             }
                         },
-                        ModifyEntryMenu::ChangeCodec => {
-                            let current_track_index = output_tracks[*idx].track.index;
-                            let any_copy_already = output_tracks.iter().enumerate().any(|(i, stream)| i != *idx && stream.track.index == current_track_index && stream.encoder=="copy");
-                            let origin_codec = if any_copy_already {None} else {Some(output_tracks[*idx].track.codec.as_str())};
-                            if let Some((codec, encoder)) = choose_encoder(T::ENCODER_LIST_NAME, T::get_encoders(), origin_codec) {
-                                output_tracks[*idx].codec = codec;
-                                output_tracks[*idx].encoder = encoder.into();
-                            }
+                        ModifyEntryMenu::ChangeLanguage => {
+                            let entry = &mut output_tracks[*idx];
+                            let track = entry.track();
+                            edit_language(entry.overrides_mut(), track, editor);
                         },
-                        ModifyEntryMenu::ChangeFfmpegArgs => {
-                            modify_ffmpeg_args_menu(&mut output_tracks[*idx].extra_ffmpeg_args, editor);
+                        ModifyEntryMenu::ChangeTitle => {
+                            let entry = &mut output_tracks[*idx];
+                            let track = entry.track();
+                            edit_title(entry.overrides_mut(), track, editor);
                         },
+                        ModifyEntryMenu::MoveUp => move_entry(output_tracks, *idx, true),
+                        ModifyEntryMenu::MoveDown => move_entry(output_tracks, *idx, false),
                         ModifyEntryMenu::DeleteTrack => {
                             if ask_if_sure("Really delete?") {
                                 output_tracks.remove(*idx);
                             }
                         },
                         ModifyEntryMenu::Done => {},
+                        option => T::modify(output_tracks, *idx, *option, capabilities, editor),
                     }
                 }
             },
@@ -353,17 +873,128 @@ fn show_tracks_menu<'ff, T: Menuable<'ff> + Copy + Display + FromStr + Into<&'st
 
 
 
-fn modify_ffmpeg_args_menu(extra_ffmpeg_args: &mut Vec<OsString>, line_editor: &mut rustyline::Editor<(), rustyline::history::DefaultHistory>) {
-    println!("TODO: this interface is super basic and doesn't support spaces in arguments at all!");
-    println!("TODO: I don't have time to implement a better one right now, but I really need to!");
-    let s = extra_ffmpeg_args.iter().map(|x|x.to_string_lossy()).intersperse(std::borrow::Cow::Borrowed(" ")).collect::<String>();
-    if let Ok(new_args) = line_editor.readline_with_initial("ffmpeg args:", (&s,"")) {
-        *extra_ffmpeg_args = new_args.split(' ').map(Into::into).collect();
+/// Moves an entry one place towards the start of the list (or the end), if there's room.
+fn move_entry<T>(list: &mut [T], idx: usize, up: bool) {
+    if up && idx > 0 {
+        list.swap(idx, idx - 1);
+    } else if !up && idx + 1 < list.len() {
+        list.swap(idx, idx + 1);
+    }
+}
+
+/// Marks the subtitle track that should be on by default (see default_subtitle_track()), going by
+/// the language of the first audio track that's going to be output.  Sidecars never are, since
+/// there's nothing in them to go by.
+fn pick_default_subtitle(subtitles: &mut [SubtitleOptions], audio_tracks: &[TrackOptions<AudioCodec>]) {
+    let audio_language = audio_tracks.first().and_then(|x| x.overrides.language(x.track));
+    let default = default_subtitle_track(subtitles.iter().filter(|x| x.file.is_none()).map(|x| x.track), audio_language);
+    for subtitle in subtitles {
+        subtitle.default = subtitle.file.is_none() && Some(subtitle.track.index) == default;
+    }
+}
+
+/// Picks the bitmap subtitle track to burn into the video, if any.  There's only room for one
+/// set of subtitles on the screen at a time.
+fn choose_burn_subtitles(tracks: &[&Track], burn_subtitles: &mut Option<u16>) {
+    if tracks.is_empty() {
+        Menu::new(vec![MenuOption {label: "Back".into(), value: ()}], MenuProps {title: "-- no bitmap subtitle tracks --", ..MenuProps::default()}).show();
+        return;
+    }
+    let mut options = vec![MenuOption {label: "Don't burn in subtitles".into(), value: None}];
+    options.extend(tracks.iter().map(|track| {
+        let burned = if *burn_subtitles == Some(track.index) {" - burned in"} else {""};
+        MenuOption {label: format!("{}{}", track, burned), value: Some(track.index)}
+    }));
+    let mut menu = Menu::new(options, MenuProps {
+        title: "Subtitles to burn in",
+        message: "Bitmap subtitles can't be turned into WebVTT, but they can be drawn onto a video track that's being re-encoded.",
+        ..MenuProps::default()
+    });
+    if let Some(choice) = menu.show() {
+        *burn_subtitles = *choice;
     }
+}
 
+/// Asks for a new language for a track.  Leaving it blank goes back to whatever the input file
+/// says.
+fn edit_language(overrides: &mut MetadataOverrides, track: &Track, line_editor: &mut rustyline::Editor<(), rustyline::history::DefaultHistory>) {
+    let current = overrides.language(track).map(|x| x.as_str().to_owned()).unwrap_or_default();
+    loop {
+        let Ok(new_language) = line_editor.readline_with_initial("Language (e.g. eng, jpn, und): ", (&current, "")) else {
+            return;
+        };
+        if new_language.trim().is_empty() {
+            overrides.language = None;
+            return;
+        }
+        match parse_language(&new_language) {
+            Ok(x) => {
+                overrides.language = Some(x);
+                return;
+            },
+            Err(e) => println!("{}", e),
+        }
+    }
 }
 
-impl<'ff> Menuable<'ff> for VideoCodec {
+/// Asks for a new title for a track.  Leaving it blank gets rid of the title.
+fn edit_title(overrides: &mut MetadataOverrides, track: &Track, line_editor: &mut rustyline::Editor<(), rustyline::history::DefaultHistory>) {
+    let current = overrides.title(track).unwrap_or_default().to_owned();
+    if let Ok(new_title) = line_editor.readline_with_initial("Title: ", (&current, "")) {
+        // don't count it as an override if it's what the file said anyway
+        overrides.title = if track.title.as_deref() == Some(new_title.as_str()) { None } else { Some(new_title) };
+    }
+}
+
+#[derive(Clone, Copy)]
+enum JobArgs {
+    Global,
+    Input,
+    Output,
+}
+
+/// The extra arguments that apply to the whole job rather than one track.
+fn job_args_menu(global: &mut Vec<OsString>, input: &mut Vec<OsString>, output: &mut Vec<OsString>, line_editor: &mut rustyline::Editor<(), rustyline::history::DefaultHistory>) {
+    loop {
+        let show = |args: &[OsString]| if args.is_empty() { "(none)".to_string() } else { shell_words::join(args.iter().map(|x| x.to_string_lossy())) };
+        let mut menu = Menu::new(vec![
+            MenuOption { label: format!("Global options: {}", show(global)), value: Some(JobArgs::Global) },
+            MenuOption { label: format!("Input options: {}", show(input)), value: Some(JobArgs::Input) },
+            MenuOption { label: format!("Options for every output: {}", show(output)), value: Some(JobArgs::Output) },
+            MenuOption { label: "Done, go back".into(), value: None },
+        ], MenuProps {
+            title: "Extra ffmpeg args",
+            message: "Global options go before everything else, input options just before -i, and output options before each output's own track options.",
+            ..MenuProps::default()
+        });
+        match menu.show() {
+            Some(Some(JobArgs::Global)) => modify_ffmpeg_args_menu(global, line_editor),
+            Some(Some(JobArgs::Input)) => modify_ffmpeg_args_menu(input, line_editor),
+            Some(Some(JobArgs::Output)) => modify_ffmpeg_args_menu(output, line_editor),
+            Some(None) | None => return,
+        }
+    }
+}
+
+/// Lets the user edit a list of ffmpeg arguments as one line, quoted like they would be in a shell.
+fn modify_ffmpeg_args_menu(extra_ffmpeg_args: &mut Vec<OsString>, line_editor: &mut rustyline::Editor<(), rustyline::history::DefaultHistory>) {
+    let mut s = shell_words::join(extra_ffmpeg_args.iter().map(|x| x.to_string_lossy()));
+    // keep asking until it parses, starting from whatever they typed last time
+    while let Ok(new_args) = line_editor.readline_with_initial("ffmpeg args: ", (&s, "")) {
+        match shell_words::split(&new_args) {
+            Ok(args) => {
+                *extra_ffmpeg_args = args.into_iter().map(Into::into).collect();
+                return;
+            },
+            Err(e) => {
+                println!("Can't make sense of that: {}", e);
+                s = new_args;
+            },
+        }
+    }
+}
+
+impl<'ff> MenuCodec<'ff> for VideoCodec {
     const TRACK_TYPE: TrackType = TrackType::Video;
 
     const MENU_NAME: &'static str = "Video streams";
@@ -374,12 +1005,12 @@ impl<'ff> Menuable<'ff> for VideoCodec {
         format!("{} ({})", options.codec, options.encoder)
     }
 
-    fn get_encoders() -> &'static [(Self, Vec<String>)] {
-        cytrans::codecs::get_video_encoders()
+    fn get_encoders(capabilities: &Capabilities) -> &[(Self, Vec<String>)] {
+        &capabilities.video_encoders
     }
 }
 
-impl<'ff> Menuable<'ff> for AudioCodec {
+impl<'ff> MenuCodec<'ff> for AudioCodec {
     const TRACK_TYPE: TrackType = TrackType::Audio;
 
     const MENU_NAME: &'static str = "Audio streams";
@@ -387,10 +1018,127 @@ impl<'ff> Menuable<'ff> for AudioCodec {
     const ENCODER_LIST_NAME: &'static str = "Select audio encoder";
 
     fn label_for(options: &TrackOptions<'ff, Self>) -> String {
-        format!("#{} ({}) -> {} ({})", options.track.index, options.track.language.as_ref().map(|x| x.as_str()).unwrap_or("unknown"), options.codec, options.encoder)
+        format!("#{} ({}) -> {} ({})", options.track.index, options.overrides.language(options.track).as_ref().map(|x| x.as_str()).unwrap_or("unknown"), options.codec, options.encoder)
+    }
+
+    fn get_encoders(capabilities: &Capabilities) -> &[(Self, Vec<String>)] {
+        &capabilities.audio_encoders
+    }
+}
+
+impl<'ff, T: MenuCodec<'ff> + Copy + Display + FromStr + Into<&'static str> + 'static> Menuable<'ff> for TrackOptions<'ff, T> {
+    type Source = &'ff Track;
+
+    const MENU_NAME: &'static str = T::MENU_NAME;
+
+    fn source_label(source: &&'ff Track) -> String {
+        source.to_string()
+    }
+
+    fn from_source(source: &&'ff Track, others: &[Self], capabilities: &Capabilities) -> Option<Self> {
+        let track = *source;
+        let origin_codec = if others.iter().any(|stream| stream.track.index == track.index && stream.encoder=="copy") {
+            None
+        } else {
+            Some(track.codec.as_str())
+        };
+        let (codec, encoder) = choose_encoder(T::ENCODER_LIST_NAME, T::get_encoders(capabilities), origin_codec)?;
+        Some(TrackOptions::new(track, codec, encoder.into()))
     }
 
-    fn get_encoders() -> &'static [(Self, Vec<String>)] {
-        cytrans::codecs::get_audio_encoders()
+    fn set_source(&mut self, source: &&'ff Track) {
+        self.track = source;
+    }
+
+    fn label(&self) -> String {
+        T::label_for(self)
+    }
+
+    fn track(&self) -> &'ff Track {
+        self.track
+    }
+
+    fn overrides_mut(&mut self) -> &mut MetadataOverrides {
+        &mut self.overrides
+    }
+
+    fn offers(option: &ModifyEntryMenu) -> bool {
+        !matches!(option, ModifyEntryMenu::ToggleDefault)
+    }
+
+    fn modify(tracks: &mut [Self], idx: usize, option: ModifyEntryMenu, capabilities: &Capabilities, editor: &mut rustyline::Editor<(), rustyline::history::DefaultHistory>) {
+        match option {
+            ModifyEntryMenu::ChangeCodec => {
+                let current_track_index = tracks[idx].track.index;
+                let any_copy_already = tracks.iter().enumerate().any(|(i, stream)| i != idx && stream.track.index == current_track_index && stream.encoder=="copy");
+                let origin_codec = if any_copy_already {None} else {Some(tracks[idx].track.codec.as_str())};
+                if let Some((codec, encoder)) = choose_encoder(T::ENCODER_LIST_NAME, T::get_encoders(capabilities), origin_codec) {
+                    tracks[idx].codec = codec;
+                    tracks[idx].encoder = encoder.into();
+                }
+            },
+            ModifyEntryMenu::ChangeFfmpegArgs => modify_ffmpeg_args_menu(&mut tracks[idx].extra_ffmpeg_args, editor),
+            _ => {},
+        }
+    }
+}
+
+/// Subtitles don't have codecs to choose, since they all become WebVTT.  They come from the
+/// input's text subtitle tracks and from sidecar files.
+impl<'ff> Menuable<'ff> for SubtitleOptions<'ff> {
+    type Source = SubtitleOptions<'ff>;
+
+    const MENU_NAME: &'static str = "Subtitle streams";
+
+    fn source_label(source: &SubtitleOptions<'ff>) -> String {
+        source.label()
+    }
+
+    fn from_source(source: &SubtitleOptions<'ff>, _others: &[Self], _capabilities: &Capabilities) -> Option<Self> {
+        Some(source.clone())
+    }
+
+    fn set_source(&mut self, source: &SubtitleOptions<'ff>) {
+        self.track = source.track;
+        self.file = source.file.clone();
+    }
+
+    fn label(&self) -> String {
+        let mut label = match &self.file {
+            Some(path) => path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned(),
+            None => format!("#{}", self.track.index),
+        };
+        label.push_str(&format!(" {}", self.track.codec));
+        if let Some(language) = self.overrides.language(self.track) {
+            label.push_str(&format!(" ({})", language));
+        }
+        if let Some(title) = self.overrides.title(self.track) {
+            label.push_str(&format!(" \"{}\"", title));
+        }
+        if self.default {
+            label.push_str(" - default");
+        }
+        label
+    }
+
+    fn track(&self) -> &'ff Track {
+        self.track
+    }
+
+    fn overrides_mut(&mut self) -> &mut MetadataOverrides {
+        &mut self.overrides
+    }
+
+    fn offers(option: &ModifyEntryMenu) -> bool {
+        !matches!(option, ModifyEntryMenu::ChangeCodec | ModifyEntryMenu::ChangeFfmpegArgs)
+    }
+
+    fn modify(tracks: &mut [Self], idx: usize, option: ModifyEntryMenu, _capabilities: &Capabilities, _editor: &mut rustyline::Editor<(), rustyline::history::DefaultHistory>) {
+        if let ModifyEntryMenu::ToggleDefault = option {
+            // Cytube only turns on one track by itself
+            let default = !tracks[idx].default;
+            tracks.iter_mut().for_each(|x| x.default = false);
+            tracks[idx].default = default;
+        }
     }
 }
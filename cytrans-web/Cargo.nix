@@ -1952,6 +1952,10 @@ rec {
             packageId = "serde";
             features = [ "derive" ];
           }
+          {
+            name = "serde_json";
+            packageId = "serde_json";
+          }
           {
             name = "strum";
             packageId = "strum";
@@ -1,8 +1,105 @@
-use crate::common::{self, BrowseResult, BrowseError, PathParam};
+use std::sync::Arc;
 
-use actix_web::{body::BoxBody, get, web::{self, Data, Query}, HttpResponse, Responder, ResponseError};
+use crate::common::{self, BrowseResult, BrowseError, FfmpegError, PathParam};
+use crate::jobs::JobError;
+use crate::outputs::OutputError;
+
+use actix_web::{body::BoxBody, get, post, web::{self, Data, Json, Query}, HttpResponse, Responder, ResponseError};
+use cytrans::{codecs::Capabilities, cytube_structs::CytubeVideo, defaults::STRATEGIES, metadata::ToRemove};
+use cytrans_ws::NetworkTranscodeArgs;
 
 #[get("/api/browse")]
 pub async fn browse(Query(PathParam{path}): Query<PathParam>, data: Data<crate::Args>) -> Result<BrowseResult, BrowseError> {
     crate::common::browse(data, &path)
 }
+
+/// Which encoders this server's ffmpeg has, its version, and which optional features it
+/// supports.
+#[get("/api/capabilities")]
+pub async fn capabilities(data: Data<crate::Args>) -> Result<Json<Capabilities>, FfmpegError> {
+    let caps = web::block(move || cytrans::codecs::get_capabilities(&data.ffmpeg)).await??;
+    Ok(Json(Arc::unwrap_or_clone(caps)))
+}
+
+/// Re-detects what ffmpeg can do, e.g. after it has been upgraded.
+#[post("/api/capabilities/refresh")]
+pub async fn refresh_capabilities(data: Data<crate::Args>) -> Result<Json<Capabilities>, FfmpegError> {
+    let caps = web::block(move || cytrans::codecs::refresh_capabilities(&data.ffmpeg)).await??;
+    Ok(Json(Arc::unwrap_or_clone(caps)))
+}
+
+/// Runs ffprobe on a file, along with a report of whether our ffmpeg can decode each of its
+/// tracks.
+#[get("/api/probe")]
+pub async fn probe(Query(PathParam{path}): Query<PathParam>, data: Data<crate::Args>) -> Result<Json<crate::jobs::ProbeResult>, JobError> {
+    let result = web::block(move || crate::jobs::probe(&data, &path)).await??;
+    Ok(Json(result))
+}
+
+#[derive(serde::Deserialize)]
+pub struct DefaultsParams {
+    path: String,
+    /// One of the names /api/defaults/strategies lists.  Missing means the server's default.
+    strategy: Option<String>,
+}
+
+/// The tracks and settings we'd pick for a file, going by the server's defaults policy.  In the
+/// same shape as a job, so a client can tweak it and send it straight back.
+#[get("/api/defaults")]
+pub async fn defaults(Query(DefaultsParams{path, strategy}): Query<DefaultsParams>, data: Data<crate::Args>) -> Result<Json<NetworkTranscodeArgs>, JobError> {
+    let result = web::block(move || crate::jobs::defaults(&data, &path, strategy.as_deref())).await??;
+    Ok(Json(result))
+}
+
+#[derive(serde::Serialize)]
+pub struct StrategyInfo {
+    name: &'static str,
+    description: &'static str,
+    /// Whether it's the one /api/defaults uses when it isn't told otherwise.
+    default: bool,
+}
+
+/// The strategies /api/defaults can pick codecs with.
+#[get("/api/defaults/strategies")]
+pub async fn strategies(data: Data<crate::Args>) -> Json<Vec<StrategyInfo>> {
+    Json(STRATEGIES.iter().map(|x| StrategyInfo {
+        name: x.name(),
+        description: x.description(),
+        default: x.name() == data.defaults_strategy.name(),
+    }).collect())
+}
+
+/// Checks a job without queueing it.  Responds 204 if ffmpeg should accept it, 200 with a list
+/// of warnings if it probably will, or with an error describing what's wrong with it otherwise.
+#[post("/api/check")]
+pub async fn check_job(Query(PathParam{path}): Query<PathParam>, data: Data<crate::Args>, Json(request): Json<NetworkTranscodeArgs>) -> Result<HttpResponse, JobError> {
+    let warnings = web::block(move || crate::jobs::check_job(&data, &path, &request)).await??;
+    if warnings.is_empty() {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::Ok().json(warnings))
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ValidateParams {
+    path: String,
+    #[serde(default)]
+    probe_files: bool,
+}
+
+/// Checks an output directory's manifest against Cytube's rules for custom media.  With
+/// `probe_files=true`, also checks the files themselves, which is a lot slower.
+#[get("/api/validate")]
+pub async fn validate(Query(ValidateParams{path, probe_files}): Query<ValidateParams>, data: Data<crate::Args>) -> Result<Json<crate::outputs::ValidationResult>, OutputError> {
+    let result = web::block(move || crate::outputs::validate(&data, &path, probe_files)).await??;
+    Ok(Json(result))
+}
+
+/// Deletes files from an output directory, e.g. a video quality nobody needs, and responds with
+/// the updated Cytube manifest.
+#[post("/api/remove")]
+pub async fn remove(Query(PathParam{path}): Query<PathParam>, data: Data<crate::Args>, Json(to_remove): Json<ToRemove>) -> Result<Json<CytubeVideo>, OutputError> {
+    let result = web::block(move || crate::outputs::remove(&data, &path, &to_remove)).await??;
+    Ok(Json(result))
+}
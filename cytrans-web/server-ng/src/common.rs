@@ -66,6 +66,23 @@ impl ResponseError for SanitizePathError {
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum FfmpegError {
+    #[error("{0}")]
+    Capabilities(#[from] cytrans::codecs::CapabilitiesError),
+    #[error("{0}")]
+    Blocking(#[from] actix_web::error::BlockingError),
+}
+
+impl ResponseError for FfmpegError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            FfmpegError::Capabilities(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            FfmpegError::Blocking(error) => error.status_code(),
+        }
+    }
+}
+
 impl ResponseError for BrowseError {
     fn status_code(&self) -> StatusCode {
         match self {
@@ -140,12 +157,21 @@ pub fn sanitize_path(input_path: &str) -> Result<PathBuf, SanitizePathError> {
 
 pub(crate) struct BrowseResult(Vec<Entry>);
 
-pub fn browse(args: Data<crate::Args>, browse_path: &str) -> Result<BrowseResult, BrowseError> {
+/// Resolves a user-supplied path relative to the input directory.
+pub fn input_path(args: &crate::Args, path: &str) -> Result<PathBuf, BrowseError> {
     let Some(input_path) = &args.input_dir else {
         return Err(BrowseError::BrowsingDisabled);
     };
-    let p = sanitize_path(browse_path)?;
-    let p = input_path.join(p);
+    Ok(input_path.join(sanitize_path(path)?))
+}
+
+/// Resolves a user-supplied path relative to the output directory.
+pub fn output_path(args: &crate::Args, path: &str) -> Result<PathBuf, SanitizePathError> {
+    Ok(args.output_dir.join(sanitize_path(path)?))
+}
+
+pub fn browse(args: Data<crate::Args>, browse_path: &str) -> Result<BrowseResult, BrowseError> {
+    let p = input_path(&args, browse_path)?;
     let mut v = Vec::new();
     for entry in std::fs::read_dir(&p)? {
         match browse_inner(entry) {
@@ -158,7 +184,7 @@ pub fn browse(args: Data<crate::Args>, browse_path: &str) -> Result<BrowseResult
     }
     Ok(BrowseResult(v))
 }
- fn browse_inner(entry: std::io::Result<DirEntry>) -> std::io::Result<Optiom<Entry>>() {
+ fn browse_inner(entry: std::io::Result<std::fs::DirEntry>) -> std::io::Result<Option<Entry>> {
      let entry = entry?;
      let name = entry.file_name();
      let Some(name) = name.to_str() else {
@@ -167,7 +193,7 @@ pub fn browse(args: Data<crate::Args>, browse_path: &str) -> Result<BrowseResult
      if name.starts_with('.') {
          return Ok(None);
      }
-     let meta = entry.meta()?;
+     let meta = entry.metadata()?;
      if meta.is_dir(){
          Ok(Some(Entry::Dir(name.into())))
      } else if meta.is_file() && MOVIE_EXTS.iter().any(|ext| name.ends_with(ext)) {
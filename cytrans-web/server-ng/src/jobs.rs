@@ -0,0 +1,185 @@
+//! Turning the job descriptions clients send us into something libcytrans understands, and
+//! making sure ffmpeg will actually accept them before they go anywhere near the queue.
+
+use actix_web::{http::StatusCode, ResponseError};
+use cytrans::{defaults::find_strategy, codecs::{check_input, check_transcode_args, get_capabilities, InputProblem, InputReport, TrackProblem}, ffprobe::{ffprobe, ffprobe_with_input_args, FFprobeResult, TrackType}, options::{parse_language, MetadataOverrides, SubtitleOptions, TrackOptions, TranscodeArgs, TrimError, UnknownLanguage}, transcode::get_defaults};
+use cytrans_ws::{NetworkSubtitleTrack, NetworkTrackOptions, NetworkTranscodeArgs};
+
+use crate::common::{BrowseError, FfmpegError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobError {
+    #[error("{0}")]
+    Path(#[from] BrowseError),
+    #[error("{0}")]
+    Ffprobe(std::io::Error),
+    #[error("{0}")]
+    Ffmpeg(#[from] FfmpegError),
+    #[error("The input file has no track #{0}")]
+    NoSuchTrack(u16),
+    #[error("Track #{0} is not a {1:?} track")]
+    WrongTrackType(u16, TrackType),
+    #[error("Track #{0} can't be the default subtitle track because it isn't one of the selected subtitle tracks")]
+    DefaultNotSelected(u16),
+    #[error("{0}")]
+    UnknownLanguage(#[from] UnknownLanguage),
+    #[error("There's no defaults strategy called {0:?}")]
+    UnknownStrategy(String),
+    #[error("Can't trim the input: {0}")]
+    BadTrim(#[from] TrimError),
+    #[error("ffmpeg can't read this file: {}", join_problems(.0))]
+    Unreadable(Vec<InputProblem>),
+    #[error("ffmpeg would reject this job: {}", join_problems(.0))]
+    Rejected(Vec<TrackProblem>),
+}
+
+fn join_problems<T: ToString>(problems: &[T]) -> String {
+    problems.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+}
+
+impl From<actix_web::error::BlockingError> for JobError {
+    fn from(e: actix_web::error::BlockingError) -> Self {
+        JobError::Ffmpeg(e.into())
+    }
+}
+
+impl ResponseError for JobError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            JobError::Path(error) => error.status_code(),
+            JobError::Ffprobe(error) if error.kind() == std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            JobError::Ffprobe(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            JobError::Ffmpeg(error) => error.status_code(),
+            JobError::NoSuchTrack(_) | JobError::WrongTrackType(..) | JobError::DefaultNotSelected(_) | JobError::BadTrim(_) | JobError::UnknownLanguage(_) | JobError::UnknownStrategy(_) => StatusCode::BAD_REQUEST,
+            JobError::Unreadable(_) | JobError::Rejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+fn resolve_overrides(language: &Option<String>, title: &Option<String>) -> Result<MetadataOverrides, JobError> {
+    Ok(MetadataOverrides {
+        language: language.as_deref().map(parse_language).transpose()?,
+        title: title.clone(),
+    })
+}
+
+fn resolve_track<'ff, C>(ffprobe: &'ff FFprobeResult, options: &NetworkTrackOptions<C>, kind: TrackType) -> Result<TrackOptions<'ff, C>, JobError> where C: Copy {
+    let track = ffprobe.tracks.iter()
+        .find(|track| track.index == options.track_idx)
+        .ok_or(JobError::NoSuchTrack(options.track_idx))?;
+    if track.kind != kind {
+        return Err(JobError::WrongTrackType(track.index, kind));
+    }
+    Ok(TrackOptions {
+        track,
+        codec: options.codec,
+        encoder: options.encoder.clone(),
+        bitrate: options.bitrate,
+        extra_ffmpeg_args: options.extra_ffmpeg_args.iter().map(Into::into).collect(),
+        overrides: resolve_overrides(&options.language, &options.title)?,
+    })
+}
+
+pub fn resolve_transcode_args<'ff>(args: &crate::Args, ffprobe: &'ff FFprobeResult, request: &NetworkTranscodeArgs) -> Result<TranscodeArgs<'ff>, JobError> {
+    let video_tracks = request.video_tracks.iter()
+        .map(|x| resolve_track(ffprobe, x, TrackType::Video))
+        .collect::<Result<_, _>>()?;
+    let audio_tracks = request.audio_tracks.iter()
+        .map(|x| resolve_track(ffprobe, x, TrackType::Audio))
+        .collect::<Result<_, _>>()?;
+    let subtitle_tracks = request.subtitle_tracks.iter()
+        .map(|sub| {
+            let idx = sub.track_idx();
+            let overrides = match sub {
+                NetworkSubtitleTrack::Index(_) => MetadataOverrides::default(),
+                NetworkSubtitleTrack::WithOverrides { language, title, .. } => resolve_overrides(language, title)?,
+            };
+            match ffprobe.tracks.iter().find(|track| track.index == idx) {
+                Some(track) if track.kind == TrackType::Subtitle => Ok(SubtitleOptions {
+                    track,
+                    default: request.default_subtitle == Some(idx),
+                    overrides,
+                    file: None,
+                }),
+                Some(_) => Err(JobError::WrongTrackType(idx, TrackType::Subtitle)),
+                None => Err(JobError::NoSuchTrack(idx)),
+            }
+        })
+        .collect::<Result<_, _>>()?;
+    if let Some(idx) = request.default_subtitle {
+        if !request.subtitle_tracks.iter().any(|x| x.track_idx() == idx) {
+            return Err(JobError::DefaultNotSelected(idx));
+        }
+    }
+    request.trim.validate(ffprobe.duration)?;
+    Ok(TranscodeArgs {
+        video_tracks,
+        audio_tracks,
+        subtitle_tracks,
+        extra_ffmpeg_args: request.extra_ffmpeg_args.iter().map(Into::into).collect(),
+        input_ffmpeg_args: request.input_ffmpeg_args.iter().map(Into::into).collect(),
+        output_ffmpeg_args: request.output_ffmpeg_args.iter().map(Into::into).collect(),
+        title: request.title.clone(),
+        duration: ffprobe.duration,
+        force_demux_audio: false,
+        filenames: args.filenames.clone(),
+        add_muxed_silence: false,
+        trim: request.trim,
+        visual: None,
+        burn_subtitles: None,
+    })
+}
+
+#[derive(serde::Serialize)]
+pub struct ProbeResult {
+    pub ffprobe: FFprobeResult,
+    pub report: InputReport,
+}
+
+/// Runs ffprobe on an input file and works out whether this ffmpeg can actually read it.  This
+/// launches ffmpeg and ffprobe, so call it from a blocking context.
+pub fn probe(args: &crate::Args, path: &str) -> Result<ProbeResult, JobError> {
+    let path = crate::common::input_path(args, path)?;
+    let ffprobe = ffprobe(&args.ffmpeg, &path).map_err(JobError::Ffprobe)?;
+    let capabilities = get_capabilities(&args.ffmpeg).map_err(FfmpegError::from)?;
+    let report = check_input(&ffprobe, &capabilities);
+    Ok(ProbeResult { ffprobe, report })
+}
+
+/// Works out what `get_defaults()` would do with an input file, using the named strategy or the
+/// server's default one.  This launches ffmpeg and ffprobe, so call it from a blocking context.
+pub fn defaults(args: &crate::Args, path: &str, strategy: Option<&str>) -> Result<NetworkTranscodeArgs, JobError> {
+    let strategy = match strategy {
+        Some(name) => find_strategy(name).ok_or_else(|| JobError::UnknownStrategy(name.to_owned()))?,
+        None => args.defaults_strategy,
+    };
+    let path = crate::common::input_path(args, path)?;
+    let ffprobe = ffprobe(&args.ffmpeg, &path).map_err(JobError::Ffprobe)?;
+    let capabilities = get_capabilities(&args.ffmpeg).map_err(FfmpegError::from)?;
+    let transcode_args = get_defaults(&ffprobe, &path, &capabilities, &args.defaults_policy, strategy);
+    Ok((&transcode_args).into())
+}
+
+/// Probes the input file and checks that ffmpeg can read it, has every encoder the job asks for and will
+/// accept its arguments.  Returns any warnings, e.g. options we've never heard of.  This launches
+/// ffmpeg and ffprobe, so call it from a blocking context.
+pub fn check_job(args: &crate::Args, path: &str, request: &NetworkTranscodeArgs) -> Result<Vec<TrackProblem>, JobError> {
+    let path = crate::common::input_path(args, path)?;
+    let input_args = request.input_ffmpeg_args.iter().map(Into::into).collect::<Vec<_>>();
+    let ffprobe_result = ffprobe_with_input_args(&args.ffmpeg, &path, &input_args).map_err(JobError::Ffprobe)?;
+    let transcode_args = resolve_transcode_args(args, &ffprobe_result, request)?;
+    let capabilities = get_capabilities(&args.ffmpeg).map_err(FfmpegError::from)?;
+    let input_problems = check_input(&ffprobe_result, &capabilities).problems(&transcode_args);
+    if !input_problems.is_empty() {
+        return Err(JobError::Unreadable(input_problems));
+    }
+    let (warnings, problems): (Vec<_>, Vec<_>) = check_transcode_args(&args.ffmpeg, &transcode_args, &capabilities)
+        .map_err(FfmpegError::from)?
+        .into_iter()
+        .partition(TrackProblem::is_warning);
+    if problems.is_empty() {
+        Ok(warnings)
+    } else {
+        Err(JobError::Rejected(problems))
+    }
+}
@@ -4,12 +4,15 @@ use std::{ffi::{OsStr, OsString}, net::SocketAddr, path::{Path, PathBuf}, sync::
 
 use actix_web::{body::{BoxBody, MessageBody}, get, http::{header::{AcceptEncoding, ContentEncoding, Encoding, Header, HeaderName, VARY}, StatusCode}, post, web::{self, Data, Html}, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder};
 use clap::Parser;
+use cytrans::{config::{parse_env_var, FfmpegConfig}, defaults::{find_strategy, DefaultsPolicy, DefaultsStrategy, STRATEGIES}, filenames::FilenameTemplates};
 use static_hosting::show_404;
 
 mod common;
 mod api;
 mod noscript;
 mod error;
+mod jobs;
+mod outputs;
 mod util;
 #[cfg(feature="static_hosting")]
 mod static_hosting;
@@ -66,6 +69,48 @@ struct ArgsParsed {
     /// cytrans-web-server to host these as well instead of making e.g. nginx do it.
     #[arg(long,long_help)]
     static_dir: Option<PathBuf>,
+    /// Path to the ffmpeg binary to use.  Defaults to looking up ffmpeg in $PATH.
+    #[arg(long,default_value="ffmpeg")]
+    ffmpeg: PathBuf,
+    /// Path to the ffprobe binary to use.  Defaults to looking up ffprobe in $PATH.
+    #[arg(long,default_value="ffprobe")]
+    ffprobe: PathBuf,
+    /// Extra environment variable to set when running ffmpeg and ffprobe, in the form KEY=VALUE.
+    /// May be specified more than once.  Useful for e.g. pointing a custom ffmpeg build at its
+    /// libraries with LD_LIBRARY_PATH.
+    #[arg(long="ffmpeg-env",value_name="KEY=VALUE",value_parser=parse_env_var,long_help)]
+    ffmpeg_env: Vec<(OsString, OsString)>,
+    /// Number of threads each ffmpeg process should use.  Defaults to ffmpeg's own default, which
+    /// is usually one per CPU core.
+    #[arg(long,long_help)]
+    threads: Option<u32>,
+    /// Template for naming transcoded video files, without the extension.  Can use {title},
+    /// {index} (the track's index in the input file), {quality} (e.g. 1080), {codec} and {lang}.
+    /// Names are made unique if the template doesn't manage it by itself.
+    #[arg(long,value_name="TEMPLATE",long_help)]
+    video_filename: Option<String>,
+    /// Template for naming audio files.  Same as --video-filename, minus {quality}.
+    #[arg(long,value_name="TEMPLATE",long_help)]
+    audio_filename: Option<String>,
+    /// Template for naming subtitle files.  Same as --video-filename, minus {quality}.
+    #[arg(long,value_name="TEMPLATE",long_help)]
+    subtitle_filename: Option<String>,
+    /// JSON file of preferences for which tracks /api/defaults picks: e.g.
+    /// {"audio_languages": ["jpn", "eng"], "subtitle_languages": ["eng"], "keep_commentary": false,
+    /// "max_audio_tracks": 2, "max_subtitle_tracks": 1}.  Languages are ISO 639-2 codes, most
+    /// preferred first.  Without one, you get one audio track per language and every text
+    /// subtitle track.
+    #[arg(long,value_name="FILE",long_help)]
+    defaults_policy: Option<PathBuf>,
+    /// How /api/defaults picks codecs when the client doesn't say: standard (copy what browsers
+    /// can play and encode the rest), av1 (encode everything to AV1 and Opus), copy (like standard,
+    /// but keep every audio track) or compatible (H.264 and AAC in MP4).
+    #[arg(long,value_name="NAME",value_parser=parse_strategy,default_value="standard",long_help)]
+    defaults_strategy: &'static dyn DefaultsStrategy,
+}
+
+fn parse_strategy(s: &str) -> Result<&'static dyn DefaultsStrategy, String> {
+    find_strategy(s).ok_or_else(|| format!("expected one of {}", STRATEGIES.iter().map(|x| x.name()).collect::<Vec<_>>().join(", ")))
 }
 
 struct Args {
@@ -73,6 +118,10 @@ struct Args {
     output_dir: PathBuf,
     url_prefix: String,
     static_dir: Option<PathBuf>,
+    ffmpeg: FfmpegConfig,
+    filenames: FilenameTemplates,
+    defaults_policy: DefaultsPolicy,
+    defaults_strategy: &'static dyn DefaultsStrategy,
 }
 async fn host_static(req: HttpRequest, args: Data<Args>) -> HttpResponse<BoxBody> {
     let Some(ref static_path) = args.static_dir else {
@@ -84,13 +133,30 @@ async fn host_static(req: HttpRequest, args: Data<Args>) -> HttpResponse<BoxBody
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
-    let ArgsParsed {address, input_dir, output_dir, static_dir, url_prefix} = ArgsParsed::parse();
+    let ArgsParsed {address, input_dir, output_dir, static_dir, url_prefix, ffmpeg, ffprobe, ffmpeg_env, threads, video_filename, audio_filename, subtitle_filename, defaults_policy, defaults_strategy} = ArgsParsed::parse();
+    let filenames = FilenameTemplates::with_overrides(video_filename, audio_filename, subtitle_filename)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let defaults_policy = match defaults_policy {
+        Some(path) => DefaultsPolicy::load(&path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}: {}", path.display(), e)))?,
+        None => DefaultsPolicy::default(),
+    };
+    let mut ffmpeg = FfmpegConfig {
+        ffmpeg,
+        ffprobe,
+        env: ffmpeg_env,
+        // there's never anyone at the terminal to answer ffmpeg's questions
+        global_args: vec!["-nostdin".into()],
+    };
+    if let Some(threads) = threads {
+        ffmpeg.set_threads(threads);
+    }
     //let output_dir = sneak::Dir::open(output_dir)?;
     //let input_dir = match input_dir {
     //    Some(x) => Some(sneak::Dir::open(x)?),
     //    None => None,
     //};
-    let args = web::Data::new(Args {input_dir, output_dir, static_dir, url_prefix});
+    let args = web::Data::new(Args {input_dir, output_dir, static_dir, url_prefix, ffmpeg, filenames, defaults_policy, defaults_strategy});
 
     HttpServer::new(move || {
         App::new()
@@ -98,6 +164,14 @@ async fn main() -> std::io::Result<()> {
             .app_data(args.clone())
             .service(hello)
             .service(api::browse)
+            .service(api::capabilities)
+            .service(api::refresh_capabilities)
+            .service(api::probe)
+            .service(api::defaults)
+            .service(api::strategies)
+            .service(api::check_job)
+            .service(api::validate)
+            .service(api::remove)
             .default_service(web::to(host_static))
     })
     .bind(&*address)?
@@ -0,0 +1,109 @@
+//! Everything to do with transcoded media once it's sitting in the output directory.
+
+use std::path::{Path, PathBuf};
+
+use actix_web::{http::StatusCode, ResponseError};
+use cytrans::{cytube_structs::{CytubeVideo, Finding}, metadata::{scan_output_directory, ManifestError, ManifestProblem, MetadataManifest, RemoveError, ScanError, ToRemove}};
+
+use crate::common::SanitizePathError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OutputError {
+    #[error("{0}")]
+    Path(#[from] SanitizePathError),
+    #[error("No such output directory")]
+    NotFound,
+    #[error("{0}")]
+    Manifest(#[from] ManifestError),
+    #[error("{0}")]
+    Scan(#[from] ScanError),
+    #[error("{0}")]
+    Remove(#[from] RemoveError),
+    #[error("{0}")]
+    Blocking(#[from] actix_web::error::BlockingError),
+}
+
+impl ResponseError for OutputError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OutputError::Path(error) => error.status_code(),
+            OutputError::NotFound => StatusCode::NOT_FOUND,
+            OutputError::Manifest(_) | OutputError::Scan(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            OutputError::Remove(RemoveError::Manifest(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            OutputError::Remove(RemoveError::PathEscapes(_)) => StatusCode::FORBIDDEN,
+            OutputError::Remove(RemoveError::NotInManifest(_)) => StatusCode::NOT_FOUND,
+            OutputError::Blocking(error) => error.status_code(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct ValidationResult {
+    /// Reasons Cytube would reject the generated manifest.
+    pub findings: Vec<Finding>,
+    /// Disagreements between the manifest and the files on disk.  Only filled in if the client
+    /// asked us to probe the files.
+    pub files: Vec<ManifestProblem>,
+}
+
+/// The URL prefix the files in an output directory get served under.
+pub fn url_prefix(args: &crate::Args, path: &Path) -> String {
+    let mut prefix = args.url_prefix.clone();
+    for component in path.iter() {
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        prefix.push_str(&component.to_string_lossy());
+    }
+    if !prefix.ends_with('/') {
+        prefix.push('/');
+    }
+    prefix
+}
+
+/// Loads the manifest for an output directory, rebuilding it from the files if it's gone
+/// missing.  Returns the directory's full path along with it.  Runs ffprobe, so call it from a
+/// blocking context.
+pub fn load_manifest(args: &crate::Args, path: &str) -> Result<(PathBuf, MetadataManifest), OutputError> {
+    let dir = crate::common::output_path(args, path)?;
+    if !dir.is_dir() {
+        return Err(OutputError::NotFound);
+    }
+    let manifest = match MetadataManifest::load(&dir) {
+        Ok(x) => x,
+        Err(ManifestError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::warn!("{} has no metadata manifest, rebuilding it", dir.display());
+            let (manifest, skipped) = scan_output_directory(&args.ffmpeg, &dir)?;
+            for e in skipped {
+                log::warn!("{}, leaving it out of the manifest", e);
+            }
+            manifest
+        },
+        Err(e) => return Err(e.into()),
+    };
+    Ok((dir, manifest))
+}
+
+/// Checks the Cytube manifest we'd generate for an output directory, and optionally whether the
+/// files in it are what our manifest says they are.
+pub fn validate(args: &crate::Args, path: &str, probe_files: bool) -> Result<ValidationResult, OutputError> {
+    let (dir, manifest) = load_manifest(args, path)?;
+    let relative = dir.strip_prefix(&args.output_dir).unwrap_or(Path::new(""));
+    let findings = manifest.to_cytube(&url_prefix(args, relative)).validate();
+    let files = if probe_files {
+        manifest.validate(&args.ffmpeg, &dir)
+    } else {
+        Vec::new()
+    };
+    Ok(ValidationResult { findings, files })
+}
+
+/// Deletes files from an output directory and updates both its manifests.  Returns the new
+/// Cytube manifest.
+pub fn remove(args: &crate::Args, path: &str, to_remove: &ToRemove) -> Result<CytubeVideo, OutputError> {
+    let (dir, mut manifest) = load_manifest(args, path)?;
+    let relative = dir.strip_prefix(&args.output_dir).unwrap_or(Path::new(""));
+    let url_prefix = url_prefix(args, relative);
+    manifest.apply_removal(&dir, to_remove, &url_prefix)?;
+    Ok(manifest.to_cytube(&url_prefix))
+}
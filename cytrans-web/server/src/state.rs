@@ -151,9 +151,15 @@ impl State {
 
     }
 
-    fn get_meta(&self, slug: &str) -> Option<&mut MetadataManifest> {
-        // TODO
-        None
+    fn get_meta(&self, slug: &str) -> Option<MetadataManifest> {
+        let dir = self.sanitize_path(slug, PathKind::Output).ok()?;
+        match MetadataManifest::load(&dir) {
+            Ok(meta) => Some(meta),
+            Err(e) => {
+                error!("could not load metadata for {}: {}", slug, e);
+                None
+            },
+        }
     }
     
     /*
@@ -21,21 +21,104 @@ pub struct TranscodeRequest<'ff> {
     pub slug: String,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct NetworkTrackOptions<C> {
     pub track_idx: u16,
     pub codec: C,
     pub extra_ffmpeg_args: Vec<String>,
     pub encoder: String,
     pub bitrate: Option<u32>,
+    /// Replaces the language the input file gives the track, as an ISO 639-2 code.
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Replaces the title the input file gives the track.  An empty string removes it.
+    #[serde(default)]
+    pub title: Option<String>,
 }
 
-#[derive(serde::Deserialize)]
+/// A subtitle track to include.  Either just the track index, or the index along with
+/// corrections to the track's metadata.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum NetworkSubtitleTrack {
+    Index(u16),
+    WithOverrides {
+        track_idx: u16,
+        #[serde(default)]
+        language: Option<String>,
+        #[serde(default)]
+        title: Option<String>,
+    },
+}
+
+impl NetworkSubtitleTrack {
+    pub fn track_idx(&self) -> u16 {
+        match self {
+            Self::Index(x) => *x,
+            Self::WithOverrides { track_idx, .. } => *track_idx,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct NetworkTranscodeArgs {
     pub video_tracks: Vec<NetworkTrackOptions<cytrans::options::VideoCodec>>,
     pub audio_tracks: Vec<NetworkTrackOptions<cytrans::options::AudioCodec>>,
-    pub subtitle_tracks: Vec<u16>,
+    pub subtitle_tracks: Vec<NetworkSubtitleTrack>,
+    /// Which of `subtitle_tracks` Cytube should show by default, if any.
+    #[serde(default)]
+    pub default_subtitle: Option<u16>,
     pub extra_ffmpeg_args: Vec<String>,
+    /// Options for the input, which go just before its `-i`.
+    #[serde(default)]
+    pub input_ffmpeg_args: Vec<String>,
+    /// Options for every output file.
+    #[serde(default)]
+    pub output_ffmpeg_args: Vec<String>,
     pub title: String,
-    pub slug: String
+    pub slug: String,
+    /// Which part of the input to keep.  Missing means all of it.
+    #[serde(default)]
+    pub trim: cytrans::options::Trim,
+}
+
+impl<C: Copy> From<&cytrans::options::TrackOptions<'_, C>> for NetworkTrackOptions<C> {
+    fn from(x: &cytrans::options::TrackOptions<'_, C>) -> Self {
+        Self {
+            track_idx: x.track.index,
+            codec: x.codec,
+            extra_ffmpeg_args: x.extra_ffmpeg_args.iter().map(|x| x.to_string_lossy().into_owned()).collect(),
+            encoder: x.encoder.clone(),
+            bitrate: x.bitrate,
+            language: x.overrides.language.map(|x| x.as_str().to_owned()),
+            title: x.overrides.title.clone(),
+        }
+    }
+}
+
+/// The other way round is `resolve_transcode_args()` in server-ng, since that needs the ffprobe
+/// result and can fail.
+impl From<&cytrans::options::TranscodeArgs<'_>> for NetworkTranscodeArgs {
+    fn from(x: &cytrans::options::TranscodeArgs<'_>) -> Self {
+        Self {
+            video_tracks: x.video_tracks.iter().map(Into::into).collect(),
+            audio_tracks: x.audio_tracks.iter().map(Into::into).collect(),
+            subtitle_tracks: x.subtitle_tracks.iter().map(|x| if x.overrides.is_empty() {
+                NetworkSubtitleTrack::Index(x.track.index)
+            } else {
+                NetworkSubtitleTrack::WithOverrides {
+                    track_idx: x.track.index,
+                    language: x.overrides.language.map(|x| x.as_str().to_owned()),
+                    title: x.overrides.title.clone(),
+                }
+            }).collect(),
+            default_subtitle: x.subtitle_tracks.iter().find(|x| x.default).map(|x| x.track.index),
+            extra_ffmpeg_args: x.extra_ffmpeg_args.iter().map(|x| x.to_string_lossy().into_owned()).collect(),
+            input_ffmpeg_args: x.input_ffmpeg_args.iter().map(|x| x.to_string_lossy().into_owned()).collect(),
+            output_ffmpeg_args: x.output_ffmpeg_args.iter().map(|x| x.to_string_lossy().into_owned()).collect(),
+            title: x.title.clone(),
+            slug: cytrans::filenames::slugify(&x.title),
+            trim: x.trim,
+        }
+    }
 }
@@ -1,6 +1,10 @@
-use std::process::{Command, Stdio};
-use crate::options::{AudioCodec, VideoCodec};
-use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, RwLock};
+use crate::config::FfmpegConfig;
+use crate::ffprobe::FFprobeResult;
+use crate::options::{AudioCodec, TrackOptions, TranscodeArgs, VideoCodec};
 use strum::IntoEnumIterator;
 
 pub const BITMAP_SUBTITLE_CODECS: [&'static str; 4] = [
@@ -10,28 +14,289 @@ pub const BITMAP_SUBTITLE_CODECS: [&'static str; 4] = [
     "xsub",
 ];
 
-pub type Capabilities = (Vec<(VideoCodec, Vec<String>)>, Vec<(AudioCodec, Vec<String>)>);
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Capabilities {
+    pub video_encoders: Vec<(VideoCodec, Vec<String>)>,
+    pub audio_encoders: Vec<(AudioCodec, Vec<String>)>,
+    /// Names of every codec ffmpeg can decode, in ffprobe's naming (e.g. "h264", "subrip").
+    pub decoders: Vec<String>,
+    /// Names of every format ffmpeg can demux.  ffmpeg lists some demuxers under several names at
+    /// once (e.g. "matroska,webm"); these are split up.
+    pub demuxers: Vec<String>,
+    pub info: FfmpegInfo,
+    /// Which of the optional features we know about this ffmpeg supports.  Derived from `info`,
+    /// included here so that clients don't have to know the version numbers themselves.
+    pub features: Vec<FfmpegFeature>,
+}
+
+/// What `ffmpeg -version` and `ffmpeg -buildconf` say about the ffmpeg binary.
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize)]
+pub struct FfmpegInfo {
+    /// The version string exactly as ffmpeg reports it, e.g. "6.1.1-3ubuntu5", or
+    /// "N-113456-g0123abcd" for builds from git master.
+    pub version: String,
+    /// (major, minor) for release builds.  None for git builds, which don't have one; use
+    /// the library versions for those.
+    pub release: Option<(u32, u32)>,
+    /// e.g. ("libavcodec", [60, 31, 102])
+    pub libraries: Vec<(String, [u32; 3])>,
+    /// The flags ffmpeg was configured with, e.g. "--enable-libsvtav1".
+    pub configuration: Vec<String>,
+}
+
+/// Optional things that depend on the ffmpeg version or the libraries it was built with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumIter, strum::AsRefStr, serde::Serialize)]
+#[strum(serialize_all="snake_case")]
+#[serde(rename_all="snake_case")]
+pub enum FfmpegFeature {
+    /// -movflags frag_keyframe+empty_moov+default_base_moof, for MP4 files that can be played
+    /// while they're still being written.
+    FragmentedMp4,
+    /// -progress, machine readable progress reports.
+    Progress,
+    /// -stats_period, to control how often progress is reported.
+    StatsPeriod,
+    /// The libsvtav1 encoder.
+    SvtAv1,
+    /// libsvtav1's -svtav1-params option.  Older ffmpeg versions only had a handful of
+    /// individual options for it.
+    SvtAv1Params,
+}
+
+impl FfmpegInfo {
+    pub fn library_version(&self, library: &str) -> Option<[u32; 3]> {
+        self.libraries.iter().find(|(name, _)| name == library).map(|(_, version)| *version)
+    }
+
+    fn library_at_least(&self, library: &str, major: u32, minor: u32) -> bool {
+        self.library_version(library).is_some_and(|[a, b, _]| (a, b) >= (major, minor))
+    }
+
+    /// Whether ffmpeg was configured with e.g. `--enable-libsvtav1`.
+    pub fn is_enabled(&self, library: &str) -> bool {
+        let flag = format!("--enable-{}", library);
+        self.configuration.contains(&flag)
+    }
+
+    // the library versions below are the ones that shipped with the first ffmpeg release to
+    // have the feature.  we go by library versions rather than the ffmpeg version because git
+    // builds don't have an ffmpeg version.
+    pub fn supports(&self, feature: FfmpegFeature) -> bool {
+        use FfmpegFeature::*;
+        match feature {
+            // ffmpeg 2.6
+            FragmentedMp4 => self.library_at_least("libavformat", 56, 25),
+            // ffmpeg 1.0
+            Progress => self.library_at_least("libavformat", 54, 29),
+            // ffmpeg 4.4
+            StatsPeriod => self.library_at_least("libavformat", 58, 76),
+            SvtAv1 => self.is_enabled("libsvtav1"),
+            // ffmpeg 5.1
+            SvtAv1Params => self.is_enabled("libsvtav1") && self.library_at_least("libavcodec", 59, 37),
+        }
+    }
+
+    pub fn features(&self) -> Vec<FfmpegFeature> {
+        FfmpegFeature::iter().filter(|x| self.supports(*x)).collect()
+    }
+}
+
+/// Parses the output of `ffmpeg -version` and `ffmpeg -buildconf`.
+pub fn parse_ffmpeg_info(version_output: &str, buildconf_output: &str) -> FfmpegInfo {
+    let mut info = FfmpegInfo::default();
+
+    for line in version_output.lines() {
+        if let Some(rest) = line.strip_prefix("ffmpeg version ") {
+            info.version = rest.split_whitespace().next().unwrap_or_default().to_string();
+            // release builds are "6.1.1-3ubuntu5", "n7.0" or "7.0.1-static".  git builds are
+            // "N-113456-g0123abcd" and have no release number.
+            let v = info.version.strip_prefix('n').unwrap_or(&info.version);
+            let v = &v[..v.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(v.len())];
+            let mut it = v.split('.').map(str::parse::<u32>);
+            if let Some(Ok(major)) = it.next() {
+                info.release = Some((major, it.next().and_then(Result::ok).unwrap_or(0)));
+            }
+        } else if line.starts_with("lib") {
+            // "libavcodec     60. 31.102 / 60. 31.102"
+            let Some((name, versions)) = line.split_once(' ') else {
+                continue;
+            };
+            let version = versions.split('/').next().unwrap_or_default().replace(' ', "");
+            let mut it = version.split('.').map(str::parse::<u32>);
+            if let (Some(Ok(a)), Some(Ok(b)), Some(Ok(c))) = (it.next(), it.next(), it.next()) {
+                info.libraries.push((name.to_string(), [a, b, c]));
+            }
+        }
+    }
+
+    // -version has the configuration too, but all on one line, which gets ambiguous if any of
+    // the flags contain spaces.  -buildconf puts each flag on its own line.
+    info.configuration = buildconf_output.lines()
+        .map(str::trim)
+        .filter(|x| x.starts_with("--"))
+        .map(str::to_string)
+        .collect();
+
+    info
+}
 
-static FFMPEG_OUTPUT: Lazy<Capabilities> = Lazy::new(|| {
-let out = Command::new("ffmpeg").args(["-hide_banner", "-codecs"])
+#[derive(Debug)]
+pub enum CapabilitiesError {
+    /// ffmpeg couldn't be launched at all, most likely because it isn't installed or the path is
+    /// wrong.
+    Io(std::io::Error),
+    /// ffmpeg ran but exited with an error.
+    FfmpegFailed { status: ExitStatus, stderr: String },
+    InvalidUtf8,
+    /// `ffmpeg -h encoder=...` was asked about an encoder it doesn't have.
+    UnknownEncoder(String),
+}
+
+impl std::fmt::Display for CapabilitiesError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(fmt, "error launching ffmpeg: {}", e),
+            Self::FfmpegFailed { status, stderr } => write!(fmt, "ffmpeg exited with {}: {}", status, stderr.trim()),
+            Self::InvalidUtf8 => fmt.write_str("ffmpeg output wasn't utf8"),
+            Self::UnknownEncoder(name) => write!(fmt, "ffmpeg has no encoder named {}", name),
+        }
+    }
+}
+
+impl std::error::Error for CapabilitiesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CapabilitiesError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// the ffmpeg config the cached capabilities came from, and the capabilities themselves.
+// kept behind a lock rather than a Lazy so that refresh_capabilities() can swap it out after an
+// ffmpeg upgrade without restarting the server.
+static CAPABILITIES: RwLock<Option<(FfmpegConfig, Arc<Capabilities>)>> = RwLock::new(None);
+
+// same idea for query_encoder(), which gets called for every track of every job checked.
+// keyed by encoder name.
+static ENCODERS: RwLock<Option<(FfmpegConfig, HashMap<String, EncoderInfo>)>> = RwLock::new(None);
+
+/// Runs ffmpeg with the given arguments and returns its stdout as a string.
+fn run_ffmpeg(config: &FfmpegConfig, args: &[&str]) -> Result<String, CapabilitiesError> {
+    let out = config.ffmpeg_query_command().arg("-hide_banner").args(args)
+        .stdin(Stdio::null())
         .stdout(Stdio::piped())
-        .spawn().expect("error launching ffmpeg")
-        .wait_with_output().expect("error waiting for ffmpeg");
-        assert!(out.status.success(), "ffmpeg -codecs returned error");
-        let ffmpeg_output = std::str::from_utf8(&out.stdout).expect("ffmpeg output wasn't utf8");
-        (get_encoder_names(ffmpeg_output, VideoCodec::iter().collect()),
-         get_encoder_names(ffmpeg_output, AudioCodec::iter().collect()))
-});
+        .stderr(Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    if !out.status.success() {
+        return Err(CapabilitiesError::FfmpegFailed {
+            status: out.status,
+            stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+        });
+    }
+    String::from_utf8(out.stdout).map_err(|_| CapabilitiesError::InvalidUtf8)
+}
 
-pub fn get_video_encoders() -> &'static Vec<(VideoCodec, Vec<String>)> {
-    &FFMPEG_OUTPUT.0
+/// Asks the configured ffmpeg binary what it can do.  Always launches ffmpeg; use
+/// [`get_capabilities`] if a cached result is acceptable.
+pub fn detect_capabilities(config: &FfmpegConfig) -> Result<Capabilities, CapabilitiesError> {
+    let ffmpeg_output = run_ffmpeg(config, &["-codecs"])?;
+    let info = parse_ffmpeg_info(&run_ffmpeg(config, &["-version"])?, &run_ffmpeg(config, &["-buildconf"])?);
+    Ok(Capabilities {
+        video_encoders: get_encoder_names(&ffmpeg_output, VideoCodec::iter().collect()),
+        audio_encoders: get_encoder_names(&ffmpeg_output, AudioCodec::iter().collect()),
+        decoders: get_decodable_codecs(&ffmpeg_output),
+        demuxers: get_demuxer_names(&run_ffmpeg(config, &["-demuxers"])?),
+        features: info.features(),
+        info,
+    })
 }
-pub fn get_audio_encoders() -> &'static Vec<(AudioCodec, Vec<String>)> {
-    &FFMPEG_OUTPUT.1
+
+/// Returns the capabilities of the configured ffmpeg binary, detecting them on first use.
+pub fn get_capabilities(config: &FfmpegConfig) -> Result<Arc<Capabilities>, CapabilitiesError> {
+    if let Some((cached_config, caps)) = CAPABILITIES.read().unwrap().as_ref() {
+        if cached_config == config {
+            return Ok(caps.clone());
+        }
+    }
+    refresh_capabilities(config)
 }
 
-pub fn get_capabilities() -> &'static Capabilities {
-    &FFMPEG_OUTPUT
+/// Throws away any cached capabilities and asks ffmpeg again.  Call this after upgrading ffmpeg.
+/// If detection fails, the previously cached capabilities are left in place.
+pub fn refresh_capabilities(config: &FfmpegConfig) -> Result<Arc<Capabilities>, CapabilitiesError> {
+    let caps = Arc::new(detect_capabilities(config)?);
+    *CAPABILITIES.write().unwrap() = Some((config.clone(), caps.clone()));
+    // a new ffmpeg could have new encoder options too
+    *ENCODERS.write().unwrap() = None;
+    Ok(caps)
+}
+
+/// Splits a line of `ffmpeg -codecs` output into its flags, codec name, and description.
+/// Returns None for anything that isn't a codec line (headers, the legend, blank lines...)
+fn split_codec_line(line: &str) -> Option<(&[u8], &str, &str)> {
+    let line = line.trim_start();
+    let (flags, rest) = line.split_once(' ')?;
+    // the flags column is always exactly 6 characters wide.  this rules out the "Codecs:" header
+    // and the "-------" separator.
+    if flags.len() != 6 || !flags.is_ascii() {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let (name, description) = rest.split_once(' ').unwrap_or((rest, ""));
+    if name.is_empty() || name == "=" {
+        // legend line, e.g. " .E.... = Encoding supported"
+        return None;
+    }
+    Some((flags.as_bytes(), name, description))
+}
+
+/// Returns the names of every codec `ffmpeg -codecs` says it can decode.
+pub fn get_decodable_codecs(ffmpeg_output: &str) -> Vec<String> {
+    ffmpeg_output.lines()
+        .filter_map(split_codec_line)
+        .filter(|(flags, _, _)| flags[0] == b'D')
+        .map(|(_, name, _)| name.to_string())
+        .collect()
+}
+
+/// Parses the output of `ffmpeg -demuxers`.
+pub fn get_demuxer_names(ffmpeg_output: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    // everything above the "--" line is the legend.  if there isn't one, the output is something
+    // we don't understand and we parse the whole lot on a best-effort basis.
+    let is_separator = |line: &str| line.trim().starts_with("--") && line.trim().bytes().all(|x| x == b'-');
+    let skip = ffmpeg_output.lines().position(is_separator).map_or(0, |pos| pos + 1);
+    for line in ffmpeg_output.lines().skip(skip) {
+        if line.contains(" = ") {
+            continue;
+        }
+        // " D  matroska,webm   Matroska / WebM", or on newer versions with a column for
+        // devices, " D d alsa            ALSA audio input".  the flags can end up split over
+        // several words, so skip words until we find one that isn't made of flags.
+        let mut words = line.split_whitespace();
+        let mut demux = false;
+        let name = loop {
+            match words.next() {
+                Some(word) if word.len() <= 3 && word.bytes().all(|x| b"DEd.".contains(&x)) => {
+                    demux |= word.contains('D');
+                },
+                Some(word) => break Some(word),
+                None => break None,
+            }
+        };
+        if let (true, Some(name)) = (demux, name) {
+            result.extend(name.split(',').map(str::to_string));
+        }
+    }
+    result
 }
 
 pub fn get_encoder_names<T: AsRef<str>>(ffmpeg_output: &str, mut codec_names: Vec<T>) -> Vec<(T, Vec<String>)> {
@@ -46,16 +311,14 @@ pub fn get_encoder_names<T: AsRef<str>>(ffmpeg_output: &str, mut codec_names: Ve
     */
     let mut result = Vec::new();
 
-    let lines = ffmpeg_output.lines();
-
-    'a: for line in lines {
-        if line.as_bytes()[2] != b'E' {
+    'a: for line in ffmpeg_output.lines() {
+        let Some((flags, codec_name, line)) = split_codec_line(line) else {
+            continue;
+        };
+        if flags[1] != b'E' {
             // ffmpeg can't encode this codec
             continue;
         }
-        let line = &line[8..];
-        let tab_idx = line.find(' ').unwrap();
-        let codec_name = &line[0..tab_idx];
         let idx = 'b: {
             for (i,name) in codec_names.iter().enumerate() {
                 if name.as_ref() == codec_name {
@@ -65,24 +328,441 @@ pub fn get_encoder_names<T: AsRef<str>>(ffmpeg_output: &str, mut codec_names: Ve
             continue 'a;
         };
         let codec = codec_names.swap_remove(idx);
-        let line = &line[tab_idx..];
-        
-        if let Some(idx) = line.find("(encoders: ") {
-            let line = &line[idx+11..];
-            let line = &line[..line.find(')').unwrap()];
-            let mut res = line.split(' ').collect::<Vec<_>>();
-            if res[res.len()-1].is_empty() {
-                res.remove(res.len()-1);
+
+        let encoders = line.find("(encoders: ")
+            .map(|idx| {
+                let line = &line[idx+11..];
+                let line = &line[..line.find(')').unwrap_or(line.len())];
+                line.split_whitespace().map(str::to_string).collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        result.push((codec, encoders));
+    }
+
+    result
+}
+
+/// A private option accepted by a particular encoder, as listed by `ffmpeg -h encoder=NAME`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct EncoderOption {
+    pub name: String,
+    /// The type ffmpeg reports for the option, e.g. "int", "string" or "flags".
+    pub kind: String,
+    /// Named constants the option accepts.  Empty if the option takes free-form values.
+    pub constants: Vec<String>,
+}
+
+/// Everything `ffmpeg -h encoder=NAME` tells us about an encoder.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct EncoderInfo {
+    pub name: String,
+    pub options: Vec<EncoderOption>,
+    /// Empty if ffmpeg didn't say, which means the encoder doesn't restrict them.
+    pub pixel_formats: Vec<String>,
+    /// Empty if ffmpeg didn't say, which means the encoder doesn't restrict them.
+    pub sample_rates: Vec<u32>,
+}
+
+impl EncoderInfo {
+    pub fn option(&self, name: &str) -> Option<&EncoderOption> {
+        self.options.iter().find(|x| x.name == name)
+    }
+}
+
+/// What ffmpeg says about an encoder.  Only launches ffmpeg the first time each encoder is asked
+/// about, until [`refresh_capabilities`] is called.
+pub fn query_encoder(config: &FfmpegConfig, encoder: &str) -> Result<EncoderInfo, CapabilitiesError> {
+    if let Some((cached_config, encoders)) = ENCODERS.read().unwrap().as_ref() {
+        if let Some(info) = encoders.get(encoder).filter(|_| cached_config == config) {
+            return Ok(info.clone());
+        }
+    }
+    let output = run_ffmpeg(config, &["-h", &format!("encoder={}", encoder)])?;
+    // ffmpeg exits successfully even if it's never heard of the encoder, it just prints a
+    // complaint instead of the help text.
+    let info = parse_encoder_help(&output).ok_or_else(|| CapabilitiesError::UnknownEncoder(encoder.to_string()))?;
+    let mut cache = ENCODERS.write().unwrap();
+    match cache.as_mut() {
+        Some((cached_config, encoders)) if cached_config == config => {
+            encoders.insert(encoder.to_string(), info.clone());
+        },
+        _ => *cache = Some((config.clone(), HashMap::from([(encoder.to_string(), info.clone())]))),
+    }
+    Ok(info)
+}
+
+pub fn parse_encoder_help(output: &str) -> Option<EncoderInfo> {
+    let mut lines = output.lines();
+    let name = lines.by_ref()
+        .find_map(|line| line.strip_prefix("Encoder "))?
+        .split_whitespace()
+        .next()?
+        .to_string();
+
+    let mut info = EncoderInfo {
+        name,
+        options: Vec::new(),
+        pixel_formats: Vec::new(),
+        sample_rates: Vec::new(),
+    };
+
+    for line in lines {
+        let trimmed = line.trim();
+        if let Some(formats) = trimmed.strip_prefix("Supported pixel formats:") {
+            info.pixel_formats = formats.split_whitespace().map(str::to_string).collect();
+        } else if let Some(rates) = trimmed.strip_prefix("Supported sample rates:") {
+            info.sample_rates = rates.split_whitespace().filter_map(|x| x.parse().ok()).collect();
+        } else if let Some(option) = trimmed.strip_prefix('-') {
+            // "  -crf               <float>      E..V....... Select the quality for..."
+            let mut it = option.split_whitespace();
+            let (Some(name), Some(kind)) = (it.next(), it.next()) else {
+                continue;
+            };
+            let Some(kind) = kind.strip_prefix('<').and_then(|x| x.strip_suffix('>')) else {
+                continue;
+            };
+            info.options.push(EncoderOption {
+                name: name.to_string(),
+                kind: kind.to_string(),
+                constants: Vec::new(),
+            });
+        } else if line.starts_with("     ") && !trimmed.is_empty() {
+            // named constants are indented further than the option they belong to and don't
+            // start with a dash.
+            // "     variance        1            E..V....... Variance AQ (complexity mask)"
+            if let (Some(option), Some(constant)) = (info.options.last_mut(), trimmed.split_whitespace().next()) {
+                option.constants.push(constant.to_string());
             }
-            result.push((codec, res.iter().map(|x|x.to_string()).collect::<Vec<_>>()));
+        }
+    }
+
+    Some(info)
+}
+
+// options that ffmpeg accepts for any output stream regardless of the encoder, and whether they
+// take a value.  ffmpeg has hundreds of these; this is the subset that people actually put in
+// extra_ffmpeg_args, plus the muxer options for the containers we produce.  anything else only
+// gets a warning, since it's more likely to be missing from here than to be a typo.
+const GENERIC_OPTIONS: &[(&str, bool)] = &[
+    ("c", true), ("codec", true), ("map", true), ("bsf", true), ("fflags", true),
+    ("force_key_frames", true), ("sws_flags", true), ("x264opts", true), ("x264-params", true), ("x265-params", true),
+    ("b", true), ("maxrate", true), ("minrate", true), ("bufsize", true),
+    ("g", true), ("keyint_min", true), ("bf", true), ("refs", true), ("sc_threshold", true),
+    ("q", true), ("qscale", true), ("qmin", true), ("qmax", true), ("global_quality", true),
+    ("compression_level", true), ("cutoff", true), ("frame_size", true),
+    ("profile", true), ("level", true), ("flags", true), ("strict", true), ("threads", true),
+    ("pix_fmt", true), ("r", true), ("s", true), ("aspect", true), ("fps_mode", true), ("vsync", true),
+    ("color_primaries", true), ("color_trc", true), ("colorspace", true), ("color_range", true),
+    ("ar", true), ("ac", true), ("ch_layout", true), ("channel_layout", true), ("sample_fmt", true),
+    ("vf", true), ("af", true), ("filter", true), ("metadata", true), ("disposition", true),
+    ("tag", true), ("frames", true), ("vframes", true), ("aframes", true),
+    ("t", true), ("to", true), ("ss", true), ("f", true), ("map_metadata", true), ("map_chapters", true),
+    ("max_muxing_queue_size", true), ("avoid_negative_ts", true),
+    ("movflags", true), ("frag_duration", true), ("min_frag_duration", true), ("brand", true),
+    ("cluster_size_limit", true), ("cluster_time_limit", true), ("dash", true), ("live", true),
+    ("page_duration", true), ("serial_offset", true),
+    ("an", false), ("vn", false), ("sn", false), ("dn", false), ("shortest", false),
+];
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub enum ArgProblem {
+    /// ffmpeg can't encode the chosen codec with the chosen encoder.
+    EncoderNotAvailable { codec: String, encoder: String },
+    /// Neither the encoder nor ffmpeg itself knows this option, as far as we can tell.  Could be a
+    /// typo, could be one of the many options we don't know about.  Only a warning.
+    UnknownOption { encoder: String, option: String },
+    /// An option that requires a value was the last thing in the argument list.
+    MissingValue { option: String },
+    /// The option only accepts certain named values, and this wasn't one of them.
+    InvalidValue { option: String, value: String, accepted: Vec<String> },
+    UnsupportedPixelFormat { encoder: String, pixel_format: String },
+    UnsupportedSampleRate { encoder: String, sample_rate: String },
+    /// Something that isn't an option where an option was expected.
+    StrayArgument(String),
+    /// The option needs a newer ffmpeg, or one built with a library this one wasn't.
+    Unsupported { option: String, feature: FfmpegFeature },
+}
+
+impl std::fmt::Display for ArgProblem {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EncoderNotAvailable { codec, encoder } => write!(fmt, "this ffmpeg can't encode {} using {}", codec, encoder),
+            Self::UnknownOption { encoder, option } => write!(fmt, "-{} is not an option of {} or of ffmpeg", option, encoder),
+            Self::MissingValue { option } => write!(fmt, "-{} requires a value", option),
+            Self::InvalidValue { option, value, accepted } => write!(fmt, "\"{}\" is not a valid value for -{} (expected one of: {})", value, option, accepted.join(", ")),
+            Self::UnsupportedPixelFormat { encoder, pixel_format } => write!(fmt, "{} does not support pixel format {}", encoder, pixel_format),
+            Self::UnsupportedSampleRate { encoder, sample_rate } => write!(fmt, "{} does not support sample rate {}", encoder, sample_rate),
+            Self::StrayArgument(arg) => write!(fmt, "unexpected argument \"{}\"", arg),
+            Self::Unsupported { option, feature } => write!(fmt, "-{} needs {} support, which this ffmpeg doesn't have", option, feature.as_ref()),
+        }
+    }
+}
+
+impl ArgProblem {
+    /// Whether ffmpeg might well accept the job anyway, so it shouldn't be rejected over this.
+    pub fn is_warning(&self) -> bool {
+        matches!(self, Self::UnknownOption { .. })
+    }
+}
+
+/// The name of the ffmpeg encoder that will actually be used for `options`, or None if the track
+/// is being copied.
+pub fn encoder_name<'a, C: AsRef<str>>(options: &'a TrackOptions<'_, C>) -> Option<&'a str> {
+    match options.encoder.as_str() {
+        "copy" => None,
+        "" => Some(options.codec.as_ref()),
+        x => Some(x),
+    }
+}
+
+/// Whether ffmpeg has `encoder` for `codec`.  `available` is the relevant half of
+/// [`Capabilities`].
+pub fn has_encoder<C: PartialEq + AsRef<str>>(available: &[(C, Vec<String>)], codec: &C, encoder: &str) -> bool {
+    available.iter()
+        .find(|(c, _)| c == codec)
+        .is_some_and(|(c, encoders)| if encoders.is_empty() {
+            // ffmpeg didn't list any encoders, which means the encoder has the same name as the
+            // codec.
+            c.as_ref() == encoder
         } else {
-            result.push((codec, vec![]));
+            encoders.iter().any(|x| x == encoder)
+        })
+}
+
+/// Checks that the encoder chosen for `options` is one ffmpeg actually has for the chosen codec.
+/// `available` is the relevant half of [`Capabilities`].
+pub fn check_encoder<C: PartialEq + AsRef<str>>(options: &TrackOptions<C>, available: &[(C, Vec<String>)]) -> Option<ArgProblem> {
+    let encoder = encoder_name(options)?;
+    if has_encoder(available, &options.codec, encoder) {
+        None
+    } else {
+        Some(ArgProblem::EncoderNotAvailable {codec: options.codec.as_ref().to_string(), encoder: encoder.to_string()})
+    }
+}
+
+/// Checks `extra_ffmpeg_args` against the options the encoder supports.  `encoder` should be
+/// None if the track is being copied, in which case only ffmpeg's own options are accepted.
+pub fn validate_ffmpeg_args(extra_ffmpeg_args: &[OsString], encoder: Option<&EncoderInfo>) -> Vec<ArgProblem> {
+    let mut problems = Vec::new();
+    let encoder_label = encoder.map(|x| x.name.as_str()).unwrap_or("copy");
+    let mut it = extra_ffmpeg_args.iter().map(|x| x.to_string_lossy()).peekable();
+
+    while let Some(arg) = it.next() {
+        let Some(option) = arg.strip_prefix('-') else {
+            problems.push(ArgProblem::StrayArgument(arg.into_owned()));
+            continue;
+        };
+        // strip any stream specifier, e.g. -b:v or -crf:v:0
+        let name = option.split(':').next().unwrap_or(option);
+
+        let private = encoder.and_then(|x| x.option(name));
+        let takes_value = match (private, GENERIC_OPTIONS.iter().find(|(x, _)| *x == name)) {
+            (Some(_), _) => true,
+            (None, Some((_, takes_value))) => *takes_value,
+            (None, None) => {
+                problems.push(ArgProblem::UnknownOption {encoder: encoder_label.to_string(), option: option.to_string()});
+                // we can't know whether it was supposed to take a value, but most options do, so
+                // skip over anything that doesn't look like the next option.
+                it.next_if(|x| !x.starts_with('-'));
+                continue;
+            },
+        };
+        if !takes_value {
+            continue;
+        }
+        let Some(value) = it.next() else {
+            problems.push(ArgProblem::MissingValue {option: option.to_string()});
+            break;
+        };
+
+        if let Some(private) = private {
+            // numbers are always accepted in place of a named constant, and flags can be
+            // combined with +/-, so only check the simple case.
+            if !private.constants.is_empty() && private.kind != "flags" && value.parse::<f64>().is_err() && !private.constants.iter().any(|x| *x == value) {
+                problems.push(ArgProblem::InvalidValue {option: option.to_string(), value: value.into_owned(), accepted: private.constants.clone()});
+            }
+        } else if let Some(encoder) = encoder {
+            match name {
+                "pix_fmt" if !encoder.pixel_formats.is_empty() && !encoder.pixel_formats.iter().any(|x| *x == value) => {
+                    problems.push(ArgProblem::UnsupportedPixelFormat {encoder: encoder.name.clone(), pixel_format: value.into_owned()});
+                },
+                "ar" if !encoder.sample_rates.is_empty() && !value.parse().is_ok_and(|rate: u32| encoder.sample_rates.contains(&rate)) => {
+                    problems.push(ArgProblem::UnsupportedSampleRate {encoder: encoder.name.clone(), sample_rate: value.into_owned()});
+                },
+                _ => {},
+            }
         }
     }
 
-    result
+    problems
+}
+
+/// Checks for options that only work on some ffmpeg versions or builds.  Unlike
+/// [`validate_ffmpeg_args`] this doesn't complain about options it doesn't know, so it can be used
+/// on job-level arguments as well as per-track ones.
+pub fn check_feature_gates(extra_ffmpeg_args: &[OsString], info: &FfmpegInfo) -> Vec<ArgProblem> {
+    let mut problems = Vec::new();
+    let mut it = extra_ffmpeg_args.iter().map(|x| x.to_string_lossy()).peekable();
+    while let Some(arg) = it.next() {
+        let Some(option) = arg.strip_prefix('-') else {
+            continue;
+        };
+        let name = option.split(':').next().unwrap_or(option);
+        let feature = match name {
+            "progress" => Some(FfmpegFeature::Progress),
+            "stats_period" => Some(FfmpegFeature::StatsPeriod),
+            "svtav1-params" => Some(FfmpegFeature::SvtAv1Params),
+            "movflags" => it.peek()
+                .filter(|value| ["frag_keyframe", "empty_moov", "default_base_moof"].iter().any(|flag| value.contains(flag)))
+                .map(|_| FfmpegFeature::FragmentedMp4),
+            _ => None,
+        };
+        if let Some(feature) = feature.filter(|x| !info.supports(*x)) {
+            problems.push(ArgProblem::Unsupported {option: option.to_string(), feature});
+        }
+    }
+    problems
+}
+
+/// Runs every check we have on a single output track: whether the encoder exists, and whether
+/// ffmpeg will accept its extra arguments.  Launches ffmpeg to ask about the encoder.
+pub fn check_track_options<C: PartialEq + AsRef<str>>(config: &FfmpegConfig, options: &TrackOptions<C>, available: &[(C, Vec<String>)]) -> Result<Vec<ArgProblem>, CapabilitiesError> {
+    if let Some(problem) = check_encoder(options, available) {
+        return Ok(vec![problem]);
+    }
+    let info = match encoder_name(options) {
+        Some(encoder) => Some(query_encoder(config, encoder)?),
+        None => None,
+    };
+    Ok(validate_ffmpeg_args(&options.extra_ffmpeg_args, info.as_ref()))
 }
 
+/// An [`ArgProblem`] along with the index of the input track whose output it was found in, or
+/// None if it was found in the job-level arguments.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct TrackProblem {
+    pub track: Option<u16>,
+    pub problem: ArgProblem,
+}
+
+impl TrackProblem {
+    pub fn is_warning(&self) -> bool {
+        self.problem.is_warning()
+    }
+}
+
+impl std::fmt::Display for TrackProblem {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.track {
+            Some(track) => write!(fmt, "track #{}: {}", track, self.problem),
+            None => write!(fmt, "{}", self.problem),
+        }
+    }
+}
+
+/// Runs [`check_track_options`] on every video and audio track of a job.  If everything in the
+/// result is a warning, ffmpeg should accept the job's per-track arguments.
+pub fn check_transcode_args(config: &FfmpegConfig, args: &TranscodeArgs, capabilities: &Capabilities) -> Result<Vec<TrackProblem>, CapabilitiesError> {
+    let mut problems = [&args.extra_ffmpeg_args, &args.input_ffmpeg_args, &args.output_ffmpeg_args].into_iter()
+        .flat_map(|x| check_feature_gates(x, &capabilities.info))
+        .map(|problem| TrackProblem {track: None, problem})
+        .collect::<Vec<_>>();
+    for video in args.video_tracks.iter() {
+        let track = Some(video.track.index);
+        problems.extend(check_track_options(config, video, &capabilities.video_encoders)?.into_iter().map(|problem| TrackProblem {track, problem}));
+        problems.extend(check_feature_gates(&video.extra_ffmpeg_args, &capabilities.info).into_iter().map(|problem| TrackProblem {track, problem}));
+    }
+    for audio in args.audio_tracks.iter() {
+        let track = Some(audio.track.index);
+        problems.extend(check_track_options(config, audio, &capabilities.audio_encoders)?.into_iter().map(|problem| TrackProblem {track, problem}));
+        problems.extend(check_feature_gates(&audio.extra_ffmpeg_args, &capabilities.info).into_iter().map(|problem| TrackProblem {track, problem}));
+    }
+    Ok(problems)
+}
+
+/// Whether ffmpeg can do anything with an input file, worked out from its ffprobe results before
+/// any time is wasted on trying to transcode it.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InputReport {
+    /// The demuxer ffprobe used.  None if ffprobe didn't say, in which case `can_demux` is a guess.
+    pub format: Option<String>,
+    pub can_demux: bool,
+    pub tracks: Vec<TrackReport>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TrackReport {
+    pub index: u16,
+    pub codec: String,
+    pub can_decode: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub enum InputProblem {
+    CannotDemux(String),
+    CannotDecode { track: u16, codec: String },
+}
+
+impl std::fmt::Display for InputProblem {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CannotDemux(format) => write!(fmt, "this ffmpeg can't read {} files", format),
+            Self::CannotDecode { track, codec } => write!(fmt, "track #{}: this ffmpeg can't decode {}", track, codec),
+        }
+    }
+}
+
+pub fn check_input(ffprobe: &FFprobeResult, capabilities: &Capabilities) -> InputReport {
+    let can_demux = match &ffprobe.format {
+        Some(format) => format.split(',').any(|name| capabilities.demuxers.iter().any(|x| x == name)),
+        // ffprobe managed to read the file, and ffprobe and ffmpeg are usually built together, so
+        // give it the benefit of the doubt.
+        None => true,
+    };
+    InputReport {
+        format: ffprobe.format.clone(),
+        can_demux,
+        tracks: ffprobe.tracks.iter().map(|track| TrackReport {
+            index: track.index,
+            codec: track.codec.clone(),
+            can_decode: capabilities.decoders.contains(&track.codec),
+        }).collect(),
+    }
+}
+
+impl InputReport {
+    pub fn demux_problem(&self) -> Option<InputProblem> {
+        if self.can_demux {
+            None
+        } else {
+            Some(InputProblem::CannotDemux(self.format.clone().unwrap_or_default()))
+        }
+    }
+
+    /// Checks whether a track can be used as a source.  Tracks that are being copied don't need
+    /// to be decoded, so `copied` tracks always pass.
+    pub fn check_track(&self, index: u16, copied: bool) -> Option<InputProblem> {
+        if copied {
+            return None;
+        }
+        let track = self.tracks.iter().find(|x| x.index == index)?;
+        if track.can_decode {
+            None
+        } else {
+            Some(InputProblem::CannotDecode {track: index, codec: track.codec.clone()})
+        }
+    }
+
+    /// Every reason ffmpeg won't be able to read what `args` asks it to.
+    pub fn problems(&self, args: &TranscodeArgs) -> Vec<InputProblem> {
+        self.demux_problem().into_iter()
+            .chain(args.video_tracks.iter().filter_map(|x| self.check_track(x.track.index, x.encoder == "copy")))
+            .chain(args.audio_tracks.iter().filter_map(|x| self.check_track(x.track.index, x.encoder == "copy")))
+            // subtitles always get converted to webvtt, so they always need decoding
+            .chain(args.subtitle_tracks.iter().filter_map(|x| self.check_track(x.track.index, false)))
+            .collect()
+    }
+}
 
 /*
 pub fn get_encoder_names(typ: char) -> HashMap<String, Vec<String>> {
@@ -120,3 +800,164 @@ pub fn get_encoder_names(typ: char) -> HashMap<String, Vec<String>> {
     result
 }
 */
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CODECS_OUTPUT: &str = "Codecs:
+ D..... = Decoding supported
+ .E.... = Encoding supported
+ ..V... = Video codec
+ ..A... = Audio codec
+ -------
+
+ D.VI.S 012v                 Uncompressed 4:2:2 10-bit
+ DEV.L. av1                  Alliance for Open Media AV1 (decoders: libdav1d libaom-av1 av1 ) (encoders: libaom-av1 libsvtav1 )
+ DEV.LS h264                 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10 (decoders: h264 h264_v4l2m2m ) (encoders: libx264 libx264rgb h264_vaapi )
+ D.V.L. vp8                  On2 VP8 (decoders: vp8 libvpx )
+ DEA.L. aac                  AAC (Advanced Audio Coding) (decoders: aac aac_fixed )
+ DEA.L. opus                 Opus (Opus Interactive Audio Codec) (decoders: opus libopus ) (encoders: opus libopus )
+ DEA..S flac                 FLAC (Free Lossless Audio Codec)
+";
+
+    #[test]
+    fn test_get_encoder_names() {
+        let video = get_encoder_names(CODECS_OUTPUT, VideoCodec::iter().collect());
+        assert_eq!(video, vec![
+            (VideoCodec::AV1, vec!["libaom-av1".to_string(), "libsvtav1".to_string()]),
+            (VideoCodec::H264, vec!["libx264".to_string(), "libx264rgb".to_string(), "h264_vaapi".to_string()]),
+        ]);
+        let audio = get_encoder_names(CODECS_OUTPUT, AudioCodec::iter().collect());
+        assert_eq!(audio, vec![
+            (AudioCodec::AAC, vec![]),
+            (AudioCodec::Opus, vec!["opus".to_string(), "libopus".to_string()]),
+            (AudioCodec::FLAC, vec![]),
+        ]);
+    }
+
+    const ENCODER_HELP: &str = "Encoder libx264 [libx264 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10]:
+    General capabilities: dr1 delay threads 
+    Threading capabilities: other
+    Supported pixel formats: yuv420p yuvj420p yuv422p yuv444p nv12 yuv420p10le
+libx264 AVOptions:
+  -preset            <string>     E..V....... Set the encoding preset (cf. x264 --fullhelp) (default \"medium\")
+  -crf               <float>      E..V....... Select the quality for constant quality mode (from -1 to FLT_MAX) (default -1)
+  -aq-mode           <int>        E..V....... AQ method (from -1 to INT_MAX) (default -1)
+     none            0            E..V.......
+     variance        1            E..V....... Variance AQ (complexity mask)
+     autovariance    2            E..V....... Auto-variance AQ
+  -x264-params       <dictionary> E..V....... Override the x264 configuration using a :-separated list of key=value parameters
+
+";
+
+    fn args(s: &str) -> Vec<OsString> {
+        s.split(' ').map(Into::into).collect()
+    }
+
+    #[test]
+    fn test_parse_encoder_help() {
+        let info = parse_encoder_help(ENCODER_HELP).unwrap();
+        assert_eq!(info.name, "libx264");
+        assert_eq!(info.pixel_formats, ["yuv420p", "yuvj420p", "yuv422p", "yuv444p", "nv12", "yuv420p10le"]);
+        assert!(info.sample_rates.is_empty());
+        assert_eq!(info.options.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), ["preset", "crf", "aq-mode", "x264-params"]);
+        assert_eq!(info.option("aq-mode").unwrap().constants, ["none", "variance", "autovariance"]);
+        assert_eq!(info.option("crf").unwrap().kind, "float");
+
+        assert_eq!(parse_encoder_help("Codec 'libx265' is not recognized by FFmpeg.\n"), None);
+    }
+
+    #[test]
+    fn test_validate_ffmpeg_args() {
+        let info = parse_encoder_help(ENCODER_HELP).unwrap();
+        assert_eq!(validate_ffmpeg_args(&args("-crf 20 -preset slow -b:v 2M -aq-mode variance -pix_fmt yuv420p10le"), Some(&info)), vec![]);
+        assert_eq!(validate_ffmpeg_args(&args("-map 0:v:0 -bsf:v h264_mp4toannexb -force_key_frames expr:gte(t,n_forced*2) -sws_flags lanczos"), Some(&info)), vec![]);
+        let problems = validate_ffmpeg_args(&args("-cfr 20"), Some(&info));
+        assert_eq!(problems, vec![
+            ArgProblem::UnknownOption {encoder: "libx264".into(), option: "cfr".into()},
+        ]);
+        assert!(problems[0].is_warning());
+        assert_eq!(validate_ffmpeg_args(&args("-aq-mode variant -pix_fmt rgb24 -crf"), Some(&info)), vec![
+            ArgProblem::InvalidValue {option: "aq-mode".into(), value: "variant".into(), accepted: vec!["none".into(), "variance".into(), "autovariance".into()]},
+            ArgProblem::UnsupportedPixelFormat {encoder: "libx264".into(), pixel_format: "rgb24".into()},
+            ArgProblem::MissingValue {option: "crf".into()},
+        ]);
+        assert!(!ArgProblem::MissingValue {option: "crf".into()}.is_warning());
+        // encoder-specific options make no sense when copying
+        assert_eq!(validate_ffmpeg_args(&args("-crf 20"), None), vec![
+            ArgProblem::UnknownOption {encoder: "copy".into(), option: "crf".into()},
+        ]);
+    }
+
+    const VERSION_OUTPUT: &str = "ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023 the FFmpeg developers
+built with gcc 13 (Ubuntu 13.2.0-23ubuntu3)
+configuration: --prefix=/usr --extra-version=3ubuntu5 --enable-gpl --enable-libsvtav1
+libavutil      58. 29.100 / 58. 29.100
+libavcodec     60. 31.102 / 60. 31.102
+libavformat    60. 16.100 / 60. 16.100
+";
+    const BUILDCONF_OUTPUT: &str = "  configuration:
+    --prefix=/usr
+    --extra-version=3ubuntu5
+    --enable-gpl
+    --enable-libsvtav1
+";
+
+    #[test]
+    fn test_parse_ffmpeg_info() {
+        let info = parse_ffmpeg_info(VERSION_OUTPUT, BUILDCONF_OUTPUT);
+        assert_eq!(info.version, "6.1.1-3ubuntu5");
+        assert_eq!(info.release, Some((6, 1)));
+        assert_eq!(info.library_version("libavcodec"), Some([60, 31, 102]));
+        assert_eq!(info.configuration, ["--prefix=/usr", "--extra-version=3ubuntu5", "--enable-gpl", "--enable-libsvtav1"]);
+        assert_eq!(info.features(), FfmpegFeature::iter().collect::<Vec<_>>());
+
+        let git = parse_ffmpeg_info("ffmpeg version N-113456-g0123abcd Copyright (c) 2000-2024 the FFmpeg developers\nlibavformat    54. 63.104 / 54. 63.104\n", "");
+        assert_eq!(git.version, "N-113456-g0123abcd");
+        assert_eq!(git.release, None);
+        assert_eq!(git.features(), [FfmpegFeature::Progress]);
+        assert_eq!(check_feature_gates(&args("-movflags +frag_keyframe+empty_moov -stats_period 5 -progress -"), &git), vec![
+            ArgProblem::Unsupported {option: "movflags".into(), feature: FfmpegFeature::FragmentedMp4},
+            ArgProblem::Unsupported {option: "stats_period".into(), feature: FfmpegFeature::StatsPeriod},
+        ]);
+
+        assert_eq!(parse_ffmpeg_info("ffmpeg version n7.0 Copyright (c) 2000-2024 the FFmpeg developers\n", "").release, Some((7, 0)));
+    }
+
+    #[test]
+    fn test_decoders_and_demuxers() {
+        assert_eq!(get_decodable_codecs(CODECS_OUTPUT), ["012v", "av1", "h264", "vp8", "aac", "opus", "flac"]);
+
+        let old = "File formats:
+ D. = Demuxing supported
+ .E = Muxing supported
+ --
+ D  aac             raw ADTS AAC (Advanced Audio Coding)
+ DE flac            raw FLAC
+  E webm            WebM
+ D  matroska,webm   Matroska / WebM
+";
+        assert_eq!(get_demuxer_names(old), ["aac", "flac", "matroska", "webm"]);
+        let new = "Demuxers:
+ D.. = Demuxing supported
+ .E. = Muxing supported
+ ..d = Is a device
+ ---
+ D   aac             raw ADTS AAC (Advanced Audio Coding)
+ D d alsa            ALSA audio input
+ D   mov,mp4,m4a,3gp,3g2,mj2 QuickTime / MOV
+";
+        assert_eq!(get_demuxer_names(new), ["aac", "alsa", "mov", "mp4", "m4a", "3gp", "3g2", "mj2"]);
+    }
+
+    #[test]
+    fn test_get_encoder_names_garbage() {
+        // none of these should panic
+        assert!(get_encoder_names("", VideoCodec::iter().collect()).is_empty());
+        assert!(get_encoder_names("\n\n  \nE\n.E\n", VideoCodec::iter().collect()).is_empty());
+        assert!(get_encoder_names(" DEV.LS", VideoCodec::iter().collect()).is_empty());
+        assert_eq!(get_encoder_names(" DEV.LS h264 (encoders: libx264", VideoCodec::iter().collect()),
+            vec![(VideoCodec::H264, vec!["libx264".to_string()])]);
+    }
+}
@@ -0,0 +1,180 @@
+//! Joining several input files into one Cytube item, for movies that come as CD1/CD2 and
+//! recordings that got split partway through.
+//!
+//! This uses ffmpeg's concat demuxer rather than the concat filter.  The demuxer offsets every
+//! stream's timestamps by the length of the files before it, subtitles included, and it lets
+//! tracks be copied.  Filters can't touch subtitle streams at all.  The catch is that every
+//! file has to have the same tracks in the same order with the same codecs, which is what
+//! `concat_probe()` checks.
+
+use std::io::Write as _;
+use std::path::Path;
+
+use crate::ffprobe::{FFprobeResult, Track};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConcatError {
+    NoInputs,
+    /// Input number `input` (counting from 0) has a different number of tracks to the first.
+    TrackCount { input: usize, expected: usize, found: usize },
+    /// A track in input number `input` doesn't match the same track in the first input.
+    Mismatch { input: usize, track: u16, what: &'static str, expected: String, found: String },
+}
+
+impl std::fmt::Display for ConcatError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoInputs => write!(fmt, "no input files to join"),
+            Self::TrackCount { input, expected, found } => write!(fmt, "input #{} has {} tracks but the first input has {}", input + 1, found, expected),
+            Self::Mismatch { input, track, what, expected, found } => write!(fmt, "track #{} of input #{} has {} {} but the first input has {}", track, input + 1, what, found, expected),
+        }
+    }
+}
+
+impl std::error::Error for ConcatError {}
+
+fn compare_tracks(input: usize, first: &Track, other: &Track) -> Result<(), ConcatError> {
+    let mismatch = |what, expected: String, found: String| Err(ConcatError::Mismatch { input, track: first.index, what, expected, found });
+    if first.kind != other.kind {
+        return mismatch("type", format!("{:?}", first.kind), format!("{:?}", other.kind));
+    }
+    if first.codec != other.codec {
+        return mismatch("codec", first.codec.clone(), other.codec.clone());
+    }
+    let resolution = |x: &Track| format!("{}x{}", x.resolution_h.unwrap_or(0), x.resolution_v.unwrap_or(0));
+    if (first.resolution_h, first.resolution_v) != (other.resolution_h, other.resolution_v) {
+        return mismatch("resolution", resolution(first), resolution(other));
+    }
+    if first.channels != other.channels {
+        return mismatch("channel count", first.channels.unwrap_or(0).to_string(), other.channels.unwrap_or(0).to_string());
+    }
+    // the concat demuxer takes the first file's word for all of these, so a copied track that
+    // changes any of them partway through plays back wrong, or not at all
+    let show = |x: &Option<String>| x.clone().unwrap_or_else(|| "unknown".into());
+    if first.channel_layout != other.channel_layout {
+        return mismatch("channel layout", show(&first.channel_layout), show(&other.channel_layout));
+    }
+    if first.sample_rate != other.sample_rate {
+        return mismatch("sample rate", show(&first.sample_rate.map(|x| x.to_string())), show(&other.sample_rate.map(|x| x.to_string())));
+    }
+    if first.pixel_format != other.pixel_format {
+        return mismatch("pixel format", show(&first.pixel_format), show(&other.pixel_format));
+    }
+    if first.profile != other.profile {
+        return mismatch("profile", show(&first.profile), show(&other.profile));
+    }
+    Ok(())
+}
+
+/// Checks that the inputs can be joined, and works out what ffprobe would say about the result
+/// if it could read it.  The tracks (and so the track indexes `TranscodeArgs` refers to) are the
+/// first input's, and the duration is all of them added up.
+pub fn concat_probe(inputs: &[FFprobeResult]) -> Result<FFprobeResult, ConcatError> {
+    let Some(first) = inputs.first() else {
+        return Err(ConcatError::NoInputs);
+    };
+    for (input, other) in inputs.iter().enumerate().skip(1) {
+        if other.tracks.len() != first.tracks.len() {
+            return Err(ConcatError::TrackCount { input, expected: first.tracks.len(), found: other.tracks.len() });
+        }
+        for (a, b) in first.tracks.iter().zip(other.tracks.iter()) {
+            compare_tracks(input, a, b)?;
+        }
+    }
+    let duration = inputs.iter().map(|x| x.duration).sum::<f32>();
+    // average the bitrates, weighted by how long each file is
+    let bitrate = if duration > 0.0 {
+        (inputs.iter().map(|x| x.bitrate as f64 * x.duration as f64).sum::<f64>() / duration as f64) as u64
+    } else {
+        first.bitrate
+    };
+    Ok(FFprobeResult {
+        duration,
+        bitrate,
+        ..first.clone()
+    })
+}
+
+/// Quotes a path for a concat demuxer script.  Inside single quotes everything is literal, so
+/// the only thing that needs escaping is the single quote itself.
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+/// Writes a concat demuxer script listing `inputs` in order, along with their durations.  ffmpeg
+/// can work the durations out for itself, but giving it ffprobe's figures means it doesn't have
+/// to guess from the last packet of each file, which tends to knock the later files' subtitles
+/// out of sync.
+///
+/// The paths get made absolute, since ffmpeg would otherwise look for them next to the script.
+pub fn write_concat_list(inputs: &[(&Path, f32)], list_file: &Path) -> std::io::Result<()> {
+    let mut out = String::from("ffconcat version 1.0\n");
+    for (path, duration) in inputs {
+        let path = std::fs::canonicalize(path)?;
+        let Some(path) = path.to_str() else {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} isn't valid UTF-8", path.display())));
+        };
+        out.push_str(&format!("file {}\nduration {}\n", quote(path), duration));
+    }
+    std::fs::File::create(list_file)?.write_all(out.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ffprobe::TrackType;
+
+    fn probe(duration: f32, tracks: Vec<Track>) -> FFprobeResult {
+        FFprobeResult { tracks, title: None, duration, bitrate: 1000, format: Some("matroska,webm".into()) }
+    }
+
+    #[test]
+    fn test_concat_probe() {
+        let tracks = vec![Track::example(0, TrackType::Video, "h264"), Track::example(1, TrackType::Audio, "aac"), Track::example(2, TrackType::Subtitle, "ass")];
+        let joined = concat_probe(&[probe(3000.0, tracks.clone()), probe(2400.0, tracks.clone())]).unwrap();
+        assert_eq!(joined.duration, 5400.0);
+        assert_eq!(joined.bitrate, 1000);
+        assert_eq!(joined.tracks.len(), 3);
+
+        assert_eq!(concat_probe(&[]).unwrap_err(), ConcatError::NoInputs);
+        assert_eq!(concat_probe(&[probe(1.0, tracks.clone()), probe(1.0, tracks[..2].to_vec())]).unwrap_err(),
+                   ConcatError::TrackCount { input: 1, expected: 3, found: 2 });
+        let mut other = tracks.clone();
+        other[1].codec = "opus".into();
+        assert!(matches!(concat_probe(&[probe(1.0, tracks.clone()), probe(1.0, other)]).unwrap_err(),
+                         ConcatError::Mismatch { input: 1, track: 1, what: "codec", .. }));
+        let mut other = tracks.clone();
+        other[1].sample_rate = Some(44100);
+        assert!(matches!(concat_probe(&[probe(1.0, tracks.clone()), probe(1.0, other)]).unwrap_err(),
+                         ConcatError::Mismatch { input: 1, track: 1, what: "sample rate", .. }));
+        let mut other = tracks.clone();
+        other[1].channel_layout = Some("5.1(side)".into());
+        assert!(matches!(concat_probe(&[probe(1.0, tracks.clone()), probe(1.0, other)]).unwrap_err(),
+                         ConcatError::Mismatch { input: 1, track: 1, what: "channel layout", .. }));
+        let mut other = tracks.clone();
+        other[0].pixel_format = Some("yuv420p10le".into());
+        assert!(matches!(concat_probe(&[probe(1.0, tracks.clone()), probe(1.0, other)]).unwrap_err(),
+                         ConcatError::Mismatch { input: 1, track: 0, what: "pixel format", .. }));
+        let mut other = tracks.clone();
+        other[0].profile = Some("High 10".into());
+        assert!(matches!(concat_probe(&[probe(1.0, tracks), probe(1.0, other)]).unwrap_err(),
+                         ConcatError::Mismatch { input: 1, track: 0, what: "profile", .. }));
+    }
+
+    #[test]
+    fn test_write_concat_list() {
+        let root = std::env::temp_dir().join(format!("cytrans-concat-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let part1 = root.join("Movie (CD1).mkv");
+        let part2 = root.join("Director's Cut (CD2).mkv");
+        std::fs::write(&part1, b"").unwrap();
+        std::fs::write(&part2, b"").unwrap();
+        let list = root.join("list.txt");
+        write_concat_list(&[(&part1, 3000.0), (&part2, 2400.5)], &list).unwrap();
+        let root = std::fs::canonicalize(&root).unwrap();
+        assert_eq!(std::fs::read_to_string(&list).unwrap(), format!(
+            "ffconcat version 1.0\nfile '{0}/Movie (CD1).mkv'\nduration 3000\nfile '{0}/Director'\\''s Cut (CD2).mkv'\nduration 2400.5\n",
+            root.display()));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
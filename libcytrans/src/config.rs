@@ -0,0 +1,80 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Which ffmpeg/ffprobe binaries to run and how to run them.  Every function in this crate that
+/// launches ffmpeg or ffprobe takes one of these, so a custom ffmpeg build can be used instead of
+/// whatever happens to be first in $PATH.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FfmpegConfig {
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+    /// Extra environment variables to set for every ffmpeg/ffprobe invocation, e.g.
+    /// LD_LIBRARY_PATH for a build that lives outside the system library path.
+    pub env: Vec<(OsString, OsString)>,
+    /// Arguments passed to ffmpeg before anything else on every transcode, e.g. `-nostdin` or
+    /// `-threads 4`.  These are not passed to ffprobe or when querying ffmpeg's capabilities.
+    pub global_args: Vec<OsString>,
+}
+
+impl Default for FfmpegConfig {
+    fn default() -> Self {
+        FfmpegConfig {
+            ffmpeg: "ffmpeg".into(),
+            ffprobe: "ffprobe".into(),
+            env: Vec::new(),
+            global_args: Vec::new(),
+        }
+    }
+}
+
+impl FfmpegConfig {
+    pub fn set_threads(&mut self, threads: u32) {
+        self.global_args.push("-threads".into());
+        self.global_args.push(threads.to_string().into());
+    }
+
+    /// Command for a transcode, with the global args already applied.
+    pub fn ffmpeg_command(&self) -> Command {
+        let mut command = self.ffmpeg_query_command();
+        command.args(&self.global_args);
+        command
+    }
+
+    /// Command for asking ffmpeg about itself (`-codecs`, `-version` and so on), which doesn't
+    /// get the global args.
+    pub(crate) fn ffmpeg_query_command(&self) -> Command {
+        let mut command = Command::new(&self.ffmpeg);
+        command.envs(self.env.iter().map(|(k, v)| (k, v)));
+        command
+    }
+
+    pub fn ffprobe_command(&self) -> Command {
+        let mut command = Command::new(&self.ffprobe);
+        command.envs(self.env.iter().map(|(k, v)| (k, v)));
+        command
+    }
+}
+
+/// Parses a `KEY=VALUE` command line argument into an entry for `FfmpegConfig::env`.  The value
+/// can be empty, the key can't.
+pub fn parse_env_var(s: &str) -> Result<(OsString, OsString), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.into(), v.into())),
+        _ => Err(format!("expected KEY=VALUE, got {:?}", s)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_env_var() {
+        assert_eq!(parse_env_var("LD_LIBRARY_PATH=/opt/ffmpeg/lib").unwrap(), ("LD_LIBRARY_PATH".into(), "/opt/ffmpeg/lib".into()));
+        assert_eq!(parse_env_var("A=b=c").unwrap(), ("A".into(), "b=c".into()));
+        assert_eq!(parse_env_var("EMPTY=").unwrap(), ("EMPTY".into(), "".into()));
+        assert!(parse_env_var("=value").is_err());
+        assert!(parse_env_var("NOVALUE").is_err());
+    }
+}
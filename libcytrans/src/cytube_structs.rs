@@ -1,44 +1,297 @@
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 
 pub const CYTUBE_ACCEPTABLE_QUALITY_VALUES: [u16; 8] = [240, 360, 480, 540, 720, 1080, 1440, 2160];
 
+// These lists are copied out of Cytube's custom media code.  If Cytube starts rejecting things
+// this validator lets through, check whether they've changed.
+pub const CYTUBE_SOURCE_CONTENT_TYPES: [&str; 9] = [
+    "application/x-mpegURL", "application/dash+xml",
+    "audio/aac", "audio/ogg", "audio/mpeg", "audio/opus",
+    "video/mp4", "video/ogg", "video/webm",
+];
+pub const CYTUBE_AUDIO_TRACK_CONTENT_TYPES: [&str; 5] = ["audio/aac", "audio/mp4", "audio/mpeg", "audio/ogg", "audio/opus"];
+pub const CYTUBE_TEXT_TRACK_CONTENT_TYPES: [&str; 1] = ["text/vtt"];
 
-#[derive(Serialize)]
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all="camelCase")]
 pub struct CytubeVideo {
     pub title: String,
     pub duration: f32,
     pub sources: Vec<Source>,
+    // cytube lets you leave these out entirely
+    #[serde(default)]
     pub audio_tracks: Vec<AudioTrack>,
+    #[serde(default)]
     pub text_tracks: Vec<TextTrack>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub thumbnail: Option<String>,
+    /// Live streams have no fixed duration, so Cytube doesn't try to keep everyone's playback
+    /// position in sync with the server's.
+    #[serde(default, skip_serializing_if="std::ops::Not::not")]
+    pub live: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all="camelCase")]
 pub struct Source {
     pub url: String,
-    pub content_type: &'static str,
+    pub content_type: String,
     pub quality: u16, // cytube accepts 240, 360, 480, 540, 720, 1080, 1440, and 2160
     #[serde(skip_serializing_if="Option::is_none")]
     #[serde(default)]
     pub bitrate: Option<u32>,
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all="camelCase")]
 pub struct TextTrack {
     pub url: String,
     pub name: String,
-    pub content_type: &'static str,
+    pub content_type: String,
+    /// Show this track without the viewer having to pick it.
+    #[serde(default, skip_serializing_if="std::ops::Not::not")]
+    pub default: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all="camelCase")]
 pub struct AudioTrack {
     pub url: String,
     pub label: String,
     pub language: String,
-    pub content_type: &'static str,
+    pub content_type: String,
+}
+
+/// Which part of a CytubeVideo a finding is about.  Indices are into the corresponding Vec.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all="snake_case")]
+pub enum Location {
+    Source(usize),
+    AudioTrack(usize),
+    TextTrack(usize),
+    Thumbnail,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Source(i) => write!(fmt, "source #{}", i),
+            Self::AudioTrack(i) => write!(fmt, "audio track #{}", i),
+            Self::TextTrack(i) => write!(fmt, "text track #{}", i),
+            Self::Thumbnail => write!(fmt, "thumbnail"),
+        }
+    }
+}
+
+/// Something Cytube would reject a manifest for.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all="snake_case")]
+pub enum Finding {
+    NoSources,
+    BadDuration(f32),
+    NotHttps { location: Location, url: String },
+    BadContentType { location: Location, content_type: String },
+    BadQuality { source: usize, quality: u16 },
+    DuplicateQuality { source: usize, quality: u16 },
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSources => write!(fmt, "there must be at least one source"),
+            Self::BadDuration(duration) => write!(fmt, "duration must be greater than zero, not {}", duration),
+            Self::NotHttps { location, url } => write!(fmt, "{}: {:?} is not an https:// URL", location, url),
+            Self::BadContentType { location, content_type } => write!(fmt, "{}: Cytube doesn't accept content type {:?} here", location, content_type),
+            Self::BadQuality { source, quality } => write!(fmt, "source #{}: {} is not one of Cytube's quality levels {:?}", source, quality, CYTUBE_ACCEPTABLE_QUALITY_VALUES),
+            Self::DuplicateQuality { source, quality } => write!(fmt, "source #{}: there is already a source with quality {}", source, quality),
+        }
+    }
+}
+
+fn is_https(url: &str) -> bool {
+    url.get(..8).is_some_and(|x| x.eq_ignore_ascii_case("https://")) && url.len() > 8
 }
 
+impl CytubeVideo {
+    /// Checks the manifest against the rules Cytube enforces when you add custom media, so we
+    /// can say exactly what's wrong instead of getting Cytube's one-size-fits-all error.
+    /// Returns an empty Vec if Cytube should accept it.
+    pub fn validate(&self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        // live streams have no duration, so cytube doesn't care what goes in that field
+        if !self.live && (self.duration.is_nan() || self.duration <= 0.0) {
+            findings.push(Finding::BadDuration(self.duration));
+        }
+        if self.sources.is_empty() {
+            findings.push(Finding::NoSources);
+        }
+        let mut check = |location: Location, url: &str, content_type: &str, acceptable: &[&str]| {
+            if !is_https(url) {
+                findings.push(Finding::NotHttps { location, url: url.to_owned() });
+            }
+            if !acceptable.contains(&content_type) {
+                findings.push(Finding::BadContentType { location, content_type: content_type.to_owned() });
+            }
+        };
+        for (i, source) in self.sources.iter().enumerate() {
+            check(Location::Source(i), &source.url, &source.content_type, &CYTUBE_SOURCE_CONTENT_TYPES);
+        }
+        for (i, track) in self.audio_tracks.iter().enumerate() {
+            check(Location::AudioTrack(i), &track.url, &track.content_type, &CYTUBE_AUDIO_TRACK_CONTENT_TYPES);
+        }
+        for (i, track) in self.text_tracks.iter().enumerate() {
+            check(Location::TextTrack(i), &track.url, &track.content_type, &CYTUBE_TEXT_TRACK_CONTENT_TYPES);
+        }
+        if let Some(thumbnail) = &self.thumbnail {
+            if !is_https(thumbnail) {
+                findings.push(Finding::NotHttps { location: Location::Thumbnail, url: thumbnail.clone() });
+            }
+        }
+        for (i, source) in self.sources.iter().enumerate() {
+            if !CYTUBE_ACCEPTABLE_QUALITY_VALUES.contains(&source.quality) {
+                findings.push(Finding::BadQuality { source: i, quality: source.quality });
+            } else if self.sources[..i].iter().any(|x| x.quality == source.quality) {
+                findings.push(Finding::DuplicateQuality { source: i, quality: source.quality });
+            }
+        }
+        findings
+    }
 
+    fn urls_mut(&mut self) -> impl Iterator<Item=&mut String> {
+        self.sources.iter_mut().map(|x| &mut x.url)
+            .chain(self.audio_tracks.iter_mut().map(|x| &mut x.url))
+            .chain(self.text_tracks.iter_mut().map(|x| &mut x.url))
+            .chain(self.thumbnail.as_mut())
+    }
+
+    /// Replaces `old_prefix` with `new_prefix` at the start of every URL in the manifest, e.g.
+    /// after moving the files to a different host.  URLs that don't start with `old_prefix` are
+    /// left alone.  Returns how many URLs were changed.
+    pub fn rewrite_url_prefix(&mut self, old_prefix: &str, new_prefix: &str) -> usize {
+        let mut count = 0;
+        for url in self.urls_mut() {
+            if let Some(rest) = url.strip_prefix(old_prefix) {
+                *url = format!("{}{}", new_prefix, rest);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Adds the sources and tracks from `other` to this manifest.  Anything with the same URL as
+    /// something we already have is skipped, so merging the same manifest twice is harmless.
+    /// Our title and duration are kept, and so is our thumbnail if we have one.
+    pub fn merge(&mut self, other: CytubeVideo) {
+        fn merge_into<T>(ours: &mut Vec<T>, theirs: Vec<T>, url: impl Fn(&T) -> &str) {
+            for item in theirs {
+                if !ours.iter().any(|x| url(x) == url(&item)) {
+                    ours.push(item);
+                }
+            }
+        }
+        merge_into(&mut self.sources, other.sources, |x| &x.url);
+        merge_into(&mut self.audio_tracks, other.audio_tracks, |x| &x.url);
+        merge_into(&mut self.text_tracks, other.text_tracks, |x| &x.url);
+        if self.thumbnail.is_none() {
+            self.thumbnail = other.thumbnail;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn source(url: &str, quality: u16) -> Source {
+        Source { url: url.into(), content_type: "video/webm".into(), quality, bitrate: None }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut video = CytubeVideo {
+            title: "Example".into(),
+            duration: 60.0,
+            sources: vec![source("https://example.com/a.webm", 1080), source("https://example.com/b.webm", 720)],
+            audio_tracks: vec![],
+            text_tracks: vec![TextTrack { url: "https://example.com/sub.vtt".into(), name: "English".into(), content_type: "text/vtt".into(), default: false }],
+            thumbnail: Some("https://example.com/thumbnail.jpg".into()),
+            live: false,
+        };
+        assert_eq!(video.validate(), vec![]);
+
+        video.live = true;
+        video.duration = 0.0;
+        assert_eq!(video.validate(), vec![]);
+        // and back to a normal video, which is fine again until we break it below
+        video.live = false;
+        video.duration = 60.0;
+        assert_eq!(video.validate(), vec![]);
+
+        video.duration = 0.0;
+        video.sources.push(source("http://example.com/c.webm", 1080));
+        video.sources.push(source("HTTPS://example.com/d.webm", 1000));
+        video.text_tracks[0].content_type = "text/plain".into();
+        assert_eq!(video.validate(), vec![
+            Finding::BadDuration(0.0),
+            Finding::NotHttps { location: Location::Source(2), url: "http://example.com/c.webm".into() },
+            Finding::BadContentType { location: Location::TextTrack(0), content_type: "text/plain".into() },
+            Finding::DuplicateQuality { source: 2, quality: 1080 },
+            Finding::BadQuality { source: 3, quality: 1000 },
+        ]);
+
+        video.sources.clear();
+        assert!(video.validate().contains(&Finding::NoSources));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let json = r#"{
+            "title": "Example",
+            "duration": 60.5,
+            "sources": [{"url": "https://example.com/a.webm", "contentType": "video/webm", "quality": 1080, "bitrate": 5000}],
+            "textTracks": [{"url": "https://example.com/sub.vtt", "name": "English", "contentType": "text/vtt"}]
+        }"#;
+        let video: CytubeVideo = serde_json::from_str(json).unwrap();
+        assert_eq!(video.sources[0].bitrate, Some(5000));
+        assert!(video.audio_tracks.is_empty());
+        assert_eq!(video.text_tracks[0].content_type, "text/vtt");
+        assert_eq!(video.thumbnail, None);
+        assert!(!video.live);
+        assert!(!serde_json::to_string(&video).unwrap().contains("live"));
+        let reparsed: CytubeVideo = serde_json::from_str(&serde_json::to_string(&video).unwrap()).unwrap();
+        assert_eq!(video, reparsed);
+    }
+
+    #[test]
+    fn test_rewrite_and_merge() {
+        let mut video = CytubeVideo {
+            title: "Example".into(),
+            duration: 60.0,
+            sources: vec![source("https://old.example.com/a.webm", 1080), source("https://elsewhere.com/b.webm", 720)],
+            audio_tracks: vec![],
+            text_tracks: vec![],
+            thumbnail: None,
+            live: false,
+        };
+        assert_eq!(video.rewrite_url_prefix("https://old.example.com/", "https://new.example.com/media/"), 1);
+        assert_eq!(video.sources[0].url, "https://new.example.com/media/a.webm");
+        assert_eq!(video.sources[1].url, "https://elsewhere.com/b.webm");
+
+        let extra = CytubeVideo {
+            title: "Ignored".into(),
+            duration: 1.0,
+            sources: vec![source("https://elsewhere.com/b.webm", 720)],
+            audio_tracks: vec![],
+            text_tracks: vec![TextTrack { url: "https://example.com/sub.vtt".into(), name: "English".into(), content_type: "text/vtt".into(), default: false }],
+            thumbnail: Some("https://example.com/thumbnail.jpg".into()),
+            live: false,
+        };
+        video.merge(extra.clone());
+        video.merge(extra);
+        assert_eq!(video.title, "Example");
+        assert_eq!(video.sources.len(), 2);
+        assert_eq!(video.text_tracks.len(), 1);
+        assert_eq!(video.thumbnail.as_deref(), Some("https://example.com/thumbnail.jpg"));
+    }
+}
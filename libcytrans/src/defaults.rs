@@ -0,0 +1,291 @@
+//! Preferences for what `get_defaults()` picks.  `DefaultsPolicy` says which tracks: out of the
+//! box it takes one audio track per language and every text subtitle track, which is fine for a
+//! movie with one dub and terrible for a Blu-ray remux with nine of each.  `DefaultsStrategy`
+//! says which codecs.
+
+use std::path::Path;
+
+use fixedstr::str4;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::codecs::{has_encoder, Capabilities};
+use crate::ffprobe::Track;
+use crate::options::{parse_language, AudioCodec, VideoCodec};
+use crate::transcode::{fallback_video_encoder, AudioContainer, VideoContainer};
+
+#[derive(Debug)]
+pub enum PolicyError {
+    Io(std::io::Error),
+    /// Bad JSON, or a language code `parse_language()` doesn't know.
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(fmt, "error reading defaults policy: {}", e),
+            Self::Json(e) => write!(fmt, "invalid defaults policy: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+impl From<std::io::Error> for PolicyError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for PolicyError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+fn languages<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<str4>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|x| parse_language(x).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Languages are ISO 639-2 codes, most preferred first.  The default policy has no preferences
+/// and no limits, which gets you the old behaviour.
+///
+/// Stored as JSON, e.g. `{"audio_languages": ["jpn", "eng"], "subtitle_languages": ["eng"],
+/// "keep_commentary": false, "max_audio_tracks": 2}`.  Anything left out takes its default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DefaultsPolicy {
+    /// One audio track gets picked per language on this list that the input has.  If it has none
+    /// of them, it's one per language as if the list were empty.
+    #[serde(deserialize_with = "languages")]
+    pub audio_languages: Vec<str4>,
+    /// Subtitle tracks in other languages get left out.  An empty list means all of them.
+    #[serde(deserialize_with = "languages")]
+    pub subtitle_languages: Vec<str4>,
+    pub keep_commentary: bool,
+    pub max_audio_tracks: Option<usize>,
+    pub max_subtitle_tracks: Option<usize>,
+}
+
+impl Default for DefaultsPolicy {
+    fn default() -> Self {
+        Self {
+            audio_languages: Vec::new(),
+            subtitle_languages: Vec::new(),
+            keep_commentary: true,
+            max_audio_tracks: None,
+            max_subtitle_tracks: None,
+        }
+    }
+}
+
+impl DefaultsPolicy {
+    pub fn load(path: &Path) -> Result<Self, PolicyError> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Whether the track is worth considering at all.
+    pub fn allows(&self, track: &Track) -> bool {
+        self.keep_commentary || !is_commentary(track)
+    }
+
+    /// Where the track's language comes on the audio list, if it's there at all.
+    pub fn audio_rank(&self, track: &Track) -> Option<usize> {
+        rank(&self.audio_languages, track)
+    }
+
+    pub fn subtitle_rank(&self, track: &Track) -> Option<usize> {
+        rank(&self.subtitle_languages, track)
+    }
+}
+
+fn rank(languages: &[str4], track: &Track) -> Option<usize> {
+    let language = track.language?;
+    languages.iter().position(|x| *x == language)
+}
+
+/// Not every commentary track has the disposition set, but they nearly all say so in the title.
+pub fn is_commentary(track: &Track) -> bool {
+    track.commentary || track.title.as_ref().is_some_and(|x| x.to_lowercase().contains("commentary"))
+}
+
+/// How `get_defaults()` picks codecs and encoders.  Every method has a default, which together
+/// make up the `standard` strategy: copy whatever a browser can already play, and encode the
+/// rest.
+pub trait DefaultsStrategy: Sync {
+    /// What it's called on the command line and in the API.
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    fn video_encoder(&self, track: &Track, capabilities: &Capabilities) -> (VideoCodec, String) {
+        match track.codec.parse() {
+            Ok(codec) => (codec, "copy".to_string()),
+            Err(_) => fallback_video_encoder(capabilities),
+        }
+    }
+
+    /// `video_codec` is what the video track is being encoded to, if there is one.
+    fn audio_encoder(&self, track: &Track, video_codec: Option<VideoCodec>, _capabilities: &Capabilities) -> (AudioCodec, String) {
+        if let Ok(x) = track.codec.parse() {
+            if video_codec.is_none() && AudioContainer::find_source(x).is_none() {
+                // with no video this becomes the source, and ALAC can't be one.  FLAC is
+                // lossless too, so nothing is lost
+                (AudioCodec::FLAC, "flac".to_string())
+            } else {
+                (x, "copy".to_string())
+            }
+        } else if let Some(vc) = video_codec {
+            if matches!(VideoContainer::find(vc), VideoContainer::MP4) {
+                (AudioCodec::AAC, "aac".to_string())
+            } else {
+                (AudioCodec::Opus, "libopus".to_string())
+            }
+        } else if track.codec.starts_with("pcm_") {
+            // music straight off a CD (or out of a WAV file) deserves better than lossy
+            (AudioCodec::FLAC, "flac".to_string())
+        } else {
+            (AudioCodec::Opus, "libopus".to_string())
+        }
+    }
+
+    /// If false, every audio track the policy allows gets picked, not just the best one in each
+    /// language.
+    fn one_audio_track_per_language(&self) -> bool {
+        true
+    }
+}
+
+impl std::fmt::Debug for dyn DefaultsStrategy {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "{}", self.name())
+    }
+}
+
+pub struct Standard;
+
+impl DefaultsStrategy for Standard {
+    fn name(&self) -> &'static str {
+        "standard"
+    }
+
+    fn description(&self) -> &'static str {
+        "Copy whatever browsers can play, and encode the rest"
+    }
+}
+
+/// Re-encodes all the video to AV1 and all the audio to Opus, which takes ages but makes for
+/// much smaller files than most of what's out there.
+pub struct Av1;
+
+impl DefaultsStrategy for Av1 {
+    fn name(&self) -> &'static str {
+        "av1"
+    }
+
+    fn description(&self) -> &'static str {
+        "Encode everything to AV1 and Opus, for the smallest files"
+    }
+
+    fn video_encoder(&self, track: &Track, capabilities: &Capabilities) -> (VideoCodec, String) {
+        if track.codec == "av1" {
+            return (VideoCodec::AV1, "copy".to_string());
+        }
+        match ["libsvtav1", "libaom-av1"].into_iter().find(|x| has_encoder(&capabilities.video_encoders, &VideoCodec::AV1, x)) {
+            Some(encoder) => (VideoCodec::AV1, encoder.to_string()),
+            // fallback_video_encoder() will pick the next best thing
+            None => fallback_video_encoder(capabilities),
+        }
+    }
+
+    fn audio_encoder(&self, track: &Track, video_codec: Option<VideoCodec>, capabilities: &Capabilities) -> (AudioCodec, String) {
+        if track.codec == "opus" {
+            (AudioCodec::Opus, "copy".to_string())
+        } else if has_encoder(&capabilities.audio_encoders, &AudioCodec::Opus, "libopus") {
+            (AudioCodec::Opus, "libopus".to_string())
+        } else {
+            // ffmpeg's own opus encoder is experimental, so do whatever the standard strategy would
+            Standard.audio_encoder(track, video_codec, capabilities)
+        }
+    }
+}
+
+/// Like `Standard`, but keeps every audio track rather than one per language, so nothing gets
+/// encoded that doesn't have to be and nothing gets thrown away.
+pub struct CopyAll;
+
+impl DefaultsStrategy for CopyAll {
+    fn name(&self) -> &'static str {
+        "copy"
+    }
+
+    fn description(&self) -> &'static str {
+        "Keep every audio track and copy as much as possible"
+    }
+
+    fn one_audio_track_per_language(&self) -> bool {
+        false
+    }
+}
+
+/// H.264 and AAC in an MP4, which plays on anything made in the last fifteen years, including
+/// phones and old iPads that choke on VP9.
+pub struct Compatible;
+
+impl DefaultsStrategy for Compatible {
+    fn name(&self) -> &'static str {
+        "compatible"
+    }
+
+    fn description(&self) -> &'static str {
+        "Encode to H.264 and AAC in MP4, for the oldest devices"
+    }
+
+    fn video_encoder(&self, track: &Track, capabilities: &Capabilities) -> (VideoCodec, String) {
+        if track.codec == "h264" {
+            (VideoCodec::H264, "copy".to_string())
+        } else if has_encoder(&capabilities.video_encoders, &VideoCodec::H264, "libx264") {
+            (VideoCodec::H264, "libx264".to_string())
+        } else {
+            // plenty of ffmpeg builds leave out libx264 for licensing reasons
+            fallback_video_encoder(capabilities)
+        }
+    }
+
+    fn audio_encoder(&self, track: &Track, video_codec: Option<VideoCodec>, capabilities: &Capabilities) -> (AudioCodec, String) {
+        if track.codec == "aac" {
+            return (AudioCodec::AAC, "copy".to_string());
+        }
+        match ["aac", "libfdk_aac"].into_iter().find(|x| has_encoder(&capabilities.audio_encoders, &AudioCodec::AAC, x)) {
+            Some(encoder) => (AudioCodec::AAC, encoder.to_string()),
+            // no AAC encoder, so do whatever the standard strategy would
+            None => Standard.audio_encoder(track, video_codec, capabilities),
+        }
+    }
+}
+
+/// Every built-in strategy, `Standard` first.
+pub static STRATEGIES: [&dyn DefaultsStrategy; 4] = [&Standard, &Av1, &CopyAll, &Compatible];
+
+pub fn find_strategy(name: &str) -> Option<&'static dyn DefaultsStrategy> {
+    STRATEGIES.iter().copied().find(|x| x.name() == name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        let policy: DefaultsPolicy = serde_json::from_str(r#"{"audio_languages": ["JPN", "eng"], "max_audio_tracks": 1}"#).unwrap();
+        assert_eq!(policy.audio_languages, vec![str4::from("jpn"), str4::from("eng")]);
+        assert!(policy.subtitle_languages.is_empty());
+        assert!(policy.keep_commentary);
+        assert_eq!(policy.max_audio_tracks, Some(1));
+        assert_eq!(serde_json::from_str::<DefaultsPolicy>("{}").unwrap(), DefaultsPolicy::default());
+        assert!(serde_json::from_str::<DefaultsPolicy>(r#"{"subtitle_languages": ["english"]}"#).is_err());
+    }
+}
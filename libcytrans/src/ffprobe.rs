@@ -1,6 +1,7 @@
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::Stdio;
+use crate::config::FfmpegConfig;
 use fixedstr::str4;
 use serde::{Serialize,Deserialize};
 
@@ -23,9 +24,69 @@ pub struct Track {
     pub language: Option<str4>,
     pub title: Option<String>,
     pub channels: Option<u8>,
+    /// e.g. "stereo" or "5.1(side)".  Audio only.
+    #[serde(default)]
+    pub channel_layout: Option<String>,
+    /// In Hz.  Audio only.
+    #[serde(default)]
+    pub sample_rate: Option<u32>,
+    /// e.g. "yuv420p".  Video only.
+    #[serde(default)]
+    pub pixel_format: Option<String>,
+    /// e.g. "High" or "Main 10".  Not every codec has profiles.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// bitrate in bits per second.  Lots of containers (e.g. Matroska) don't record this per
+    /// stream, in which case it's None.
+    #[serde(default)]
+    pub bitrate: Option<u64>,
+    /// The file says this track should be played if the viewer hasn't picked one.
+    #[serde(default)]
+    pub default: bool,
+    /// The file says this track should be played no matter what, e.g. subtitles for the bits of
+    /// a dub that weren't dubbed.
+    #[serde(default)]
+    pub forced: bool,
+    /// This "video" track is really the cover art of a music file.  It's a single frame long.
+    #[serde(default)]
+    pub attached_pic: bool,
+    /// Director's commentary and the like.
+    #[serde(default)]
+    pub commentary: bool,
 }
 
 impl Track {
+    /// A track with nothing but the essentials filled in, for tests to build on.  Video tracks
+    /// are 1920x1080.
+    #[cfg(test)]
+    pub(crate) fn example(index: u16, kind: TrackType, codec: &str) -> Self {
+        let resolution = |x| (kind == TrackType::Video).then_some(x);
+        Track {
+            index,
+            codec: codec.into(),
+            resolution_h: resolution(1920),
+            resolution_v: resolution(1080),
+            kind,
+            language: None,
+            title: None,
+            channels: None,
+            channel_layout: None,
+            sample_rate: None,
+            pixel_format: None,
+            profile: None,
+            bitrate: None,
+            default: false,
+            forced: false,
+            attached_pic: false,
+            commentary: false,
+        }
+    }
+
+    /// Video tracks that are actually video, as opposed to cover art.
+    pub fn is_valid_video_track(&self) -> bool {
+        self.kind == TrackType::Video && !self.attached_pic
+    }
+
     pub fn is_valid_subtitle_track(&self) -> bool {
         self.kind == TrackType::Subtitle && !crate::codecs::BITMAP_SUBTITLE_CODECS.contains(&self.codec.as_str())
     }
@@ -56,6 +117,9 @@ pub struct FFprobeResult {
     pub duration: f32,
     /// bitrate in kbps of the entire file
     pub bitrate: u64,
+    /// ffmpeg's name for the demuxer that can read the file, e.g. "matroska,webm"
+    #[serde(default)]
+    pub format: Option<String>,
 }
 
 fn parse_ffmpeg_line<'a>(line: &'a str) -> (&'a str, impl Iterator<Item=(&'a str, &'a str)>) {
@@ -65,14 +129,22 @@ fn parse_ffmpeg_line<'a>(line: &'a str) -> (&'a str, impl Iterator<Item=(&'a str
 }
 
 //#[cfg(feature="commands")]
-pub fn ffprobe(filename: &impl AsRef<OsStr>) -> std::io::Result<FFprobeResult> {
-    let res = Command::new("ffprobe")
+pub fn ffprobe(config: &FfmpegConfig, filename: &impl AsRef<OsStr>) -> std::io::Result<FFprobeResult> {
+    ffprobe_with_input_args(config, filename, &[])
+}
+
+/// Like `ffprobe()`, but passes `input_args` (see `TranscodeArgs::input_ffmpeg_args`) before the
+/// input, for files that need e.g. `-analyzeduration` or URLs that need `-headers` to be read at
+/// all.
+pub fn ffprobe_with_input_args(config: &FfmpegConfig, filename: &impl AsRef<OsStr>, input_args: &[OsString]) -> std::io::Result<FFprobeResult> {
+    let res = config.ffprobe_command()
+        .args(input_args)
         .arg(filename.as_ref())
         .arg("-of").arg("compact")
         .arg("-hide_banner")
         .arg("-show_streams").arg("-show_format")
         .arg("-show_entries")
-        .arg("stream_tags=title,language:stream=index,codec_type,codec_name,channels,coded_width,coded_height:stream_disposition=:format=duration,bit_rate:format_tags=title")
+        .arg("stream_tags=title,language:stream=index,codec_type,codec_name,profile,channels,channel_layout,sample_rate,width,height,pix_fmt,bit_rate:stream_disposition=default,forced,attached_pic,comment:format=duration,bit_rate,format_name:format_tags=title")
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()?
@@ -87,6 +159,8 @@ pub fn ffprobe(filename: &impl AsRef<OsStr>) -> std::io::Result<FFprobeResult> {
     let mut title: Option<String> = None;
     let mut duration = 0.0f32;
     let mut bitrate = 0u64;
+    let mut format: Option<String> = None;
+    let known = |v: &str| Some(v.to_owned()).filter(|v| !["", "unknown", "N/A"].contains(&v.as_str()));
 
     'a: for line in output.split("\n") {
         let (kind, params) = parse_ffmpeg_line(line);
@@ -96,6 +170,7 @@ pub fn ffprobe(filename: &impl AsRef<OsStr>) -> std::io::Result<FFprobeResult> {
                     match k {
                         "duration" => {duration = v.parse().unwrap();}
                         "bit_rate" => {bitrate = v.parse().unwrap();}
+                        "format_name" => {format = Some(v.to_owned());}
                         "tag:title" => {title = Some(v.to_owned());}
                         x => {println!("uncrecognized tag {}", x);},
                     }
@@ -110,6 +185,15 @@ pub fn ffprobe(filename: &impl AsRef<OsStr>) -> std::io::Result<FFprobeResult> {
                 let mut title: Option<String> = None;
                 let mut index: Option<u16> = None;
                 let mut channels: Option<u8> = None;
+                let mut channel_layout: Option<String> = None;
+                let mut sample_rate: Option<u32> = None;
+                let mut pixel_format: Option<String> = None;
+                let mut profile: Option<String> = None;
+                let mut bitrate: Option<u64> = None;
+                let mut default = false;
+                let mut forced = false;
+                let mut attached_pic = false;
+                let mut commentary = false;
                 for (k,v) in params {
                     match k {
                         "codec_type" => {
@@ -120,22 +204,35 @@ pub fn ffprobe(filename: &impl AsRef<OsStr>) -> std::io::Result<FFprobeResult> {
                         },
                         "index" => index = Some(v.parse().unwrap()),
                         "channels" => channels = Some(v.parse().unwrap()),
+                        // these are all blank, "unknown" or "N/A" when there's no telling
+                        "channel_layout" => channel_layout = known(v),
+                        "sample_rate" => sample_rate = v.parse().ok(),
+                        "pix_fmt" => pixel_format = known(v),
+                        "profile" => profile = known(v),
+                        // this one is "N/A" if the container doesn't know
+                        "bit_rate" => bitrate = v.parse().ok(),
                         "codec_name" => codec = Some(v.to_string()),
-                        "coded_width" => resolution_h = Some(v.parse().unwrap()),
-                        "coded_height" => resolution_v = Some(v.parse().unwrap()),
+                        // width and height rather than coded_width and coded_height, because the
+                        // coded size includes padding and anything the container says to crop.
+                        "width" => resolution_h = Some(v.parse().unwrap()),
+                        "height" => resolution_v = Some(v.parse().unwrap()),
                         "tag:language" => language = Some(v.into()),
                         "tag:title" => title = Some(v.to_string()),
+                        "disposition:default" => default = v == "1",
+                        "disposition:forced" => forced = v == "1",
+                        "disposition:attached_pic" => attached_pic = v == "1",
+                        "disposition:comment" => commentary = v == "1",
                         x => {println!("ffprobe returned uncrecognized tag {}", x);},
                     }
                 }
                 let index = index.expect("no index");
                 let kind = kind.expect("no codec_type");
                 let codec = codec.expect("no codec_name");
-                tracks.push(Track {index, kind, codec, resolution_h, resolution_v, language, title, channels});
+                tracks.push(Track {index, kind, codec, resolution_h, resolution_v, language, title, channels, channel_layout, sample_rate, pixel_format, profile, bitrate, default, forced, attached_pic, commentary});
             },
             _ => {},
         }
     }
-    Ok(FFprobeResult {tracks, title, duration, bitrate})
+    Ok(FFprobeResult {tracks, title, duration, bitrate, format})
 }
 
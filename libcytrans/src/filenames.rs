@@ -0,0 +1,267 @@
+//! Naming the files `build_ffmpeg_command` writes into the output directory.
+//!
+//! Templates are plain strings with placeholders in braces, e.g. `{title}_{quality}p_{codec}`.
+//! The file extension gets added on the end automatically.  The placeholders are:
+//!
+//! * `{title}`: the title, squashed down into something that's safe in a URL
+//! * `{index}`: the index of the source track in the input file
+//! * `{quality}`: the Cytube quality level, e.g. 1080.  Video only.
+//! * `{codec}`: the codec we're encoding to, e.g. vp9
+//! * `{lang}`: the track's language, or "und" if it doesn't have one
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    Empty,
+    UnknownPlaceholder(String),
+    /// `{quality}` only means anything for video.
+    PlaceholderNotAllowed(String),
+    UnmatchedBrace,
+    /// Templates can't put files in a subdirectory, or anywhere else.
+    PathSeparator,
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(fmt, "filename template is empty"),
+            Self::UnknownPlaceholder(x) => write!(fmt, "unknown placeholder {{{}}} in filename template", x),
+            Self::PlaceholderNotAllowed(x) => write!(fmt, "placeholder {{{}}} can only be used for video filenames", x),
+            Self::UnmatchedBrace => write!(fmt, "unmatched brace in filename template"),
+            Self::PathSeparator => write!(fmt, "filename templates can't contain slashes"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+const PLACEHOLDERS: [&str; 5] = ["title", "index", "quality", "codec", "lang"];
+const VIDEO_ONLY_PLACEHOLDERS: [&str; 1] = ["quality"];
+
+/// Splits a template into literal text and placeholders.  Yields `(text, is_placeholder)`.
+fn tokenize(template: &str) -> impl Iterator<Item=Result<(&str, bool), TemplateError>> {
+    let mut rest = template;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        if let Some(after) = rest.strip_prefix('{') {
+            let Some(end) = after.find('}') else {
+                rest = "";
+                return Some(Err(TemplateError::UnmatchedBrace));
+            };
+            let name = &after[..end];
+            rest = &after[end+1..];
+            Some(Ok((name, true)))
+        } else {
+            let end = rest.find(['{', '}']).unwrap_or(rest.len());
+            if end == 0 {
+                // a '}' with no '{' before it
+                rest = "";
+                return Some(Err(TemplateError::UnmatchedBrace));
+            }
+            let text = &rest[..end];
+            rest = &rest[end..];
+            Some(Ok((text, false)))
+        }
+    })
+}
+
+fn check_template(template: &str, is_video: bool) -> Result<(), TemplateError> {
+    if template.is_empty() {
+        return Err(TemplateError::Empty);
+    }
+    if template.contains(['/', '\\']) {
+        return Err(TemplateError::PathSeparator);
+    }
+    for token in tokenize(template) {
+        let (text, is_placeholder) = token?;
+        if !is_placeholder {
+            continue;
+        }
+        if !PLACEHOLDERS.contains(&text) {
+            return Err(TemplateError::UnknownPlaceholder(text.to_owned()));
+        }
+        if !is_video && VIDEO_ONLY_PLACEHOLDERS.contains(&text) {
+            return Err(TemplateError::PlaceholderNotAllowed(text.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+/// The templates for each kind of output file.  These are checked when they're created, so
+/// rendering one can't fail.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FilenameTemplates {
+    video: String,
+    audio: String,
+    subtitle: String,
+}
+
+impl Default for FilenameTemplates {
+    fn default() -> Self {
+        // the audio and subtitle names are the ones scan_output_directory() knows how to read
+        // languages back out of
+        Self {
+            video: "video_{index}_{codec}_{quality}".into(),
+            audio: "audio_{index}_{lang}".into(),
+            subtitle: "sub_{index}_{lang}".into(),
+        }
+    }
+}
+
+impl FilenameTemplates {
+    pub fn new(video: String, audio: String, subtitle: String) -> Result<Self, TemplateError> {
+        check_template(&video, true)?;
+        check_template(&audio, false)?;
+        check_template(&subtitle, false)?;
+        Ok(Self { video, audio, subtitle })
+    }
+
+    /// The default templates, with any of them replaced that aren't None.  Handy for command
+    /// line flags.
+    pub fn with_overrides(video: Option<String>, audio: Option<String>, subtitle: Option<String>) -> Result<Self, TemplateError> {
+        let default = Self::default();
+        Self::new(video.unwrap_or(default.video), audio.unwrap_or(default.audio), subtitle.unwrap_or(default.subtitle))
+    }
+
+    pub fn video(&self) -> &str {
+        &self.video
+    }
+
+    pub fn audio(&self) -> &str {
+        &self.audio
+    }
+
+    pub fn subtitle(&self) -> &str {
+        &self.subtitle
+    }
+}
+
+/// What gets substituted into a template.
+pub struct FilenameFields<'a> {
+    pub title: &'a str,
+    pub index: u16,
+    pub quality: Option<u16>,
+    pub codec: &'a str,
+    pub language: Option<&'a str>,
+}
+
+/// Squashes a string into lowercase letters, digits and dashes, so it's safe to use in a
+/// filename and a URL without any escaping.
+pub fn slugify(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() {
+            out.push(c.to_ascii_lowercase());
+        } else if !out.is_empty() && !out.ends_with('-') {
+            out.push('-');
+        }
+    }
+    while out.ends_with('-') {
+        out.pop();
+    }
+    // nobody wants to scroll past the whole title of a 40-word anime episode in a file listing
+    if out.len() > 64 {
+        out.truncate(64);
+        while out.ends_with('-') {
+            out.pop();
+        }
+    }
+    out
+}
+
+/// Fills in a template.  `template` must have come out of a FilenameTemplates.
+pub fn render(template: &str, fields: &FilenameFields) -> String {
+    let mut out = String::new();
+    for token in tokenize(template) {
+        let (text, is_placeholder) = token.expect("templates are checked when they're created");
+        if !is_placeholder {
+            out.push_str(text);
+            continue;
+        }
+        match text {
+            "title" => {
+                let slug = slugify(fields.title);
+                out.push_str(if slug.is_empty() {"untitled"} else {&slug});
+            },
+            "index" => out.push_str(&fields.index.to_string()),
+            "quality" => out.push_str(&fields.quality.unwrap_or(0).to_string()),
+            "codec" => out.push_str(&slugify(fields.codec)),
+            "lang" => {
+                let lang = fields.language.map(slugify).unwrap_or_default();
+                out.push_str(if lang.is_empty() {"und"} else {&lang});
+            },
+            _ => unreachable!("templates are checked when they're created"),
+        }
+    }
+    out
+}
+
+/// Hands out filenames, making sure none of them are handed out twice.
+#[derive(Default)]
+pub struct UniqueFilenames {
+    taken: HashSet<String>,
+}
+
+impl UniqueFilenames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `{stem}.{extension}`, or if that's already been handed out, `{stem}_2.{extension}`,
+    /// `{stem}_3.{extension}`, and so on.
+    pub fn claim(&mut self, stem: &str, extension: &str) -> String {
+        let mut filename = format!("{}.{}", stem, extension);
+        let mut n = 2;
+        while self.taken.contains(&filename) {
+            filename = format!("{}_{}.{}", stem, n, extension);
+            n += 1;
+        }
+        self.taken.insert(filename.clone());
+        filename
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_templates() {
+        assert!(FilenameTemplates::new("{title}_{quality}p_{codec}".into(), "{title}_{lang}".into(), "{title}.{lang}".into()).is_ok());
+        assert_eq!(FilenameTemplates::new("".into(), "a".into(), "s".into()), Err(TemplateError::Empty));
+        assert_eq!(FilenameTemplates::new("{resolution}".into(), "a".into(), "s".into()), Err(TemplateError::UnknownPlaceholder("resolution".into())));
+        assert_eq!(FilenameTemplates::new("v".into(), "{quality}".into(), "s".into()), Err(TemplateError::PlaceholderNotAllowed("quality".into())));
+        assert_eq!(FilenameTemplates::new("{title".into(), "a".into(), "s".into()), Err(TemplateError::UnmatchedBrace));
+        assert_eq!(FilenameTemplates::new("title}".into(), "a".into(), "s".into()), Err(TemplateError::UnmatchedBrace));
+        assert_eq!(FilenameTemplates::new("../{title}".into(), "a".into(), "s".into()), Err(TemplateError::PathSeparator));
+    }
+
+    #[test]
+    fn test_render() {
+        let fields = FilenameFields {
+            title: "Cowboy Bebop: Session #1 (Asteroid Blues)",
+            index: 0,
+            quality: Some(1080),
+            codec: "av1",
+            language: None,
+        };
+        assert_eq!(render("{title}_{quality}p_{codec}", &fields), "cowboy-bebop-session-1-asteroid-blues_1080p_av1");
+        assert_eq!(render("audio_{index}_{lang}", &fields), "audio_0_und");
+        assert_eq!(render("{lang}", &FilenameFields { language: Some("../x"), ..fields }), "x");
+        assert_eq!(render("{title}", &FilenameFields { title: "!!!", ..fields }), "untitled");
+    }
+
+    #[test]
+    fn test_unique_filenames() {
+        let mut names = UniqueFilenames::new();
+        assert_eq!(names.claim("main", "webm"), "main.webm");
+        assert_eq!(names.claim("main", "mp4"), "main.mp4");
+        assert_eq!(names.claim("main", "webm"), "main_2.webm");
+        assert_eq!(names.claim("main", "webm"), "main_3.webm");
+    }
+}
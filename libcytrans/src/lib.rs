@@ -4,4 +4,11 @@ pub mod ffprobe;
 pub mod transcode;
 pub mod options;
 pub mod codecs;
+pub mod config;
 pub mod metadata;
+pub mod filenames;
+pub mod staging;
+pub mod concat;
+pub mod defaults;
+pub mod shell_words;
+pub mod sidecars;
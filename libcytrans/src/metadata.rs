@@ -1,39 +1,62 @@
 use crate::options::{VideoCodec, AudioCodec};
 use crate::ffmpeg_languages::{LANGUAGES, FF2CT};
-use crate::cytube_structs as cytube;
+use crate::cytube_structs::{self as cytube, CYTUBE_ACCEPTABLE_QUALITY_VALUES};
 use crate::transcode::{VideoContainer, AudioContainer};
+use crate::config::FfmpegConfig;
+use crate::ffprobe::{ffprobe, TrackType};
 use serde::{Serialize,Deserialize};
+use std::io::Write;
+use std::path::Path;
 
-const CYTUBE_QUALITY_VALUES: [u16; 7] = [240, 360, 480, 540, 720, 1080, 2160];
+/// Name of the file the internal manifest gets saved under, inside the output directory.  The
+/// Cytube manifest lives next to it as manifest.json.
+pub const MANIFEST_FILENAME: &str = "cytrans_metadata.json";
 
-#[derive(Serialize,Deserialize)]
+/// Name of the Cytube manifest inside the output directory.  This is the one users paste the URL
+/// of into Cytube.
+pub const CYTUBE_MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Bump this whenever the on-disk layout of MetadataManifest changes in a way old versions of
+/// cytrans can't read.
+pub const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
 pub struct VideoMetadata {
     pub filename: String,
     pub container: VideoContainer,
     pub video_codec: VideoCodec,
     pub audio_codec: Option<AudioCodec>,
     pub audio_is_silent: bool,
+    /// Bitrate of the muxed audio track in bits per second, if we know it.
+    #[serde(default)]
+    pub audio_bitrate: Option<u64>,
     pub resolution_h: u16,
     pub resolution_v: u16,
 }
 
-#[derive(Serialize,Deserialize)]
+#[derive(Debug,Clone,Serialize,Deserialize)]
 pub struct AudioMetadata {
     pub filename: String,
     pub container: AudioContainer,
     pub codec: AudioCodec,
     pub language: fixedstr::str4,
     pub title: Option<String>,
+    /// Set if this file is one of the item's sources rather than an extra audio track, which is
+    /// how items with no video work.  It's the quality level Cytube lists it under.
+    #[serde(default)]
+    pub source_quality: Option<u16>,
 }
 
-#[derive(Serialize,Deserialize)]
+#[derive(Debug,Clone,Serialize,Deserialize)]
 pub struct TextMetadata {
     pub filename: String,
     pub language: Option<fixedstr::str4>,
     pub title: Option<String>,
+    #[serde(default)]
+    pub default: bool,
 }
 
-#[derive(Serialize,Deserialize)]
+#[derive(Debug,Clone,Serialize,Deserialize)]
 pub struct MetadataManifest {
     pub video_files: Vec<VideoMetadata>,
     pub audio_files: Vec<AudioMetadata>,
@@ -41,24 +64,178 @@ pub struct MetadataManifest {
     pub duration: f32,
     pub title: String,
     pub muxed_audio: Option<MuxedAudioMetadata>,
+    /// Filename of the thumbnail image, if we made one.
+    #[serde(default)]
+    pub thumbnail: Option<String>,
 }
 
 /**
  * Metadata about the audio track muxed into the video.  Used when demultiplexing.
  */
-#[derive(Serialize,Deserialize)]
+#[derive(Debug,Clone,Serialize,Deserialize)]
 pub struct MuxedAudioMetadata {
     pub language: fixedstr::str4,
     pub title: Option<String>,
 }
 
-#[derive(Serialize,Deserialize)]
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
 pub struct ToRemove {
     pub video_files: Vec<String>,
     pub audio_files: Vec<String>,
     pub text_files: Vec<String>,
 }
 
+#[derive(Debug)]
+pub enum ManifestError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    UnsupportedVersion(u32),
+}
+
+impl std::fmt::Display for ManifestError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(fmt, "error accessing the manifest: {}", e),
+            Self::Json(e) => write!(fmt, "malformed manifest: {}", e),
+            Self::UnsupportedVersion(v) => write!(fmt, "manifest is version {} but this cytrans only understands up to version {}", v, MANIFEST_VERSION),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+impl From<std::io::Error> for ManifestError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ManifestError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+/// Something `MetadataManifest::validate()` found wrong with the files on disk.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum ManifestProblem {
+    Missing(String),
+    Unreadable { filename: String, error: String },
+    WrongContainer { filename: String, expected: &'static str, found: Option<String> },
+    WrongCodec { filename: String, expected: String, found: Option<String> },
+    UnexpectedAudio(String),
+}
+
+impl std::fmt::Display for ManifestProblem {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing(filename) => write!(fmt, "{} does not exist", filename),
+            Self::Unreadable { filename, error } => write!(fmt, "{} could not be probed: {}", filename, error),
+            Self::WrongContainer { filename, expected, found: Some(found) } => write!(fmt, "{} should be {} but ffprobe says it's {}", filename, expected, found),
+            Self::WrongContainer { filename, expected, found: None } => write!(fmt, "{} should be {} but ffprobe couldn't tell what it is", filename, expected),
+            Self::WrongCodec { filename, expected, found: Some(found) } => write!(fmt, "{} should contain {} but contains {}", filename, expected, found),
+            Self::WrongCodec { filename, expected, found: None } => write!(fmt, "{} should contain {} but has no such track", filename, expected),
+            Self::UnexpectedAudio(filename) => write!(fmt, "{} has an audio track the manifest doesn't know about", filename),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScanError {
+    Io(std::io::Error),
+    Probe { filename: String, error: std::io::Error },
+    /// The file has no stream of the kind its extension says it should, or one in a codec we
+    /// don't know how to describe to Cytube.
+    Unusable { filename: String, reason: String },
+}
+
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(fmt, "error reading the output directory: {}", e),
+            Self::Probe { filename, error } => write!(fmt, "error probing {}: {}", filename, error),
+            Self::Unusable { filename, reason } => write!(fmt, "can't use {}: {}", filename, reason),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+impl From<std::io::Error> for ScanError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum RemoveError {
+    Manifest(ManifestError),
+    /// The filename isn't a plain filename, so deleting it might delete something outside the
+    /// output directory.
+    PathEscapes(String),
+    NotInManifest(String),
+}
+
+impl std::fmt::Display for RemoveError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Manifest(e) => e.fmt(fmt),
+            Self::PathEscapes(filename) => write!(fmt, "refusing to delete {:?}, it isn't a file in the output directory", filename),
+            Self::NotInManifest(filename) => write!(fmt, "{} isn't in the manifest", filename),
+        }
+    }
+}
+
+impl std::error::Error for RemoveError {}
+
+impl From<ManifestError> for RemoveError {
+    fn from(e: ManifestError) -> Self {
+        Self::Manifest(e)
+    }
+}
+
+impl From<std::io::Error> for RemoveError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Manifest(e.into())
+    }
+}
+
+/// Writes a file by writing a temporary file next to it and renaming that over the top, so
+/// anyone reading it concurrently (or after we crash) sees either the old contents or the new
+/// ones and never half of each.
+fn write_atomically(path: &Path, contents: impl FnOnce(&mut std::fs::File) -> Result<(), ManifestError>) -> Result<(), ManifestError> {
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(path.file_name().expect("path should be a file"));
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let result = (|| {
+        let mut file = std::fs::File::create(&temp_path)?;
+        contents(&mut file)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// The version lives alongside the manifest's own fields rather than wrapping them, so old
+// unversioned manifests are still readable as version 0.
+#[derive(Serialize)]
+struct VersionedManifestRef<'a> {
+    version: u32,
+    #[serde(flatten)]
+    manifest: &'a MetadataManifest,
+}
+
+#[derive(Deserialize)]
+struct VersionHeader {
+    #[serde(default)]
+    version: u32,
+}
+
 fn strcat(first: &str, rest: &str) -> String {
     let mut s = String::from(first);
     s.push_str(rest);
@@ -90,12 +267,25 @@ fn snap_to_nearest(val: u16, legal: &[u16]) -> u16 {
     return legal[legal.len()-1];
 }
 
+/// Picks the Cytube quality level for a video of the given (cropped) size.  Quality levels are
+/// named after the height of a landscape video, so this goes by the short side: a 1080x1920 phone
+/// video is 1080p, and so is a 2560x1080 ultrawide one.  Scope movies with the black bars cropped
+/// off go by what's left, so 1920x800 is nearer 720p than 1080p.
+pub fn quality_for_resolution(width: u16, height: u16) -> u16 {
+    let short_side = match (width, height) {
+        // we don't always know both dimensions
+        (0, x) | (x, 0) => x,
+        (w, h) => w.min(h),
+    };
+    snap_to_nearest(short_side, &CYTUBE_ACCEPTABLE_QUALITY_VALUES)
+}
+
 impl VideoMetadata {
     pub fn to_source(&self, url_prefix: &str) -> cytube::Source {
         cytube::Source {
             bitrate: None,
-            quality: snap_to_nearest(self.resolution_v, &CYTUBE_QUALITY_VALUES),
-            content_type: self.container.mimetype(),
+            quality: quality_for_resolution(self.resolution_h, self.resolution_v),
+            content_type: self.container.mimetype().into(),
             url: strcat(url_prefix, &self.filename),
         }
     }
@@ -105,15 +295,15 @@ impl AudioMetadata {
     pub fn to_source(&self, url_prefix: &str) -> cytube::Source {
         cytube::Source {
             bitrate: None,
-            quality: 240,
-            content_type: self.container.mimetype(),
+            quality: self.source_quality.unwrap_or(240),
+            content_type: self.container.mimetype().into(),
             url: strcat(url_prefix, &self.filename),
         }
     }
     pub fn to_audio_track(&self, url_prefix: &str) -> cytube::AudioTrack {
         let ref language = self.language.as_str();
         cytube::AudioTrack {
-            content_type: self.container.mimetype(),
+            content_type: self.container.mimetype().into(),
             language: FF2CT.get(language).unwrap_or(language).to_string(),
             url: strcat(url_prefix, &self.filename),
             label: build_language_string(&language, self.title.as_ref().map(|x|x.as_str())),
@@ -128,9 +318,10 @@ impl TextMetadata {
             None => self.title.clone().unwrap_or("Unknown".to_string()),
         };
         cytube::TextTrack {
-            content_type: "text/vtt",
+            content_type: "text/vtt".into(),
             url: strcat(url_prefix, self.filename.as_str()),
             name: language_string,
+            default: self.default,
         }
     }
 }
@@ -140,10 +331,111 @@ impl MetadataManifest {
         cytube::CytubeVideo {
             title: self.title.clone(),
             duration: self.duration,
-            sources: self.video_files.iter().map(|x| x.to_source(url_prefix)).collect(),
-            audio_tracks: self.audio_files.iter().map(|x| x.to_audio_track(url_prefix)).collect(),
+            sources: self.video_files.iter().map(|x| x.to_source(url_prefix))
+                .chain(self.audio_files.iter().filter(|x| x.source_quality.is_some()).map(|x| x.to_source(url_prefix)))
+                .collect(),
+            audio_tracks: self.audio_files.iter().filter(|x| x.source_quality.is_none()).map(|x| x.to_audio_track(url_prefix)).collect(),
             text_tracks: self.text_files.iter().map(|x| x.to_text_track(url_prefix)).collect(),
+            thumbnail: self.thumbnail.as_ref().map(|x| strcat(url_prefix, x)),
+            live: false,
+        }
+    }
+
+    /// Reads the manifest previously saved into `dir` by `save()`.
+    pub fn load(dir: &Path) -> Result<Self, ManifestError> {
+        let data = std::fs::read(dir.join(MANIFEST_FILENAME))?;
+        let header: VersionHeader = serde_json::from_slice(&data)?;
+        if header.version > MANIFEST_VERSION {
+            return Err(ManifestError::UnsupportedVersion(header.version));
+        }
+        // version 0 and version 1 have the same layout, 1 just says so.
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Writes the manifest into `dir`, atomically.
+    pub fn save(&self, dir: &Path) -> Result<(), ManifestError> {
+        write_atomically(&dir.join(MANIFEST_FILENAME), |file| {
+            serde_json::to_writer_pretty(&mut *file, &VersionedManifestRef { version: MANIFEST_VERSION, manifest: self })?;
+            file.write_all(b"\n")?;
+            Ok(())
+        })
+    }
+
+    /// Writes the Cytube manifest for this into `dir`, atomically.
+    pub fn save_cytube(&self, dir: &Path, url_prefix: &str) -> Result<(), ManifestError> {
+        write_atomically(&dir.join(CYTUBE_MANIFEST_FILENAME), |file| {
+            serde_json::to_writer(file, &self.to_cytube(url_prefix))?;
+            Ok(())
+        })
+    }
+
+    /// Deletes the files listed in `to_remove` from `dir` and drops them from the manifest, then
+    /// saves both manifests.  Nothing is touched unless every file listed is a plain filename
+    /// that's in the manifest.  The manifests get saved before anything is deleted, so if we're
+    /// interrupted the worst case is a stray file rather than a manifest pointing at nothing.
+    pub fn apply_removal(&mut self, dir: &Path, to_remove: &ToRemove, url_prefix: &str) -> Result<(), RemoveError> {
+        let lists = [
+            (&to_remove.video_files, self.video_files.iter().map(|x| x.filename.as_str()).collect::<Vec<_>>()),
+            (&to_remove.audio_files, self.audio_files.iter().map(|x| x.filename.as_str()).collect()),
+            (&to_remove.text_files, self.text_files.iter().map(|x| x.filename.as_str()).collect()),
+        ];
+        for (requested, ours) in &lists {
+            for filename in requested.iter() {
+                let mut components = Path::new(filename).components();
+                if !matches!((components.next(), components.next()), (Some(std::path::Component::Normal(_)), None)) {
+                    return Err(RemoveError::PathEscapes(filename.clone()));
+                }
+                if !ours.contains(&filename.as_str()) {
+                    return Err(RemoveError::NotInManifest(filename.clone()));
+                }
+            }
+        }
+
+        self.discard(to_remove);
+        self.save(dir)?;
+        self.save_cytube(dir, url_prefix)?;
+
+        for filename in to_remove.video_files.iter().chain(&to_remove.audio_files).chain(&to_remove.text_files) {
+            match std::fs::remove_file(dir.join(filename)) {
+                Ok(()) => {},
+                // already gone, which is what we wanted anyway
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every file the manifest refers to exists in `dir` and that ffprobe agrees
+    /// with what the manifest says is in it.  Returns an empty Vec if everything checks out.
+    pub fn validate(&self, config: &FfmpegConfig, dir: &Path) -> Vec<ManifestProblem> {
+        let mut problems = Vec::new();
+        for video in &self.video_files {
+            let Some(probe) = probe_file(config, dir, &video.filename, &mut problems) else { continue };
+            check_container(&video.filename, video.container.mimetype(), video.container.format_name(), &probe, &mut problems);
+            check_codec(&video.filename, TrackType::Video, video.video_codec.as_ref(), video.video_codec, &probe, &mut problems);
+            match video.audio_codec {
+                Some(codec) => check_codec(&video.filename, TrackType::Audio, codec.as_ref(), codec, &probe, &mut problems),
+                None => if probe.tracks.iter().any(|x| x.kind == TrackType::Audio) {
+                    problems.push(ManifestProblem::UnexpectedAudio(video.filename.clone()));
+                },
+            }
         }
+        for audio in &self.audio_files {
+            let Some(probe) = probe_file(config, dir, &audio.filename, &mut problems) else { continue };
+            check_container(&audio.filename, audio.container.mimetype(), audio.container.format_name(), &probe, &mut problems);
+            check_codec(&audio.filename, TrackType::Audio, audio.codec.as_ref(), audio.codec, &probe, &mut problems);
+        }
+        for text in &self.text_files {
+            let Some(probe) = probe_file(config, dir, &text.filename, &mut problems) else { continue };
+            check_container(&text.filename, "text/vtt", "webvtt", &probe, &mut problems);
+        }
+        if let Some(thumbnail) = &self.thumbnail {
+            if !dir.join(thumbnail).is_file() {
+                problems.push(ManifestProblem::Missing(thumbnail.clone()));
+            }
+        }
+        problems
     }
 
     pub fn discard(&mut self, discard: &ToRemove) {
@@ -153,6 +445,202 @@ impl MetadataManifest {
     }
 }
 
+/// Makes every one of `files` a source of an audio-only item.  Cytube wants each source to have
+/// its own quality level, which doesn't mean much for audio, so they're handed out in order of
+/// how good the codec sounds: whichever sounds best gets the highest.
+pub fn make_audio_sources(files: &mut [AudioMetadata]) {
+    let mut order = (0..files.len()).collect::<Vec<_>>();
+    order.sort_by_key(|i| files[*i].codec.quality_rank());
+    for (rank, i) in order.into_iter().enumerate() {
+        let levels = &CYTUBE_ACCEPTABLE_QUALITY_VALUES;
+        // more than 8 sources gets caught by CytubeVideo::validate()
+        files[i].source_quality = Some(levels[rank.min(levels.len() - 1)]);
+    }
+}
+
+/// Parses the `audio_{index}_{lang}` / `sub_{index}_{lang}` names `build_ffmpeg_command` gives
+/// its outputs.  `stem` is the filename without its extension.  Returns the track index and the
+/// language, which is None if the track didn't have one.
+fn parse_track_filename(stem: &str, prefix: &str) -> Option<(u16, Option<fixedstr::str4>)> {
+    let rest = stem.strip_prefix(prefix)?.strip_prefix('_')?;
+    let (index, lang) = rest.split_once('_')?;
+    let index = index.parse().ok()?;
+    match lang {
+        "unknown" | "unk" | "und" => Some((index, None)),
+        x if (2..=3).contains(&x.len()) => Some((index, Some(x.into()))),
+        _ => None,
+    }
+}
+
+fn unusable(filename: &str, reason: impl Into<String>) -> ScanError {
+    ScanError::Unusable { filename: filename.to_owned(), reason: reason.into() }
+}
+
+/// Rebuilds a manifest from whatever is in an output directory, for when the saved one has been
+/// lost or files have been added by hand.  Every video, audio and subtitle file gets probed, so
+/// this is slow-ish for big directories.  Files we don't recognize by extension are ignored, and
+/// ones we can't use get left out, with a `ScanError::Unusable` for each in the second half of
+/// the result.
+pub fn scan_output_directory(config: &FfmpegConfig, dir: &Path) -> Result<(MetadataManifest, Vec<ScanError>), ScanError> {
+    let mut filenames = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        // filenames that aren't UTF-8 can't go in a URL we hand to Cytube anyway
+        if let Ok(name) = entry.file_name().into_string() {
+            if !name.starts_with('.') {
+                filenames.push(name);
+            }
+        }
+    }
+    // read_dir() order is arbitrary, and we want the same directory to always give the same
+    // manifest
+    filenames.sort();
+
+    let mut manifest = MetadataManifest {
+        video_files: Vec::new(),
+        audio_files: Vec::new(),
+        text_files: Vec::new(),
+        duration: 0.0,
+        title: String::new(),
+        muxed_audio: None,
+        thumbnail: None,
+    };
+    let mut title = None;
+    let mut skipped = Vec::new();
+
+    for filename in filenames {
+        if filename == crate::transcode::THUMBNAIL_FILENAME {
+            manifest.thumbnail = Some(filename);
+            continue;
+        }
+        let Some((_, extension)) = filename.rsplit_once('.') else { continue };
+        let kind = match extension {
+            "webm" | "mp4" | "ogv" => TrackType::Video,
+            "ogg" | "m4a" | "aac" | "mp3" => TrackType::Audio,
+            "vtt" => TrackType::Subtitle,
+            _ => continue,
+        };
+        let probe = ffprobe(config, &dir.join(&filename)).map_err(|error| ScanError::Probe { filename: filename.clone(), error })?;
+        // one file we can't make sense of shouldn't stop the rest of the directory being usable
+        if let Err(e) = add_scanned_file(&mut manifest, &mut title, filename, kind, &probe) {
+            skipped.push(e);
+        }
+    }
+
+    // with no video, the audio is all there is
+    if manifest.video_files.is_empty() {
+        make_audio_sources(&mut manifest.audio_files);
+    }
+
+    manifest.title = title.unwrap_or_else(|| dir.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default());
+    Ok((manifest, skipped))
+}
+
+/// Adds one probed file from an output directory to `manifest`, or says why it can't be used.
+fn add_scanned_file(manifest: &mut MetadataManifest, title: &mut Option<String>, filename: String, kind: TrackType, probe: &crate::ffprobe::FFprobeResult) -> Result<(), ScanError> {
+    let (stem, extension) = filename.rsplit_once('.').expect("we only get here for files with an extension");
+    let first_track = |kind: TrackType| probe.tracks.iter().find(|x| x.kind == kind);
+    match kind {
+        TrackType::Video => {
+            let track = first_track(TrackType::Video).ok_or_else(|| unusable(&filename, "no video stream"))?;
+            let video_codec = track.codec.parse().map_err(|_| unusable(&filename, format!("unsupported video codec {}", track.codec)))?;
+            let audio = first_track(TrackType::Audio);
+            let audio_codec = audio.map(|x| x.codec.parse().map_err(|_| unusable(&filename, format!("unsupported audio codec {}", x.codec)))).transpose()?;
+            if let Some(audio) = audio {
+                if manifest.muxed_audio.is_none() {
+                    manifest.muxed_audio = Some(MuxedAudioMetadata {
+                        language: audio.language.unwrap_or("unk".into()),
+                        title: audio.title.clone(),
+                    });
+                }
+            }
+            if title.is_none() {
+                *title = probe.title.clone();
+            }
+            manifest.video_files.push(VideoMetadata {
+                container: VideoContainer::from_extension(extension).expect("we only get here for video extensions"),
+                video_codec,
+                audio_codec,
+                // there's no telling generated silence apart from a real track that happens to
+                // be quiet
+                audio_is_silent: false,
+                audio_bitrate: audio.and_then(|x| x.bitrate),
+                resolution_h: track.resolution_h.unwrap_or(0),
+                resolution_v: track.resolution_v.unwrap_or(0),
+                filename,
+            });
+        },
+        TrackType::Audio => {
+            let track = first_track(TrackType::Audio).ok_or_else(|| unusable(&filename, "no audio stream"))?;
+            let codec: AudioCodec = track.codec.parse().map_err(|_| unusable(&filename, format!("unsupported audio codec {}", track.codec)))?;
+            let container = match extension {
+                "ogg" => AudioContainer::OGG,
+                "aac" => AudioContainer::ADTS,
+                "mp3" => AudioContainer::MP3,
+                // m4a files are either the real thing or MP4s in disguise, depending on the
+                // codec.  same decision build_ffmpeg_command makes.
+                _ => AudioContainer::find(codec),
+            };
+            let from_filename = parse_track_filename(stem, "audio").and_then(|x| x.1);
+            manifest.audio_files.push(AudioMetadata {
+                container,
+                codec,
+                language: track.language.or(from_filename).unwrap_or("unk".into()),
+                title: track.title.clone(),
+                source_quality: None,
+                filename,
+            });
+        },
+        TrackType::Subtitle => {
+            // ffmpeg doesn't write language or title tags into webvtt files, so the filename
+            // is all we have to go on
+            let language = parse_track_filename(stem, "sub").and_then(|x| x.1);
+            let title = probe.tracks.first().and_then(|x| x.title.clone());
+            // nothing in a vtt file says whether it's the default, so that has to be
+            // chosen again by hand
+            manifest.text_files.push(TextMetadata { filename, language, title, default: false });
+        },
+    }
+    manifest.duration = manifest.duration.max(probe.duration);
+    Ok(())
+}
+
+fn probe_file(config: &FfmpegConfig, dir: &Path, filename: &str, problems: &mut Vec<ManifestProblem>) -> Option<crate::ffprobe::FFprobeResult> {
+    let path = dir.join(filename);
+    if !path.is_file() {
+        problems.push(ManifestProblem::Missing(filename.to_owned()));
+        return None;
+    }
+    match ffprobe(config, &path) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            problems.push(ManifestProblem::Unreadable { filename: filename.to_owned(), error: e.to_string() });
+            None
+        },
+    }
+}
+
+fn check_container(filename: &str, mimetype: &'static str, format_name: &str, probe: &crate::ffprobe::FFprobeResult, problems: &mut Vec<ManifestProblem>) {
+    // ffprobe reports a list of demuxer names, e.g. "mov,mp4,m4a,3gp,3g2,mj2"
+    if !probe.format.as_ref().is_some_and(|x| x.split(',').any(|x| x == format_name)) {
+        problems.push(ManifestProblem::WrongContainer { filename: filename.to_owned(), expected: mimetype, found: probe.format.clone() });
+    }
+}
+
+fn check_codec(filename: &str, kind: TrackType, codec_name: &str, pretty_name: impl std::fmt::Display, probe: &crate::ffprobe::FFprobeResult, problems: &mut Vec<ManifestProblem>) {
+    let mut tracks = probe.tracks.iter().filter(|x| x.kind == kind);
+    if !tracks.clone().any(|x| x.codec == codec_name) {
+        problems.push(ManifestProblem::WrongCodec {
+            filename: filename.to_owned(),
+            expected: pretty_name.to_string(),
+            found: tracks.next().map(|x| x.codec.clone()),
+        });
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -162,7 +650,165 @@ mod test {
         assert_eq!(snap_to_nearest(4,&[1,5]),5);
         assert_eq!(snap_to_nearest(6,&[1,5]),5);
 
-        assert_eq!(snap_to_nearest(535,&CYTUBE_QUALITY_VALUES),540);
-        assert_eq!(snap_to_nearest(555,&CYTUBE_QUALITY_VALUES),540);
+        assert_eq!(snap_to_nearest(535,&CYTUBE_ACCEPTABLE_QUALITY_VALUES),540);
+        assert_eq!(snap_to_nearest(555,&CYTUBE_ACCEPTABLE_QUALITY_VALUES),540);
+        assert_eq!(snap_to_nearest(1440,&CYTUBE_ACCEPTABLE_QUALITY_VALUES),1440);
+        assert_eq!(snap_to_nearest(1600,&CYTUBE_ACCEPTABLE_QUALITY_VALUES),1440);
+        assert_eq!(snap_to_nearest(4320,&CYTUBE_ACCEPTABLE_QUALITY_VALUES),2160);
+    }
+
+    #[test]
+    fn test_quality_for_resolution() {
+        // 16:9
+        assert_eq!(quality_for_resolution(1920, 1080), 1080);
+        assert_eq!(quality_for_resolution(2560, 1440), 1440);
+        assert_eq!(quality_for_resolution(3840, 2160), 2160);
+        // scope movies with the letterboxing cropped off go by the height that's left
+        assert_eq!(quality_for_resolution(1920, 822), 720);
+        assert_eq!(quality_for_resolution(1920, 800), 720);
+        assert_eq!(quality_for_resolution(1280, 536), 540);
+        assert_eq!(quality_for_resolution(3840, 1608), 1440);
+        // ultrawide
+        assert_eq!(quality_for_resolution(2560, 1080), 1080);
+        assert_eq!(quality_for_resolution(3440, 1440), 1440);
+        // 4:3
+        assert_eq!(quality_for_resolution(640, 480), 480);
+        assert_eq!(quality_for_resolution(1440, 1080), 1080);
+        // vertical
+        assert_eq!(quality_for_resolution(1080, 1920), 1080);
+        assert_eq!(quality_for_resolution(720, 1280), 720);
+        assert_eq!(quality_for_resolution(1440, 2560), 1440);
+        // unknown
+        assert_eq!(quality_for_resolution(0, 480), 480);
+        assert_eq!(quality_for_resolution(0, 0), 240);
+    }
+
+    #[test]
+    fn test_parse_track_filename() {
+        assert_eq!(parse_track_filename("audio_1_jpn", "audio"), Some((1, Some("jpn".into()))));
+        assert_eq!(parse_track_filename("sub_12_unknown", "sub"), Some((12, None)));
+        assert_eq!(parse_track_filename("sub_3_en", "sub"), Some((3, Some("en".into()))));
+        assert_eq!(parse_track_filename("sub_3_eng", "audio"), None);
+        assert_eq!(parse_track_filename("audio_x_eng", "audio"), None);
+        assert_eq!(parse_track_filename("audio_2", "audio"), None);
+        assert_eq!(parse_track_filename("audio_2_english", "audio"), None);
+    }
+
+    fn example_manifest() -> MetadataManifest {
+        MetadataManifest {
+            video_files: vec![VideoMetadata {
+                filename: "video.webm".into(),
+                container: VideoContainer::WEBM,
+                video_codec: VideoCodec::VP9,
+                audio_codec: Some(AudioCodec::Opus),
+                audio_is_silent: false,
+                audio_bitrate: None,
+                resolution_h: 1920,
+                resolution_v: 1080,
+            }],
+            audio_files: vec![],
+            text_files: vec![TextMetadata { filename: "sub_2_eng.vtt".into(), language: Some("eng".into()), title: None, default: true }],
+            duration: 12.5,
+            title: "Example".into(),
+            muxed_audio: Some(MuxedAudioMetadata { language: "eng".into(), title: None }),
+            thumbnail: None,
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("cytrans-manifest-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        example_manifest().save(&dir).unwrap();
+        let loaded = MetadataManifest::load(&dir).unwrap();
+        assert_eq!(loaded.title, "Example");
+        assert_eq!(loaded.video_files[0].video_codec, VideoCodec::VP9);
+        assert_eq!(loaded.text_files[0].language.as_ref().map(|x| x.as_str()), Some("eng"));
+        assert!(loaded.text_files[0].default);
+        assert!(!dir.join(format!(".{}.tmp", MANIFEST_FILENAME)).exists());
+
+        let mut json: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join(MANIFEST_FILENAME)).unwrap()).unwrap();
+        assert_eq!(json["version"], MANIFEST_VERSION);
+
+        // manifests from before we started versioning them should still load
+        json.as_object_mut().unwrap().remove("version");
+        std::fs::write(dir.join(MANIFEST_FILENAME), json.to_string()).unwrap();
+        assert!(MetadataManifest::load(&dir).is_ok());
+
+        json["version"] = (MANIFEST_VERSION + 1).into();
+        std::fs::write(dir.join(MANIFEST_FILENAME), json.to_string()).unwrap();
+        assert!(matches!(MetadataManifest::load(&dir), Err(ManifestError::UnsupportedVersion(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply_removal() {
+        let dir = std::env::temp_dir().join(format!("cytrans-remove-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("video.webm"), b"").unwrap();
+        std::fs::write(dir.join("sub_2_eng.vtt"), b"").unwrap();
+        let mut manifest = example_manifest();
+
+        for filename in ["../video.webm", "/etc/passwd", "", "."] {
+            let to_remove = ToRemove { text_files: vec![filename.into()], ..ToRemove::default() };
+            assert!(matches!(manifest.apply_removal(&dir, &to_remove, "https://example.com/"), Err(RemoveError::PathEscapes(_))), "{}", filename);
+        }
+        let to_remove = ToRemove { text_files: vec!["video.webm".into()], ..ToRemove::default() };
+        assert!(matches!(manifest.apply_removal(&dir, &to_remove, "https://example.com/"), Err(RemoveError::NotInManifest(_))));
+        assert!(dir.join("video.webm").exists());
+
+        let to_remove = ToRemove { text_files: vec!["sub_2_eng.vtt".into()], ..ToRemove::default() };
+        manifest.apply_removal(&dir, &to_remove, "https://example.com/").unwrap();
+        assert!(!dir.join("sub_2_eng.vtt").exists());
+        assert!(dir.join("video.webm").exists());
+        assert!(MetadataManifest::load(&dir).unwrap().text_files.is_empty());
+        let cytube: crate::cytube_structs::CytubeVideo = serde_json::from_slice(&std::fs::read(dir.join(CYTUBE_MANIFEST_FILENAME)).unwrap()).unwrap();
+        assert!(cytube.text_tracks.is_empty());
+        assert_eq!(cytube.sources[0].url, "https://example.com/video.webm");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_missing_files() {
+        let dir = std::env::temp_dir().join(format!("cytrans-validate-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let problems = example_manifest().validate(&FfmpegConfig::default(), &dir);
+        assert_eq!(problems, vec![
+            ManifestProblem::Missing("video.webm".into()),
+            ManifestProblem::Missing("sub_2_eng.vtt".into()),
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_scan_skips_unusable_files() {
+        use std::os::unix::fs::PermissionsExt as _;
+        let dir = std::env::temp_dir().join(format!("cytrans-scan-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // a stand-in for ffprobe that prints whatever's in the file, so the test doesn't need real
+        // media (or ffprobe)
+        let fake_ffprobe = dir.join(".ffprobe");
+        std::fs::write(&fake_ffprobe, "#!/bin/sh\ncat \"$1\"\n").unwrap();
+        std::fs::set_permissions(&fake_ffprobe, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config = FfmpegConfig { ffprobe: fake_ffprobe, ..FfmpegConfig::default() };
+
+        std::fs::write(dir.join("video.webm"), "stream|index=0|codec_type=video|codec_name=vp9|width=1920|height=1080\nformat|duration=60.0|bit_rate=1000|format_name=matroska,webm\n").unwrap();
+        std::fs::write(dir.join("broken.mp4"), "stream|index=0|codec_type=video|codec_name=mpeg2video|width=720|height=480\nformat|duration=90.0|bit_rate=1000|format_name=mov,mp4\n").unwrap();
+        std::fs::write(dir.join("silent.ogg"), "format|duration=60.0|bit_rate=0|format_name=ogg\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not media").unwrap();
+
+        let (manifest, skipped) = scan_output_directory(&config, &dir).unwrap();
+        assert_eq!(manifest.video_files.len(), 1);
+        assert_eq!(manifest.video_files[0].filename, "video.webm");
+        assert!(manifest.audio_files.is_empty());
+        // the broken file's duration doesn't count either
+        assert_eq!(manifest.duration, 60.0);
+        let skipped = skipped.iter().map(|x| match x {
+            ScanError::Unusable { filename, .. } => filename.as_str(),
+            x => panic!("unexpected {}", x),
+        }).collect::<Vec<_>>();
+        assert_eq!(skipped, ["broken.mp4", "silent.ogg"]);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
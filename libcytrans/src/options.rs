@@ -1,7 +1,9 @@
 use crate::ffprobe::Track;
+use crate::filenames::FilenameTemplates;
+use fixedstr::str4;
 use std::ffi::OsString;
-use std::path::Path;
-use serde::{Serialize, Serializer, ser::SerializeSeq};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Serializer};
 
 use std::fmt::*;
 
@@ -45,6 +47,36 @@ pub enum AudioCodec {
     MP3,
 }
 
+impl AudioCodec {
+    /// How good this codec sounds relative to the others, all else being equal.  Higher is
+    /// better.  Lossless beats everything, and after that it's roughly in order of age.
+    pub fn quality_rank(&self) -> u8 {
+        use AudioCodec::*;
+        match self {
+            FLAC | ALAC => 4,
+            Opus => 3,
+            AAC => 2,
+            Vorbis => 1,
+            MP3 => 0,
+        }
+    }
+
+    /// Sort key for picking the best-sounding of several tracks, higher is better.  Bitrate
+    /// counts for the most, since 320k AAC beats 32k Opus however good Opus is.  Bitrates within
+    /// about a fifth of each other count as the same, and then the codec decides.  Lossless always
+    /// wins, and an unknown bitrate counts as the lowest.
+    pub fn quality_key(&self, bitrate: Option<u64>) -> (u32, u8, u64) {
+        let band = match (self, bitrate) {
+            (AudioCodec::FLAC | AudioCodec::ALAC, _) => u32::MAX,
+            // quarter-octave bands, centred so that the usual 96k, 128k, 192k and so on land in
+            // the middle of one rather than on an edge
+            (_, Some(b)) if b > 0 => ((b as f64).log2() * 4.0).round() as u32,
+            _ => 0,
+        };
+        (band, self.quality_rank(), bitrate.unwrap_or(0))
+    }
+}
+
 impl Display for AudioCodec {
     fn fmt(&self, fmt: &mut Formatter<'_>) -> Result {
         use AudioCodec::*;
@@ -69,30 +101,329 @@ pub struct TrackOptions<'a, C> {
     pub extra_ffmpeg_args: Vec<OsString>,
     pub encoder: String,
     pub bitrate: Option<u32>,
+    pub overrides: MetadataOverrides,
+}
+
+impl<'a, C> TrackOptions<'a, C> {
+    pub fn new(track: &'a Track, codec: C, encoder: String) -> Self {
+        Self { track, codec, extra_ffmpeg_args: vec![], encoder, bitrate: None, overrides: MetadataOverrides::default() }
+    }
+}
+
+/// Corrections to what the input file says about a track.  Plenty of files have no language
+/// tags, or English tagged as Japanese, and titles like "Track 2".
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MetadataOverrides {
+    /// An ISO 639-2 code as checked by `parse_language()`.  "und" means the language is unknown,
+    /// even if the input file thinks otherwise.
+    pub language: Option<str4>,
+    /// An empty string gets rid of the track's title.
+    pub title: Option<String>,
+}
+
+impl MetadataOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.language.is_none() && self.title.is_none()
+    }
+
+    /// The track's language, after overrides.
+    pub fn language(&self, track: &Track) -> Option<str4> {
+        match self.language {
+            Some(x) if x == "und" => None,
+            Some(x) => Some(x),
+            None => track.language,
+        }
+    }
+
+    /// The track's title, after overrides.
+    pub fn title<'a>(&'a self, track: &'a Track) -> Option<&'a str> {
+        match &self.title {
+            Some(x) if x.is_empty() => None,
+            Some(x) => Some(x),
+            None => track.title.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownLanguage(pub String);
+
+impl Display for UnknownLanguage {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> Result {
+        write!(fmt, "{:?} isn't an ISO 639-2 language code (like eng or jpn)", self.0)
+    }
+}
+
+impl std::error::Error for UnknownLanguage {}
+
+/// Checks a language code for `MetadataOverrides`.  Takes the three letter codes ffmpeg and
+/// Matroska use, plus "und" for unknown.
+pub fn parse_language(s: &str) -> std::result::Result<str4, UnknownLanguage> {
+    let s = s.trim().to_ascii_lowercase();
+    if s == "und" || crate::ffmpeg_languages::ENGLISH_NAMES.contains_key(s.as_str()) {
+        Ok(s.as_str().into())
+    } else {
+        Err(UnknownLanguage(s))
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct SubtitleOptions<'a> {
+    #[serde(serialize_with="serialize_track_id")]
+    pub track: &'a Track,
+    /// Whether Cytube should turn this track on without the viewer asking.  At most one track
+    /// should have this set.
+    pub default: bool,
+    pub overrides: MetadataOverrides,
+    /// A subtitle file sitting next to the input (see `sidecars`), if the track comes from one of
+    /// those rather than the input itself.  `track` is then ffprobe's idea of the file's track.
+    pub file: Option<PathBuf>,
+}
+
+impl<'a> SubtitleOptions<'a> {
+    pub fn new(track: &'a Track) -> Self {
+        Self { track, default: false, overrides: MetadataOverrides::default(), file: None }
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Default, Serialize)]
 pub struct TranscodeArgs<'ff> {
     pub video_tracks: Vec<TrackOptions<'ff, VideoCodec>>,
     pub audio_tracks: Vec<TrackOptions<'ff, AudioCodec>>,
-    #[serde(serialize_with="serialize_track_ids")]
-    pub subtitle_tracks: Vec<&'ff Track>,
+    pub subtitle_tracks: Vec<SubtitleOptions<'ff>>,
+    /// Global options, e.g. `-loglevel`.  These go first, before the input.
     pub extra_ffmpeg_args: Vec<OsString>,
+    /// Options for the input file, e.g. `-analyzeduration` or `-headers` for a URL.  These go
+    /// just before its `-i`.
+    pub input_ffmpeg_args: Vec<OsString>,
+    /// Options for every output file, e.g. `-map_metadata -1`.  Each track's own
+    /// `extra_ffmpeg_args` come after these, so they win if the two disagree.
+    pub output_ffmpeg_args: Vec<OsString>,
     pub title: String,
     #[serde(skip_serializing)]
     pub duration: f32,
     pub force_demux_audio: bool,
     pub add_muxed_silence: bool,
+    pub filenames: FilenameTemplates,
+    pub trim: Trim,
+    /// Something to look at for inputs with no video.  Ignored if there are any video tracks.
+    pub visual: Option<AudioVisual>,
+    /// A bitmap subtitle track in the input to draw onto the video, since Cytube can only show
+    /// text subtitles.  Only works on video tracks that are being re-encoded.
+    pub burn_subtitles: Option<u16>,
+}
+
+/// What to put behind an audio-only video.
+#[derive(Debug, Clone, PartialEq, Serialize, serde::Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum VisualBackground {
+    /// The cover art embedded in the input, which ffprobe lists as a video track with
+    /// `attached_pic` set.  This is the track index.
+    CoverArt(u16),
+    /// An image file.
+    Image(PathBuf),
+    /// Plain black, for when all you want is the waveform.
+    Black,
+}
+
+/// A video track to make for an input that doesn't have one.  Without this, audio-only inputs
+/// become audio-only Cytube items, which is fine for music but leaves everyone staring at a
+/// black rectangle.
+#[derive(Debug, Clone, PartialEq, Serialize, serde::Deserialize)]
+pub struct AudioVisual {
+    pub background: VisualBackground,
+    /// Draw the audio's waveform along the bottom.
+    pub waveform: bool,
+    pub codec: VideoCodec,
+    pub encoder: String,
+}
+
+/// Where a trimmed clip stops.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, serde::Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum TrimEnd {
+    /// A timestamp in the input, in seconds.
+    At(f32),
+    /// How long the clip should be, in seconds from the start of the clip.
+    Duration(f32),
+}
+
+/// Which part of the input to keep, for posting one episode out of a compilation or a clip out
+/// of a stream.  The default keeps the whole thing.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, serde::Deserialize)]
+pub struct Trim {
+    /// Where to start, in seconds into the input.
+    #[serde(default)]
+    pub start: Option<f32>,
+    #[serde(default)]
+    pub end: Option<TrimEnd>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrimError {
+    BadTimestamp(String),
+    Negative,
+    EndBeforeStart,
+    /// The clip starts after the input has already finished.
+    PastEnd { start: f32, duration: f32 },
+}
+
+impl Display for TrimError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::BadTimestamp(x) => write!(fmt, "{:?} isn't a timestamp (try 90, 1:30 or 1:01:30.5)", x),
+            Self::Negative => write!(fmt, "trim times can't be negative"),
+            Self::EndBeforeStart => write!(fmt, "the clip has to end after it starts"),
+            Self::PastEnd { start, duration } => write!(fmt, "the clip starts at {}s but the input is only {}s long", start, duration),
+        }
+    }
+}
+
+impl std::error::Error for TrimError {}
+
+/// Parses a timestamp the way people write them: plain seconds (`90`, `90.5`), `MM:SS` or
+/// `HH:MM:SS`, with optional fractional seconds on the end.
+pub fn parse_timestamp(s: &str) -> std::result::Result<f32, TrimError> {
+    let bad = || TrimError::BadTimestamp(s.to_owned());
+    let parts = s.trim().split(':').collect::<Vec<_>>();
+    if parts.len() > 3 {
+        return Err(bad());
+    }
+    let (seconds, rest) = parts.split_last().ok_or_else(bad)?;
+    let seconds = seconds.parse::<f32>().ok().filter(|x| x.is_finite() && *x >= 0.0).ok_or_else(bad)?;
+    // minutes and seconds past the first field are supposed to be under 60, but "1:90" is still
+    // pretty clearly 150 seconds so there's no point being fussy about it
+    let mut total = 0.0;
+    for part in rest {
+        let x = part.parse::<u32>().map_err(|_| bad())?;
+        total = total * 60.0 + x as f32;
+    }
+    Ok(total * 60.0 + seconds)
+}
+
+impl Trim {
+    pub fn is_whole_input(&self) -> bool {
+        self.start.unwrap_or(0.0) == 0.0 && self.end.is_none()
+    }
+
+    pub fn start(&self) -> f32 {
+        self.start.unwrap_or(0.0)
+    }
+
+    /// How long the clip is, given how long the input is.
+    pub fn duration(&self, input_duration: f32) -> f32 {
+        let start = self.start();
+        let end = match self.end {
+            Some(TrimEnd::At(x)) => x,
+            Some(TrimEnd::Duration(x)) => start + x,
+            None => input_duration,
+        };
+        (end.min(input_duration) - start).max(0.0)
+    }
+
+    /// How much to pass to ffmpeg's `-t`, or None if it should keep going to the end.
+    pub fn ffmpeg_duration(&self) -> Option<f32> {
+        match self.end? {
+            TrimEnd::At(x) => Some(x - self.start()),
+            TrimEnd::Duration(x) => Some(x),
+        }
+    }
+
+    pub fn validate(&self, input_duration: f32) -> std::result::Result<(), TrimError> {
+        let start = self.start();
+        let end = match self.end {
+            Some(TrimEnd::At(x)) => Some(x),
+            Some(TrimEnd::Duration(x)) => Some(start + x),
+            None => None,
+        };
+        if start < 0.0 || end.is_some_and(|x| x < 0.0) || matches!(self.end, Some(TrimEnd::Duration(x)) if x < 0.0) {
+            return Err(TrimError::Negative);
+        }
+        if end.is_some_and(|x| x <= start) {
+            return Err(TrimError::EndBeforeStart);
+        }
+        // ffprobe reports 0 when it doesn't know, e.g. for some live streams
+        if input_duration > 0.0 && start >= input_duration {
+            return Err(TrimError::PastEnd { start, duration: input_duration });
+        }
+        Ok(())
+    }
 }
 
 fn serialize_track_id<S: serde::Serializer>(track: &Track, s: S) -> std::result::Result<S::Ok, S::Error> {
     s.serialize_u16(track.index)
 }
 
-fn serialize_track_ids<S: serde::Serializer>(tracks: &Vec<&Track>, s: S) -> std::result::Result<S::Ok, S::Error> {
-    let mut a = s.serialize_seq(Some(tracks.len()))?;
-    for track in tracks {
-        a.serialize_element(&track.index)?;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_audio_quality_key() {
+        use AudioCodec::*;
+        let key = |codec: AudioCodec, bitrate| codec.quality_key(bitrate);
+        assert!(key(AAC, Some(320_000)) > key(Opus, Some(32_000)));
+        assert!(key(MP3, Some(192_000)) > key(Opus, Some(96_000)));
+        // close enough that the codec decides
+        assert!(key(Opus, Some(128_000)) > key(AAC, Some(128_000)));
+        assert!(key(Opus, Some(128_000)) > key(AAC, Some(140_000)));
+        assert!(key(AAC, Some(128_000)) > key(Vorbis, Some(132_000)));
+        assert!(key(FLAC, None) > key(Opus, Some(510_000)));
+        assert!(key(MP3, Some(64_000)) > key(Opus, None));
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("90"), Ok(90.0));
+        assert_eq!(parse_timestamp("1:30.5"), Ok(90.5));
+        assert_eq!(parse_timestamp("1:01:30"), Ok(3690.0));
+        assert!(parse_timestamp("").is_err());
+        assert!(parse_timestamp("1:2:3:4").is_err());
+        assert!(parse_timestamp("-5").is_err());
+        assert!(parse_timestamp("1.5:00").is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        assert_eq!(parse_language("ENG"), Ok("eng".into()));
+        assert_eq!(parse_language("und"), Ok("und".into()));
+        assert!(parse_language("english").is_err());
+        assert!(parse_language("xx").is_err());
+
+        let track = Track {
+            language: Some("jpn".into()),
+            title: Some("Track 2".into()),
+            channels: Some(2),
+            ..Track::example(1, crate::ffprobe::TrackType::Audio, "aac")
+        };
+        let none = MetadataOverrides::default();
+        assert_eq!(none.language(&track), Some("jpn".into()));
+        assert_eq!(none.title(&track), Some("Track 2"));
+        let fixed = MetadataOverrides { language: Some("eng".into()), title: Some("".into()) };
+        assert_eq!(fixed.language(&track), Some("eng".into()));
+        assert_eq!(fixed.title(&track), None);
+        let unknown = MetadataOverrides { language: Some("und".into()), title: None };
+        assert_eq!(unknown.language(&track), None);
+    }
+
+    #[test]
+    fn test_trim() {
+        assert!(Trim::default().is_whole_input());
+        assert_eq!(Trim::default().duration(60.0), 60.0);
+
+        let trim = Trim { start: Some(10.0), end: Some(TrimEnd::At(25.0)) };
+        assert_eq!(trim.duration(60.0), 15.0);
+        assert_eq!(trim.ffmpeg_duration(), Some(15.0));
+        assert_eq!(trim.validate(60.0), Ok(()));
+
+        // running off the end of the input just stops at the end
+        let trim = Trim { start: Some(50.0), end: Some(TrimEnd::Duration(30.0)) };
+        assert_eq!(trim.duration(60.0), 10.0);
+        assert_eq!(trim.ffmpeg_duration(), Some(30.0));
+
+        assert_eq!(Trim { start: Some(20.0), end: Some(TrimEnd::At(10.0)) }.validate(60.0), Err(TrimError::EndBeforeStart));
+        assert_eq!(Trim { start: Some(70.0), end: None }.validate(60.0), Err(TrimError::PastEnd { start: 70.0, duration: 60.0 }));
+        assert_eq!(Trim { start: None, end: Some(TrimEnd::Duration(-1.0)) }.validate(60.0), Err(TrimError::Negative));
     }
-    a.end()
 }
@@ -0,0 +1,148 @@
+//! Splitting and quoting arguments the way a POSIX shell does, for letting people type in
+//! ffmpeg arguments and for showing them the commands we run.  Filters are full of spaces,
+//! commas and colons, so splitting on spaces doesn't cut it.
+//!
+//! Only quoting and escaping are supported.  There's no variable expansion, globbing, command
+//! substitution or anything else that would need an actual shell.
+
+use std::borrow::Cow;
+use std::process::Command;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// A quote (the char) that never got closed.
+    UnterminatedQuote(char),
+    /// A backslash at the very end, with nothing to escape.
+    TrailingBackslash,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnterminatedQuote(c) => write!(fmt, "missing closing {}", c),
+            Self::TrailingBackslash => write!(fmt, "backslash at the end with nothing after it"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Splits `s` into arguments.  Inside single quotes everything is literal.  Inside double quotes
+/// a backslash only escapes `"`, `\`, `$` and `` ` ``, like in sh.  Anywhere else it escapes
+/// whatever comes after it.
+pub fn split(s: &str) -> Result<Vec<String>, ParseError> {
+    let mut out = Vec::new();
+    // None between arguments, so that '' can still make an empty one
+    let mut current: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(arg) = current.take() {
+                    out.push(arg);
+                }
+            },
+            '\'' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(ParseError::UnterminatedQuote('\'')),
+                    }
+                }
+            },
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => arg.push(c),
+                            // a line continuation
+                            Some('\n') => {},
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            },
+                            None => return Err(ParseError::UnterminatedQuote('"')),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(ParseError::UnterminatedQuote('"')),
+                    }
+                }
+            },
+            '\\' => match chars.next() {
+                Some('\n') => {},
+                Some(c) => current.get_or_insert_with(String::new).push(c),
+                None => return Err(ParseError::TrailingBackslash),
+            },
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    out.extend(current);
+    Ok(out)
+}
+
+/// Quotes one argument so that `split()` (or sh) turns it back into exactly the same thing.
+/// Arguments that don't need quoting are left alone, so the usual `-c:v libx264` stays readable.
+pub fn quote(s: &str) -> Cow<'_, str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_+=:,./@%".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(format!("'{}'", s.replace('\'', r"'\''")))
+    }
+}
+
+/// Quotes every argument and puts spaces between them.  The inverse of `split()`.
+pub fn join<I: IntoIterator<Item=S>, S: AsRef<str>>(args: I) -> String {
+    args.into_iter().map(|x| quote(x.as_ref()).into_owned()).collect::<Vec<_>>().join(" ")
+}
+
+/// Renders a command the way you'd type it into a shell, environment variables and all, for
+/// printing.  Anything that isn't valid UTF-8 gets mangled, so don't try to run the result.
+pub fn render_command(command: &Command) -> String {
+    let env = command.get_envs()
+        .filter_map(|(k, v)| Some(format!("{}={}", k.to_string_lossy(), quote(&v?.to_string_lossy()))));
+    let program = std::iter::once(command.get_program()).chain(command.get_args())
+        .map(|x| quote(&x.to_string_lossy()).into_owned());
+    env.chain(program).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split(r#"-vf "scale=1280:-2, unsharp" -crf 30"#).unwrap(), ["-vf", "scale=1280:-2, unsharp", "-crf", "30"]);
+        assert_eq!(split("  -metadata   title='It'\\''s here'  ").unwrap(), ["-metadata", "title=It's here"]);
+        assert_eq!(split(r#"a\ b "c\"d\n" '' """#).unwrap(), ["a b", "c\"d\\n", "", ""]);
+        assert_eq!(split("").unwrap(), Vec::<String>::new());
+        assert_eq!(split("-vf 'scale"), Err(ParseError::UnterminatedQuote('\'')));
+        assert_eq!(split("-vf \"scale"), Err(ParseError::UnterminatedQuote('"')));
+        assert_eq!(split("-vf scale\\"), Err(ParseError::TrailingBackslash));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("libx264"), "libx264");
+        assert_eq!(quote("scale=1280:-2"), "scale=1280:-2");
+        assert_eq!(quote("scale=1280:-2, unsharp"), "'scale=1280:-2, unsharp'");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        // some shells (the original Bourne shell, zsh with extended globs) give ^ a meaning
+        assert_eq!(quote("^1"), "'^1'");
+
+        let args = ["-vf", "scale=1280:-2, unsharp", "", "it's \"quoted\"", "$HOME", "back\\slash"];
+        assert_eq!(split(&join(args)).unwrap(), args);
+    }
+
+    #[test]
+    fn test_render_command() {
+        let mut command = Command::new("ffmpeg");
+        command.args(["-i", "My Movie.mkv", "-c:v", "copy"]).env("LD_LIBRARY_PATH", "/opt/ffmpeg/lib");
+        assert_eq!(render_command(&command), "LD_LIBRARY_PATH=/opt/ffmpeg/lib ffmpeg -i 'My Movie.mkv' -c:v copy");
+    }
+}
@@ -0,0 +1,81 @@
+//! Subtitle files sitting next to the video they're for, like `Movie.srt` or `Movie.jpn.ass`
+//! next to `Movie.mkv`.  Fansubs and downloaded subtitles nearly always come this way.
+
+use std::path::{Path, PathBuf};
+
+use fixedstr::str4;
+
+use crate::ffmpeg_languages::CT2FF;
+use crate::options::parse_language;
+
+/// Text formats only.  ffmpeg can't turn bitmap ones into WebVTT, and burning them in only works
+/// for tracks in the input itself.
+pub const SIDECAR_EXTENSIONS: [&str; 4] = ["srt", "ass", "ssa", "vtt"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sidecar {
+    pub path: PathBuf,
+    /// Going by the filename, e.g. jpn for `Movie.jpn.ass` or eng for `Movie.en.srt`.  Subtitle
+    /// files hardly ever say what language they're in themselves.
+    pub language: Option<str4>,
+}
+
+/// Finds the subtitle files next to `input` whose names start with its name (minus the
+/// extension), sorted by filename.
+pub fn find_sidecars(input: &Path) -> std::io::Result<Vec<Sidecar>> {
+    let (Some(stem), Some(dir)) = (input.file_stem().and_then(|x| x.to_str()), input.parent()) else {
+        return Ok(vec![]);
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut out = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|x| x.to_str()) else {
+            continue;
+        };
+        let Some(rest) = name.strip_prefix(stem).and_then(|x| x.strip_prefix('.')) else {
+            continue;
+        };
+        // whatever's between the input's name and the extension, e.g. "jpn" or "en.forced"
+        let (tags, extension) = rest.rsplit_once('.').unwrap_or(("", rest));
+        if !SIDECAR_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()) {
+            continue;
+        }
+        let language = tags.split('.').find_map(tag_language).filter(|x| *x != "und");
+        out.push(Sidecar { path, language });
+    }
+    out.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(out)
+}
+
+/// Subtitle sites mostly go by ISO 639-1 (`en`), ffmpeg and Matroska by ISO 639-2 (`eng`), so
+/// take either.
+fn tag_language(tag: &str) -> Option<str4> {
+    match CT2FF.get(tag.to_ascii_lowercase().as_str()) {
+        Some(language) => Some((*language).into()),
+        None => parse_language(tag).ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_sidecars() {
+        let root = std::env::temp_dir().join(format!("cytrans-sidecars-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        for name in ["Movie.mkv", "Movie.srt", "Movie.jpn.forced.ASS", "Movie.en.vtt", "Movie.PT.sdh.srt", "Movie.xx.srt", "Movie.nfo", "Movie 2.srt", "Other.srt"] {
+            std::fs::write(root.join(name), b"").unwrap();
+        }
+        let found = find_sidecars(&root.join("Movie.mkv")).unwrap();
+        assert_eq!(found, vec![
+            Sidecar { path: root.join("Movie.PT.sdh.srt"), language: Some("por".into()) },
+            Sidecar { path: root.join("Movie.en.vtt"), language: Some("eng".into()) },
+            Sidecar { path: root.join("Movie.jpn.forced.ASS"), language: Some("jpn".into()) },
+            Sidecar { path: root.join("Movie.srt"), language: None },
+            Sidecar { path: root.join("Movie.xx.srt"), language: None },
+        ]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+//! Staged output: encode somewhere the world can't see, and only move the results into the
+//! (public) output directory once they're known to be good.
+
+use std::path::{Path, PathBuf};
+
+use crate::config::FfmpegConfig;
+use crate::metadata::{ManifestError, ManifestProblem, MetadataManifest, CYTUBE_MANIFEST_FILENAME, MANIFEST_FILENAME};
+
+#[derive(Debug)]
+pub enum PublishError {
+    Io(std::io::Error),
+    Manifest(ManifestError),
+    /// The files don't match the manifest, so something went wrong with the encode.
+    Invalid(Vec<ManifestProblem>),
+}
+
+impl std::fmt::Display for PublishError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(fmt, "error moving output into place: {}", e),
+            Self::Manifest(e) => e.fmt(fmt),
+            Self::Invalid(problems) => {
+                write!(fmt, "output failed validation: ")?;
+                for (i, problem) in problems.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, "; ")?;
+                    }
+                    write!(fmt, "{}", problem)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+impl From<std::io::Error> for PublishError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ManifestError> for PublishError {
+    fn from(e: ManifestError) -> Self {
+        Self::Manifest(e)
+    }
+}
+
+/// A hidden directory next to the real output directory that a job writes into.  Call
+/// `publish()` once the job has finished.  If it's dropped without being published (because the
+/// job failed, say) the directory and everything in it gets deleted.
+///
+/// It lives next to the destination rather than in /tmp so that publishing is a rename and not a
+/// copy, which is instant, and atomic too if the destination is new.
+pub struct StagedOutput {
+    destination: PathBuf,
+    staging: PathBuf,
+    published: bool,
+}
+
+impl StagedOutput {
+    pub fn new(destination: &Path) -> std::io::Result<Self> {
+        let Some(name) = destination.file_name() else {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "output directory has no name"));
+        };
+        let parent = match destination.parent() {
+            Some(x) if !x.as_os_str().is_empty() => x,
+            _ => Path::new("."),
+        };
+        // the leading dot keeps it out of directory listings, and server-ng won't serve it
+        let mut staging_name = std::ffi::OsString::from(".");
+        staging_name.push(name);
+        staging_name.push(format!(".staging-{}-{}", std::process::id(), std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos()));
+        let staging = parent.join(staging_name);
+        std::fs::create_dir(&staging)?;
+        Ok(Self { destination: destination.to_owned(), staging, published: false })
+    }
+
+    /// Where the job should write its output.
+    pub fn path(&self) -> &Path {
+        &self.staging
+    }
+
+    /// Writes the manifests, checks the output files against them, and moves everything into the
+    /// destination.  On failure the staged files are deleted.
+    ///
+    /// If the destination doesn't exist yet (or is empty), the whole staging directory gets
+    /// renamed over it in one go.  Otherwise the files are moved in one at a time, manifests
+    /// last, so the published manifest never refers to a file that isn't there yet.  That isn't
+    /// atomic: if a move fails part way, the files already moved are moved back and whatever they
+    /// replaced is put back, but a crash part way leaves a partial publish behind.
+    pub fn publish(mut self, config: &FfmpegConfig, manifest: &MetadataManifest, url_prefix: &str) -> Result<(), PublishError> {
+        manifest.save(&self.staging)?;
+        manifest.save_cytube(&self.staging, url_prefix)?;
+        let problems = manifest.validate(config, &self.staging);
+        if !problems.is_empty() {
+            return Err(PublishError::Invalid(problems));
+        }
+
+        let destination_is_empty = match std::fs::read_dir(&self.destination) {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e.into()),
+        };
+        if destination_is_empty {
+            // rename() won't replace a directory on every platform, even an empty one
+            match std::fs::remove_dir(&self.destination) {
+                Ok(()) => {},
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e.into()),
+            }
+            std::fs::rename(&self.staging, &self.destination)?;
+        } else {
+            let is_manifest = |name: &std::ffi::OsStr| name == MANIFEST_FILENAME || name == CYTUBE_MANIFEST_FILENAME;
+            let mut files = std::fs::read_dir(&self.staging)?
+                .map(|x| x.map(|x| x.file_name()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            files.sort_by_key(|x| is_manifest(x));
+            // whatever the new files replace (the manifests, at least) goes in here until we're
+            // done, in case it has to be put back
+            let replaced = self.staging.join(".replaced");
+            std::fs::create_dir(&replaced)?;
+            let mut moved = Vec::new();
+            for file in &files {
+                if let Err(e) = self.move_in(file, &replaced, &mut moved) {
+                    self.roll_back(&replaced, &moved);
+                    return Err(e.into());
+                }
+            }
+            std::fs::remove_dir_all(&self.staging)?;
+        }
+        self.published = true;
+        Ok(())
+    }
+
+    /// Moves one staged file into the destination, first moving whatever it's about to replace
+    /// into `replaced`.  Records what it did in `moved`, with whether anything was replaced, even
+    /// if the move itself then fails.
+    fn move_in<'a>(&self, file: &'a std::ffi::OsStr, replaced: &Path, moved: &mut Vec<(&'a std::ffi::OsStr, bool)>) -> std::io::Result<()> {
+        let target = self.destination.join(file);
+        let had_old = match std::fs::rename(&target, replaced.join(file)) {
+            Ok(()) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        moved.push((file, had_old));
+        std::fs::rename(self.staging.join(file), &target)
+    }
+
+    /// Undoes `move_in()`, as far as it can.  The files go back into staging, to be deleted along
+    /// with it.
+    fn roll_back(&self, replaced: &Path, moved: &[(&std::ffi::OsStr, bool)]) {
+        for (file, had_old) in moved.iter().rev() {
+            let target = self.destination.join(file);
+            let _ = std::fs::rename(&target, self.staging.join(file));
+            if *had_old {
+                let _ = std::fs::rename(replaced.join(file), &target);
+            }
+        }
+    }
+}
+
+impl Drop for StagedOutput {
+    fn drop(&mut self) {
+        if !self.published {
+            let _ = std::fs::remove_dir_all(&self.staging);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn empty_manifest() -> MetadataManifest {
+        MetadataManifest {
+            video_files: vec![],
+            audio_files: vec![],
+            text_files: vec![],
+            duration: 1.0,
+            title: "Example".into(),
+            muxed_audio: None,
+            thumbnail: None,
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let root = std::env::temp_dir().join(format!("cytrans-staging-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let config = FfmpegConfig::default();
+
+        // into a directory that doesn't exist yet
+        let destination = root.join("new");
+        let staged = StagedOutput::new(&destination).unwrap();
+        std::fs::write(staged.path().join("extra.txt"), b"hi").unwrap();
+        staged.publish(&config, &empty_manifest(), "https://example.com/").unwrap();
+        assert!(destination.join("extra.txt").is_file());
+        assert!(destination.join(MANIFEST_FILENAME).is_file());
+        assert!(destination.join(CYTUBE_MANIFEST_FILENAME).is_file());
+
+        // into one that already has stuff in it
+        let staged = StagedOutput::new(&destination).unwrap();
+        std::fs::write(staged.path().join("more.txt"), b"hi").unwrap();
+        staged.publish(&config, &empty_manifest(), "https://example.com/").unwrap();
+        assert!(destination.join("extra.txt").is_file());
+        assert!(destination.join("more.txt").is_file());
+
+        // a move failing part way puts back what was already moved, including anything it
+        // replaced.  it's hard to make rename() fail on cue, so this does the failing by hand
+        let staged = StagedOutput::new(&destination).unwrap();
+        std::fs::write(staged.path().join("extra.txt"), b"replacement").unwrap();
+        std::fs::write(staged.path().join("new.txt"), b"hi").unwrap();
+        let replaced = staged.path().join(".replaced");
+        std::fs::create_dir(&replaced).unwrap();
+        let mut moved = Vec::new();
+        let files = [std::ffi::OsString::from("extra.txt"), "new.txt".into()];
+        for file in &files {
+            staged.move_in(file, &replaced, &mut moved).unwrap();
+        }
+        assert_eq!(std::fs::read(destination.join("extra.txt")).unwrap(), b"replacement");
+        staged.roll_back(&replaced, &moved);
+        assert_eq!(std::fs::read(destination.join("extra.txt")).unwrap(), b"hi");
+        assert!(!destination.join("new.txt").exists());
+        drop(staged);
+
+        // failed validation cleans up after itself
+        let staged = StagedOutput::new(&destination).unwrap();
+        let staging_path = staged.path().to_owned();
+        let mut manifest = empty_manifest();
+        manifest.thumbnail = Some("thumbnail.jpg".into());
+        assert!(matches!(staged.publish(&config, &manifest, "https://example.com/"), Err(PublishError::Invalid(_))));
+        assert!(!staging_path.exists());
+
+        // and so does dropping it
+        let staged = StagedOutput::new(&destination).unwrap();
+        let staging_path = staged.path().to_owned();
+        std::fs::write(staging_path.join("garbage.txt"), b"hi").unwrap();
+        drop(staged);
+        assert!(!staging_path.exists());
+
+        // nothing but the published directory is left over
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,15 +1,18 @@
 use crate::ffprobe::{FFprobeResult, Track, TrackType::*};
 use crate::options::*;
-use crate::codecs::BITMAP_SUBTITLE_CODECS;
+use crate::codecs::{has_encoder, BITMAP_SUBTITLE_CODECS, Capabilities};
 use crate::metadata::*;
-use std::ffi::OsStr;
+use crate::config::FfmpegConfig;
+use crate::defaults::{is_commentary, DefaultsPolicy, DefaultsStrategy};
+use crate::filenames::{render, FilenameFields, FilenameTemplates, UniqueFilenames};
+use std::ffi::{OsStr, OsString};
 use std::path::Path;
 use std::process::Command;
 use fixedstr::str4;
 use std::collections::HashMap;
 use strum::IntoEnumIterator;
 
-#[derive(strum::EnumIter, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, strum::EnumIter, serde::Serialize, serde::Deserialize)]
 pub enum VideoContainer {
     MP4, WEBM, OGG
 }
@@ -78,6 +81,15 @@ impl VideoContainer {
             OGG  => "video/ogg",
         }
     }
+    /// The name of ffmpeg's demuxer for this container, as reported in ffprobe's format_name.
+    pub fn format_name(&self) -> &'static str {
+        use VideoContainer::*;
+        match self {
+            MP4  => "mp4",
+            WEBM => "webm",
+            OGG  => "ogg",
+        }
+    }
     pub fn from_extension(s: &str) -> Option<Self> {
         use VideoContainer::*;
         match s{
@@ -89,7 +101,7 @@ impl VideoContainer {
     }
 }
 
-#[derive(serde::Serialize,serde::Deserialize, Clone,Copy)]
+#[derive(Debug, serde::Serialize,serde::Deserialize, Clone,Copy, PartialEq)]
 pub enum AudioContainer {
     M4A, OGG,
     // Every source I can find on the internet says that M4A files are just renamed MP4 files that
@@ -104,6 +116,9 @@ pub enum AudioContainer {
     // there's a somewhat persuasive argument to be made if you haven't looked at compatibility
     // data.
     PseudoM4A,
+    // Bare AAC and MP3 streams, with no container to speak of.  Only used for audio-only items,
+    // because Cytube won't take audio/mp4 as a source, only as an audio track.
+    ADTS, MP3,
 }
 
 
@@ -113,6 +128,8 @@ impl AudioContainer {
         match self {
             OGG => "ogg",
             M4A | PseudoM4A => "m4a",
+            ADTS => "aac",
+            MP3 => "mp3",
         }
     }
     pub fn mimetype(&self) -> &'static str {
@@ -120,6 +137,30 @@ impl AudioContainer {
         match self {
             OGG => "audio/ogg",
             M4A | PseudoM4A => "audio/mp4",
+            ADTS => "audio/aac",
+            MP3 => "audio/mpeg",
+        }
+    }
+    pub fn format_name(&self) -> &'static str {
+        use AudioContainer::*;
+        match self {
+            OGG => "ogg",
+            M4A | PseudoM4A => "m4a",
+            ADTS => "aac",
+            MP3 => "mp3",
+        }
+    }
+    /// Like `find()`, but for audio that's going to be one of the item's sources instead of an
+    /// audio track.  Sources have their own list of content types, which doesn't include
+    /// audio/mp4.  Returns None for ALAC, which only goes in MP4s.
+    pub fn find_source(codec: AudioCodec) -> Option<AudioContainer> {
+        use AudioContainer::*;
+        use AudioCodec::*;
+        match codec {
+            AAC => Some(ADTS),
+            AudioCodec::MP3 => Some(AudioContainer::MP3),
+            Opus | Vorbis | FLAC => Some(OGG),
+            ALAC => None,
         }
     }
     pub fn find(codec: AudioCodec) -> AudioContainer {
@@ -151,41 +192,58 @@ impl AudioContainer {
             Opus | Vorbis | FLAC=> OGG,
             // cytube doesn't support MP3 for some reason
             // fortunately we can use the same trick we use with flac
-            MP3 => PseudoM4A,
+            AudioCodec::MP3 => PseudoM4A,
         }
     }
 }
 
+/// Picks the codec and encoder for a video track that can't be copied.  We'd like AV1 via
+/// libsvtav1, but not every ffmpeg is built with it.
+pub fn fallback_video_encoder(capabilities: &Capabilities) -> (VideoCodec, String) {
+    use VideoCodec::*;
+    const PREFERENCES: [(VideoCodec, &str); 5] = [
+        (AV1, "libsvtav1"),
+        (AV1, "libaom-av1"),
+        (VP9, "libvpx-vp9"),
+        (H264, "libx264"),
+        (VP8, "libvpx"),
+    ];
+    PREFERENCES.iter()
+        .find(|(codec, encoder)| has_encoder(&capabilities.video_encoders, codec, encoder))
+        .map(|(codec, encoder)| (*codec, encoder.to_string()))
+        // nothing we'd want to use.  ask for libsvtav1 anyway and let ffmpeg explain the problem.
+        .unwrap_or((AV1, "libsvtav1".to_string()))
+}
 
-
-pub fn get_defaults<'a>(ffprobe: &'a FFprobeResult, file: &Path) -> TranscodeArgs<'a> {
+/// Picks a sensible set of tracks and codecs for an input file: which tracks go by `policy`,
+/// which codecs by `strategy`.
+pub fn get_defaults<'a>(ffprobe: &'a FFprobeResult, file: &Path, capabilities: &Capabilities, policy: &DefaultsPolicy, strategy: &dyn DefaultsStrategy) -> TranscodeArgs<'a> {
     let mut subtitle_tracks: Vec<&Track> = Vec::new();
     let mut audio_tracks: Vec<&Track> = Vec::new();
     let mut video_tracks: Vec<&Track> = Vec::new();
 
-    let mut subtitle_reqs: Vec<&Track> = Vec::new();
+    let mut subtitle_reqs: Vec<SubtitleOptions> = Vec::new();
     let mut audio_reqs: Vec<TrackOptions<AudioCodec>> = Vec::new();
     let mut video_reqs: Vec<TrackOptions<VideoCodec>> = Vec::new();
 
     for track in &ffprobe.tracks {
         match track.kind {
+            // cover art gets treated as video by ffprobe but isn't any use as a video track
+            Video if track.attached_pic => {},
             Video => video_tracks.push(track),
             Audio => audio_tracks.push(track),
             Subtitle => subtitle_tracks.push(track),
         }
     }
+    // if the only audio is commentary, commentary is better than silence
+    if audio_tracks.iter().any(|x| policy.allows(x)) {
+        audio_tracks.retain(|x| policy.allows(x));
+    }
+    subtitle_tracks.retain(|x| policy.allows(x));
 
     let video_codec = video_tracks.first().map(|track| {
-        let codec: Option<VideoCodec> = track.codec.parse().ok();
-        let encoder = if codec.is_some() {"copy"} else {"libsvtav1"}.to_string();
-        let codec = codec.unwrap_or(VideoCodec::AV1);
-        video_reqs.push(TrackOptions {
-            track,
-            codec,
-            encoder,
-            bitrate: None,
-            extra_ffmpeg_args: vec![],
-        });
+        let (codec, encoder) = strategy.video_encoder(track, capabilities);
+        video_reqs.push(TrackOptions::new(track, codec, encoder));
         codec
     });
     
@@ -196,48 +254,49 @@ pub fn get_defaults<'a>(ffprobe: &'a FFprobeResult, file: &Path) -> TranscodeArg
             .or_default()
             .push(*track);
     }
-    let chosen_tracks = if audio_tracks_by_language.len() == 1 {
-        let tracks = audio_tracks_by_language.values().next().unwrap();
-        let track = tracks.iter().max_by_key(|track| {
-            let mut score = 0;
-            if let Ok(ac) = track.codec.parse::<AudioCodec>() {
-                score += 100;
-                if let Some(vc) = video_codec {
-                    if VideoContainer::find_av(vc, ac).is_some() {
-                        score += 100;
-                    }
+    let score = |track: &&&Track| {
+        let mut score = 0;
+        if let Ok(ac) = track.codec.parse::<AudioCodec>() {
+            score += 100;
+            if let Some(vc) = video_codec {
+                if VideoContainer::find_av(vc, ac).is_some() {
+                    score += 100;
                 }
             }
-            score + track.channels.unwrap_or(0)
-        }).unwrap();
+        }
+        // commentary only gets picked when it's all there is, even if it's kept
+        (!is_commentary(track), score + track.channels.unwrap_or(0))
+    };
+    let mut preferred = audio_tracks_by_language.values()
+        .filter_map(|tracks| Some((policy.audio_rank(tracks[0])?, *tracks.iter().max_by_key(score)?)))
+        .collect::<Vec<_>>();
+    preferred.sort_by_key(|(rank, _)| *rank);
+    let mut chosen_tracks = if !strategy.one_audio_track_per_language() {
+        let mut tracks = audio_tracks.clone();
+        if tracks.iter().any(|x| policy.audio_rank(x).is_some()) {
+            tracks.retain(|x| policy.audio_rank(x).is_some());
+            tracks.sort_by_key(|x| policy.audio_rank(x));
+        }
+        tracks
+    } else if !preferred.is_empty() {
+        preferred.into_iter().map(|(_, track)| track).collect()
+    } else if audio_tracks_by_language.len() == 1 {
+        let tracks = audio_tracks_by_language.values().next().unwrap();
+        let track = tracks.iter().max_by_key(score).unwrap();
         vec![*track] // get rid of the double reference
     } else {
-        // this line is about as readable as a python list comprehension so let me explain.
-        // we've already grouped the audio tracks by language. we select one track per
-        // language to go into the final chosen list, and we decide which one based first on
-        // whether or not it will fit into a web format without transocding, then by the number of
-        // channels it has.
-        audio_tracks_by_language.values().map(|x| *x.iter().max_by_key(|track| if track.codec.parse::<AudioCodec>().is_ok() {100} else {0} + track.channels.unwrap_or(0)).unwrap()).collect()
+        // we've already grouped the audio tracks by language, so take the best one from each
+        let mut tracks: Vec<&Track> = audio_tracks_by_language.values().map(|x| *x.iter().max_by_key(score).unwrap()).collect();
+        // HashMap order is random, and max_audio_tracks shouldn't be
+        tracks.sort_by_key(|x| x.index);
+        tracks
     };
+    if let Some(max) = policy.max_audio_tracks {
+        chosen_tracks.truncate(max);
+    }
     for track in chosen_tracks {
-        let (codec, encoder) = if let Ok(x) = track.codec.parse() {
-            (x, "copy".to_string())
-        } else {
-            if let Some(vc) = video_codec {
-                if matches!(VideoContainer::find(vc), VideoContainer::MP4) {
-                    (AudioCodec::AAC, "aac".to_string())
-                } else {
-                    (AudioCodec::Opus, "libopus".to_string())
-                }
-            } else {
-                (AudioCodec::AAC, "aac".to_string())
-            }
-        };
-        audio_reqs.push(TrackOptions {
-            track, codec, encoder,
-            bitrate: None,
-            extra_ffmpeg_args: vec![],
-        });
+        let (codec, encoder) = strategy.audio_encoder(track, video_codec, capabilities);
+        audio_reqs.push(TrackOptions::new(track, codec, encoder));
     }
 
     // put all subtitle tracks by default, except the bitmap ones
@@ -257,7 +316,26 @@ pub fn get_defaults<'a>(ffprobe: &'a FFprobeResult, file: &Path) -> TranscodeArg
             // client that allows selecting subtitle tracks that any track we pass to cytube must
             // not have a codec in this list.  (I won't bother to perform this check in the
             // server-side code, since ffmpeg will do it for me)
-            subtitle_reqs.push(track);
+            subtitle_reqs.push(SubtitleOptions::new(track));
+        }
+    }
+    if !policy.subtitle_languages.is_empty() {
+        subtitle_reqs.retain(|x| policy.subtitle_rank(x.track).is_some());
+        // stable, so tracks in the same language stay in file order
+        subtitle_reqs.sort_by_key(|x| policy.subtitle_rank(x.track));
+    }
+    if let Some(max) = policy.max_subtitle_tracks {
+        subtitle_reqs.truncate(max);
+    }
+    if let Some(idx) = default_subtitle_track(subtitle_reqs.iter().map(|x| x.track), audio_reqs.first().and_then(|x| x.track.language)) {
+        subtitle_reqs.iter_mut().filter(|x| x.track.index == idx).for_each(|x| x.default = true);
+    } else if !policy.subtitle_languages.is_empty() {
+        // the file doesn't say, but if the audio isn't in the language the subtitles are for,
+        // whoever wrote the policy presumably wants to read along
+        if let (Some(audio), Some(sub)) = (audio_reqs.first(), subtitle_reqs.first_mut()) {
+            if audio.track.language.is_some() && audio.track.language != sub.track.language {
+                sub.default = true;
+            }
         }
     }
 
@@ -268,26 +346,103 @@ pub fn get_defaults<'a>(ffprobe: &'a FFprobeResult, file: &Path) -> TranscodeArg
         duration: ffprobe.duration,
         title: ffprobe.title.to_owned().unwrap_or_else(|| file.file_stem().unwrap_or(file.as_os_str()).to_string_lossy().to_string()),
         extra_ffmpeg_args: Vec::new(),
+        input_ffmpeg_args: Vec::new(),
+        output_ffmpeg_args: Vec::new(),
         force_demux_audio: false,
         add_muxed_silence: false,
+        filenames: FilenameTemplates::default(),
+        trim: Trim::default(),
+        visual: None,
+        burn_subtitles: None,
     }
 }
 
+/// Picks which of the given subtitle tracks should be on by default, going by what the input file
+/// says.  Forced subtitles win over ones that are merely marked default, since they're there for
+/// a reason.  If several are marked, one in the same language as the audio wins: forced
+/// subtitles in particular only make sense for the dub they were made for.
+pub fn default_subtitle_track<'a>(tracks: impl Iterator<Item=&'a Track> + Clone, audio_language: Option<str4>) -> Option<u16> {
+    let find = |marked: fn(&Track) -> bool| {
+        tracks.clone().find(|x| marked(x) && audio_language.is_some() && x.language == audio_language)
+            .or_else(|| tracks.clone().find(|x| marked(x)))
+    };
+    find(|x| x.forced)
+        .or_else(|| find(|x| x.default))
+        .map(|x| x.index)
+}
 
+pub fn build_ffmpeg_command(config: &FfmpegConfig,
+                            media_file: &OsStr,
+                            transcode_args: TranscodeArgs,
+                            outputdir: &Path) -> (Command, MetadataManifest, bool) {
+    build_command(config, &[], media_file, transcode_args, outputdir)
+}
 
-pub fn build_ffmpeg_command(media_file: &OsStr,
+/// Like `build_ffmpeg_command()`, but the input is a concat demuxer script from
+/// `concat::write_concat_list()` that joins several files into one.  `transcode_args` should be
+/// based on what `concat::concat_probe()` said about them.
+pub fn build_concat_command(config: &FfmpegConfig,
+                            list_file: &Path,
                             transcode_args: TranscodeArgs,
                             outputdir: &Path) -> (Command, MetadataManifest, bool) {
-    let mut command = Command::new("ffmpeg");
+    // -safe 0 because write_concat_list() uses absolute paths, which the demuxer considers unsafe
+    build_command(config, &["-f", "concat", "-safe", "0"], list_file.as_os_str(), transcode_args, outputdir)
+}
+
+fn build_command(config: &FfmpegConfig,
+                 input_options: &[&str],
+                 media_file: &OsStr,
+                 transcode_args: TranscodeArgs,
+                 outputdir: &Path) -> (Command, MetadataManifest, bool) {
+    let mut command = config.ffmpeg_command();
     command.arg("-hide_banner");
     command.args(transcode_args.extra_ffmpeg_args);
+
+    // Trimming.  When everything's being re-encoded, -ss and -t go before the -i and seek the
+    // input, which is fast and (since ffmpeg 2.1) exact: ffmpeg decodes from the keyframe before
+    // the start and throws away the frames it doesn't need.  Copied video can only be cut on a
+    // keyframe, so then they go on each output instead, and ffmpeg drops everything up to the
+    // first keyframe after the start.  Either way ffmpeg shifts every output's timestamps
+    // (subtitles included) to start from zero.
+    let trim = transcode_args.trim;
+    let duration = trim.duration(transcode_args.duration);
+    let mut trim_args = Vec::new();
+    if trim.start() > 0.0 {
+        trim_args.extend(["-ss".to_string(), trim.start().to_string()]);
+    }
+    if let Some(x) = trim.ffmpeg_duration() {
+        trim_args.extend(["-t".to_string(), x.to_string()]);
+    }
+    let output_seek = transcode_args.video_tracks.iter().any(|x| x.encoder == "copy");
+    let (input_trim_args, mut output_args): (Vec<String>, Vec<std::ffi::OsString>) = if output_seek {
+        (Vec::new(), trim_args.into_iter().map(Into::into).collect())
+    } else {
+        (trim_args, Vec::new())
+    };
+    output_args.extend(transcode_args.output_ffmpeg_args);
+    command.args(&input_trim_args);
+    command.args(input_options);
+    command.args(transcode_args.input_ffmpeg_args);
     command.arg("-i").arg(media_file);
+    // how many -i's there have been, so the next one is input number `inputs`
+    let mut inputs = 1;
 
     let mut video_out = Vec::new();
     let mut audio_out = Vec::new();
     let mut text_out = Vec::new();
+    let mut filenames = UniqueFilenames::new();
+    let templates = &transcode_args.filenames;
+    let title = transcode_args.title.as_str();
+
+    // audio-only inputs get a video made for them if they asked for one.  otherwise the audio
+    // files become the item's sources.
+    let visual = transcode_args.visual.filter(|_| transcode_args.video_tracks.is_empty());
+    let audio_only = transcode_args.video_tracks.is_empty() && visual.is_none();
+    let video_codecs = transcode_args.video_tracks.iter().map(|x| x.codec)
+        .chain(visual.as_ref().map(|x| x.codec))
+        .collect::<Vec<_>>();
 
-    let mut will_demux_audio = transcode_args.force_demux_audio;
+    let mut will_demux_audio = transcode_args.force_demux_audio || audio_only;
 
     if !will_demux_audio {
         // if there is more than one audio track, we must demux.
@@ -304,8 +459,8 @@ pub fn build_ffmpeg_command(media_file: &OsStr,
             // if we're going to use ANY video codecs that can't fit in the same container as our
             // chosen audio codec, demux.
             let audio_codec = transcode_args.audio_tracks[0].codec;
-            for track in transcode_args.video_tracks.iter() {
-                if VideoContainer::find_av(track.codec, audio_codec).is_none() {
+            for codec in video_codecs.iter() {
+                if VideoContainer::find_av(*codec, audio_codec).is_none() {
                     will_demux_audio = true;
                     break;
                 }
@@ -323,13 +478,75 @@ pub fn build_ffmpeg_command(media_file: &OsStr,
         // updated in years so I doubt if it's really still necessary.  I implemented it anyway and
         // made it optional, just to be safe.
         // TODO copy the sample rate and channel layout from the source file!
-        command.args(["-f", "lavfi", "-t", transcode_args.duration.to_string().as_str(), "-i", "anullsrc=channel_layout=stereo:sample_rate=48000",
+        // an output seek skips the start of this too, so then it needs to be that much longer
+        let silence = if output_seek { trim.start() + duration } else { duration };
+        command.args(["-f", "lavfi", "-t", silence.to_string().as_str(), "-i", "anullsrc=channel_layout=stereo:sample_rate=48000",
         ]);
-        (None, Some("1:0".to_string()))
+        inputs += 1;
+        (None, Some(format!("{}:0", inputs - 1)))
     } else {(None, None)};
-    
-    for video in transcode_args.video_tracks {
-        command.args(["-map", format!("0:{}", video.track.index).as_str()]); 
+
+    // burning subtitles in is an overlay per video track.  copied video can't have anything drawn
+    // on it, so those get left alone.
+    let mut burn_filters = Vec::new();
+    let mut video_outputs = transcode_args.video_tracks.into_iter().map(|video| VideoOutput {
+        map: match transcode_args.burn_subtitles.filter(|_| video.encoder != "copy") {
+            Some(sub) => {
+                let label = format!("[burn{}]", burn_filters.len());
+                burn_filters.push(format!("[0:{}][0:{}]overlay=eof_action=pass{}", video.track.index, sub, label));
+                label
+            },
+            None => format!("0:{}", video.track.index),
+        },
+        index: video.track.index,
+        language: video.overrides.language(video.track),
+        metadata_args: metadata_args("v:0", &video.overrides),
+        codec: video.codec,
+        encoder: video.encoder,
+        extra_ffmpeg_args: video.extra_ffmpeg_args,
+        resolution: (video.track.resolution_h.unwrap_or(0), video.track.resolution_v.unwrap_or(0)),
+    }).collect::<Vec<_>>();
+    if !burn_filters.is_empty() {
+        command.args(["-filter_complex", &burn_filters.join(";")]);
+    }
+    if let Some(visual) = visual {
+        let audio_idx = transcode_args.audio_tracks.first().map(|x| x.track.index);
+        let (filter, image) = visual_filter(&visual, inputs, audio_idx);
+        if let Some(image) = image {
+            command.args(["-loop", "1", "-framerate", &visual_frame_rate(&visual).to_string(), "-i"]).arg(image);
+            inputs += 1;
+        }
+        command.args(["-filter_complex", &filter]);
+        video_outputs.push(VideoOutput {
+            map: "[visual]".into(),
+            index: match visual.background {
+                VisualBackground::CoverArt(idx) => idx,
+                _ => audio_idx.unwrap_or(0),
+            },
+            language: None,
+            metadata_args: vec![],
+            codec: visual.codec,
+            encoder: visual.encoder,
+            // the background goes on forever, so something has to stop it
+            extra_ffmpeg_args: vec!["-t".into(), duration.to_string().into()],
+            resolution: VISUAL_RESOLUTION,
+        });
+    }
+
+    // sidecar subtitle files are inputs too, and they have to come before the first output.  an
+    // input seek only applies to the -i right after it, so it has to be repeated for each one.
+    let subtitle_maps = transcode_args.subtitle_tracks.iter().map(|sub| match &sub.file {
+        Some(file) => {
+            command.args(&input_trim_args);
+            command.arg("-i").arg(file);
+            inputs += 1;
+            format!("{}:{}", inputs - 1, sub.track.index)
+        },
+        None => format!("0:{}", sub.track.index),
+    }).collect::<Vec<_>>();
+
+    for video in video_outputs {
+        command.args(["-map", video.map.as_str()]);
         if let Some(ref idx) = muxed_audio_idx {
             command.args(["-map", idx.as_str()]);
         }
@@ -350,11 +567,15 @@ pub fn build_ffmpeg_command(media_file: &OsStr,
             VideoContainer::find(video.codec)
         };
 
-        let filename = if video.encoder == "copy" {
-            format!("main.{}", video_container.extension())
-        } else {
-            format!("video{}_{}.{}", video.track.index, video.codec.as_ref(), video_container.extension())
-        };
+        let (resolution_h, resolution_v) = video.resolution;
+
+        let filename = filenames.claim(&render(templates.video(), &FilenameFields {
+            title,
+            index: video.index,
+            quality: Some(quality_for_resolution(resolution_h, resolution_v)),
+            codec: video.codec.as_ref(),
+            language: video.language.as_ref().map(|x| x.as_str()),
+        }), video_container.extension());
 
         let encoder: &str = if video.encoder != "" {
             video.encoder.as_str()
@@ -362,7 +583,9 @@ pub fn build_ffmpeg_command(media_file: &OsStr,
             video.codec.as_ref()
         };
         command.args(["-c:v", encoder]); 
+        command.args(&video.metadata_args);
         if let Some(audio) = muxed_audio_track {
+            command.args(metadata_args("a:0", &audio.overrides));
             let encoder: &str = if audio.encoder != "" {
                 audio.encoder.as_str()
             } else {
@@ -372,18 +595,18 @@ pub fn build_ffmpeg_command(media_file: &OsStr,
             command.args(&audio.extra_ffmpeg_args);
         }
 
+        command.args(&output_args);
         command.args(video.extra_ffmpeg_args);
         command.arg(outputdir.join(&filename));
 
-        let resolution_h = video.track.resolution_h.unwrap_or(0);
-        let resolution_v = video.track.resolution_v.unwrap_or(0);
-
         video_out.push(VideoMetadata {
             filename,
             audio_is_silent: transcode_args.add_muxed_silence,
             container: video_container,
             video_codec: video.codec,
             audio_codec: muxed_audio_track.map(|x|x.codec),
+            // we only know the bitrate up front if we're copying the track
+            audio_bitrate: muxed_audio_track.filter(|x| x.encoder == "copy").and_then(|x| x.track.bitrate),
             resolution_h,
             resolution_v,
         });
@@ -392,7 +615,13 @@ pub fn build_ffmpeg_command(media_file: &OsStr,
     let muxed_audio;
     if will_demux_audio {
         for audio in transcode_args.audio_tracks {
-            let container = AudioContainer::find(audio.codec);
+            let container = if audio_only {
+                // ALAC can't be a source.  get_defaults() never asks for it, and if someone
+                // else does, CytubeVideo::validate() will point out the problem.
+                AudioContainer::find_source(audio.codec).unwrap_or_else(|| AudioContainer::find(audio.codec))
+            } else {
+                AudioContainer::find(audio.codec)
+            };
             let encoder: &str = if audio.encoder != "" {
                 audio.encoder.as_str()
             } else {
@@ -405,109 +634,709 @@ pub fn build_ffmpeg_command(media_file: &OsStr,
                          encoder,
             ]);
             command.args(audio.extra_ffmpeg_args);
-            let language = audio.track.language.unwrap_or("unk".into());
-            let filename = format!("audio_{}_{}.{}", audio.track.index, audio.track.language.as_ref().map(|x| x.as_str()).unwrap_or("unknown"), container.extension());
+            command.args(metadata_args("a:0", &audio.overrides));
+            let track_language = audio.overrides.language(audio.track);
+            let language = track_language.unwrap_or("unk".into());
+            let filename = filenames.claim(&render(templates.audio(), &FilenameFields {
+                title,
+                index: audio.track.index,
+                quality: None,
+                codec: audio.codec.as_ref(),
+                language: track_language.as_ref().map(|x| x.as_str()),
+            }), container.extension());
             if matches!(&container, AudioContainer::PseudoM4A) {
                 command.args(["-f", "mp4"]);
             }
+            command.args(&output_args);
             command.arg(outputdir.join(&filename));
             audio_out.push(AudioMetadata {
                 codec: audio.codec,
                 filename,
                 container,
                 language,
-                title: audio.track.title.to_owned(),
+                title: audio.overrides.title(audio.track).map(ToOwned::to_owned),
+                source_quality: None,
             });
         }
+        if audio_only {
+            make_audio_sources(&mut audio_out);
+        }
         muxed_audio = None;
     } else {
         let audio = transcode_args.audio_tracks.iter().next().unwrap();
-        muxed_audio = Some(MuxedAudioMetadata {language: audio.track.language.to_owned().unwrap_or("unk".into()), title: audio.track.title.to_owned()});
+        muxed_audio = Some(MuxedAudioMetadata {
+            language: audio.overrides.language(audio.track).unwrap_or("unk".into()),
+            title: audio.overrides.title(audio.track).map(ToOwned::to_owned),
+        });
     }
 
-    for sub_track in transcode_args.subtitle_tracks {
-        command.args(["-map", format!("0:{}", sub_track.index).as_str()]);
-        let lang = match &sub_track.language {
-            Some(x) => x.as_str(),
-            None => "unknown",
-        };
-        let filename = format!("sub_{}_{}.vtt", sub_track.index, lang);
+    for (sub, map) in transcode_args.subtitle_tracks.into_iter().zip(subtitle_maps) {
+        let sub_track = sub.track;
+        command.args(["-map", map.as_str()]);
+        // the webvtt muxer throws these away, but they're there for anything that reads the
+        // command back, and for if we ever support another subtitle format
+        command.args(metadata_args("s:0", &sub.overrides));
+        let language = sub.overrides.language(sub_track);
+        let filename = filenames.claim(&render(templates.subtitle(), &FilenameFields {
+            title,
+            index: sub_track.index,
+            quality: None,
+            codec: "webvtt",
+            language: language.as_ref().map(|x| x.as_str()),
+        }), "vtt");
+        command.args(&output_args);
         command.arg(outputdir.join(&filename).as_os_str());
 
         text_out.push(TextMetadata {
             filename,
-            language: sub_track.language,
-            title: sub_track.title.to_owned(),
+            language,
+            title: sub.overrides.title(sub_track).map(ToOwned::to_owned),
+            default: sub.default,
         });
     }
 
-    dbg!(&command);
     (command, MetadataManifest {
         title: transcode_args.title,
-        duration: transcode_args.duration,
+        duration,
         video_files: video_out,
         audio_files: audio_out,
         text_files: text_out,
         muxed_audio, 
+        thumbnail: None,
     }, will_demux_audio)
 }
 
-pub fn build_demux_commands(mut meta: MetadataManifest, root_path: &Path) -> (Vec<Command>, MetadataManifest) {
-    /*
-     * This function makes the following assumptions and may misbehave if they are not upheld:
-     *  1. The metadata in `meta` is accurate and ffprobe does not need to be run.
-     *  2. `meta` describes at least one video file.
-     *  3. Each video file has exactly one video track and at most one audio track.
-     *  4. Only one audio track will need to be demuxed.
-     */
-    let mut commands = Vec::new();
+/// One video file `build_command()` is going to write, either from a video track in the input or
+/// made up from an `AudioVisual`.
+struct VideoOutput {
+    /// What to pass to -map
+    map: String,
+    index: u16,
+    language: Option<str4>,
+    /// From `metadata_args()`
+    metadata_args: Vec<String>,
+    codec: VideoCodec,
+    encoder: String,
+    extra_ffmpeg_args: Vec<std::ffi::OsString>,
+    resolution: (u16, u16),
+}
 
-    if let Some(muxed_audio_meta) = meta.muxed_audio.take() {
-        // TODO account for bitrate when choosing an audio track to demux.
-        let Some(best) = meta.video_files.iter().filter(|x| x.audio_codec.is_some() && !x.audio_is_silent)
-            .max_by_key(|x| (x.audio_codec == Some(AudioCodec::FLAC), )) else {
-                // no videos have an audio track -- nothing to do
-                return (vec![], meta)
-            };
+/// The -metadata arguments that apply `overrides` to `stream` (e.g. "a:0") of the output file
+/// they come before.  ffmpeg copies the input's tags across by itself, so if nothing was
+/// overridden there's nothing to do.
+fn metadata_args(stream: &str, overrides: &MetadataOverrides) -> Vec<String> {
+    let flag = format!("-metadata:s:{}", stream);
+    let mut args = Vec::new();
+    if let Some(language) = overrides.language {
+        args.extend([flag.clone(), format!("language={}", language.as_str())]);
+    }
+    if let Some(title) = &overrides.title {
+        args.extend([flag, format!("title={}", title)]);
+    }
+    args
+}
 
-        let codec = best.audio_codec.unwrap();
-        let container = AudioContainer::find(codec);
+/// Size of the video made for audio-only inputs.  Nobody needs cover art in 4K.
+const VISUAL_RESOLUTION: (u16, u16) = (1280, 720);
+
+fn visual_frame_rate(visual: &AudioVisual) -> u32 {
+    // a still image only needs a frame now and then, but a waveform should move smoothly
+    if visual.waveform {25} else {1}
+}
+
+/// Builds the -filter_complex graph for an `AudioVisual`, with its output labelled `[visual]`.
+/// `image_input` is the input number an image file would be, and `audio_track` is the track to
+/// draw the waveform of.  Also returns the image file to add as an input, if there is one.
+fn visual_filter(visual: &AudioVisual, image_input: usize, audio_track: Option<u16>) -> (String, Option<&Path>) {
+    let (w, h) = VISUAL_RESOLUTION;
+    let fps = visual_frame_rate(visual);
+    let fit = format!("scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,setsar=1,format=yuv420p");
+    let (background, image) = match &visual.background {
+        // cover art is a single frame, so keep repeating it
+        VisualBackground::CoverArt(idx) => (format!("[0:{idx}]loop=loop=-1:size=1,setpts=N/{fps}/TB,{fit}"), None),
+        VisualBackground::Image(path) => (format!("[{image_input}:v]{fit}"), Some(path.as_path())),
+        VisualBackground::Black => (format!("color=c=black:s={w}x{h}:r={fps},format=yuv420p"), None),
+    };
+    let filter = match audio_track.filter(|_| visual.waveform) {
+        Some(idx) => format!("{background}[bg];[0:{idx}]showwaves=s={w}x{}:mode=cline:rate={fps}:colors=white[wave];[bg][wave]overlay=0:H-h:shortest=1[visual]", h / 3),
+        None => format!("{background}[visual]"),
+    };
+    (filter, image)
+}
 
-        let audio_track = AudioMetadata {
-            codec,
-            container,
-            filename: format!("demuxed.{}", container.extension()),
-            language: muxed_audio_meta.language,
-            title: muxed_audio_meta.title,
+/// The thumbnail's filename within the output directory.
+pub const THUMBNAIL_FILENAME: &str = "thumbnail.jpg";
+
+/// Picks where to grab the thumbnail from.  The very start of a video is usually a black frame or
+/// a studio logo, so go a tenth of the way in, but not so far in that we risk spoilers in long
+/// videos.
+fn thumbnail_timestamp(duration: f32) -> f32 {
+    (duration / 10.0).min(120.0)
+}
+
+/// Builds an ffmpeg command that grabs a single frame of `video_track` and saves it as a JPEG
+/// thumbnail in `outputdir`.  The frame comes from the part of the input `trim` keeps, and
+/// `duration` is the input's duration.  `input_args` go just before the `-i`, like the job's
+/// `TranscodeArgs::input_ffmpeg_args`.  Returns the command along with the thumbnail's filename.
+pub fn build_thumbnail_command(config: &FfmpegConfig, media_file: &OsStr, input_args: &[OsString], video_track: u16, duration: f32, trim: &Trim, outputdir: &Path) -> (Command, String) {
+    let mut command = config.ffmpeg_command();
+    command.arg("-hide_banner");
+    // -ss before -i seeks the input to the nearest keyframe rather than decoding its way there,
+    // which is much faster and we don't care which frame we get exactly
+    let timestamp = trim.start() + thumbnail_timestamp(trim.duration(duration));
+    command.args(["-ss", timestamp.to_string().as_str()]);
+    command.args(input_args);
+    command.arg("-i").arg(media_file);
+    command.args([
+        "-map", format!("0:{}", video_track).as_str(),
+        "-frames:v", "1",
+        // cytube shows these pretty small, no sense in keeping a 4K still around
+        "-vf", "scale=-2:'min(720,ih)'",
+        "-q:v", "3",
+        "-y",
+    ]);
+    command.arg(outputdir.join(THUMBNAIL_FILENAME));
+    (command, THUMBNAIL_FILENAME.to_owned())
+}
+
+/// Runs the command from `build_thumbnail_command` and waits for it to finish.  Returns the
+/// thumbnail's filename, for `MetadataManifest::thumbnail`.
+pub fn generate_thumbnail(config: &FfmpegConfig, media_file: &OsStr, input_args: &[OsString], video_track: u16, duration: f32, trim: &Trim, outputdir: &Path) -> std::io::Result<String> {
+    let (mut command, filename) = build_thumbnail_command(config, media_file, input_args, video_track, duration, trim, outputdir);
+    let output = command.stdin(std::process::Stdio::null()).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!("ffmpeg failed to generate a thumbnail: {}", String::from_utf8_lossy(&output.stderr).trim_end())));
+    }
+    Ok(filename)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DemuxError {
+    NoVideoFiles,
+    /// A video has audio muxed into it but the manifest has nothing to say about what's in it.
+    MissingMuxedAudioMetadata,
+    /// Either the demuxed audio or a demuxed video would overwrite a file that's already there.
+    FilenameCollision(String),
+    /// The filename has no extension for us to swap out.
+    BadFilename(String),
+}
+
+impl std::fmt::Display for DemuxError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoVideoFiles => write!(fmt, "there are no video files to demux"),
+            Self::MissingMuxedAudioMetadata => write!(fmt, "the manifest doesn't describe the muxed audio"),
+            Self::FilenameCollision(filename) => write!(fmt, "demuxing would overwrite {}", filename),
+            Self::BadFilename(filename) => write!(fmt, "{} has no file extension", filename),
+        }
+    }
+}
+
+impl std::error::Error for DemuxError {}
+
+/// Builds the ffmpeg commands to pull the muxed audio out of every video in `meta` into a separate
+/// audio file, so that more audio tracks can be added alongside it.  Returns the commands along
+/// with the manifest as it'll look once they've all run.  `meta` itself isn't touched.
+///
+/// Each video gets its audio stripped, and the best of their audio tracks (by bitrate, then by
+/// codec, see `AudioCodec::quality_key()`) becomes the demuxed track.  The videos don't need to agree on the audio codec.
+///
+/// This trusts that `meta` is accurate and doesn't run ffprobe to check, and that each video file
+/// has exactly one video track and at most one audio track, which is what
+/// `build_ffmpeg_command()` produces.
+pub fn build_demux_commands(config: &FfmpegConfig, meta: &MetadataManifest, root_path: &Path) -> Result<(Vec<Command>, MetadataManifest), DemuxError> {
+    let mut meta = meta.clone();
+    if meta.video_files.is_empty() {
+        return Err(DemuxError::NoVideoFiles);
+    }
+    // silent tracks are there on purpose, see build_ffmpeg_command()
+    let has_audio = |x: &VideoMetadata| x.audio_codec.is_some() && !x.audio_is_silent;
+    let Some(best) = meta.video_files.iter().enumerate()
+        .filter(|(_, x)| has_audio(x))
+        .max_by_key(|(_, x)| x.audio_codec.map(|codec| codec.quality_key(x.audio_bitrate)))
+        .map(|(i, _)| i) else {
+            // no videos have an audio track -- nothing to do
+            return Ok((vec![], meta));
         };
+    let Some(muxed_audio_meta) = meta.muxed_audio.take() else {
+        return Err(DemuxError::MissingMuxedAudioMetadata);
+    };
 
-        // rust doesn't like it when we borrow the same value as mutable and immutable at the same
-        // time.  we coerce the (checked) borrow to an (unchecked) pointer to force it to let us do
-        // that.  do NOT try this at home.
-        let best: *const VideoMetadata = best;
-
-        for vid in meta.video_files.iter_mut() {
-            if vid.audio_codec.is_some() && !vid.audio_is_silent {
-                let mut command = Command::new("ffmpeg");
-                let container = VideoContainer::find(vid.video_codec);
-                let (name, _ext) = vid.filename.rsplit_once('.').unwrap();
-                let new_name = format!("{}_demuxed.{}", name, container.extension());
-                command.arg("-i");
-                command.arg(root_path.join(&vid.filename));
-                command.args(["-an","-c:v","copy"]);
-                command.arg(root_path.join(&new_name));
-                vid.filename=new_name;
-
-                if std::ptr::eq(vid, best) {
-                    command.args(["-vn", "-c:a", "copy"]);
-                    command.arg(root_path.join(&audio_track.filename));
-                }
-                commands.push(command);
+    let mut taken = meta.video_files.iter().map(|x| x.filename.clone())
+        .chain(meta.audio_files.iter().map(|x| x.filename.clone()))
+        .chain(meta.text_files.iter().map(|x| x.filename.clone()))
+        .collect::<Vec<_>>();
+    let mut claim = |filename: String| {
+        if taken.contains(&filename) {
+            Err(DemuxError::FilenameCollision(filename))
+        } else {
+            taken.push(filename.clone());
+            Ok(filename)
+        }
+    };
+
+    let codec = meta.video_files[best].audio_codec.expect("we only picked videos with audio");
+    let container = AudioContainer::find(codec);
+    let audio_track = AudioMetadata {
+        codec,
+        container,
+        filename: claim(format!("demuxed.{}", container.extension()))?,
+        language: muxed_audio_meta.language,
+        title: muxed_audio_meta.title,
+        source_quality: None,
+    };
+
+    let mut commands = Vec::new();
+    for (i, vid) in meta.video_files.iter_mut().enumerate() {
+        if !has_audio(vid) {
+            continue;
+        }
+        let mut command = config.ffmpeg_command();
+        // without the audio there's nothing keeping the video in the container it was in
+        let container = VideoContainer::find(vid.video_codec);
+        let Some((name, _ext)) = vid.filename.rsplit_once('.') else {
+            return Err(DemuxError::BadFilename(vid.filename.clone()));
+        };
+        let new_name = claim(format!("{}_demuxed.{}", name, container.extension()))?;
+        command.arg("-i");
+        command.arg(root_path.join(&vid.filename));
+        command.args(["-an","-c:v","copy"]);
+        command.arg(root_path.join(&new_name));
+        if i == best {
+            command.args(["-vn", "-c:a", "copy"]);
+            if matches!(audio_track.container, AudioContainer::PseudoM4A) {
+                command.args(["-f", "mp4"]);
             }
+            command.arg(root_path.join(&audio_track.filename));
+        }
+        commands.push(command);
+
+        vid.filename = new_name;
+        vid.container = container;
+        vid.audio_codec = None;
+        vid.audio_bitrate = None;
+    }
+
+    meta.audio_files.push(audio_track);
+    Ok((commands, meta))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args_of(command: &Command) -> Vec<String> {
+        command.get_args().map(|x| x.to_string_lossy().into_owned()).collect()
+    }
+
+    fn video(filename: &str, video_codec: VideoCodec, audio_codec: Option<AudioCodec>, audio_bitrate: Option<u64>) -> VideoMetadata {
+        VideoMetadata {
+            filename: filename.into(),
+            container: VideoContainer::MP4,
+            video_codec,
+            audio_codec,
+            audio_is_silent: false,
+            audio_bitrate,
+            resolution_h: 1920,
+            resolution_v: 1080,
+        }
+    }
+
+    fn manifest(video_files: Vec<VideoMetadata>) -> MetadataManifest {
+        MetadataManifest {
+            video_files,
+            audio_files: vec![],
+            text_files: vec![],
+            duration: 60.0,
+            title: "Example".into(),
+            muxed_audio: Some(MuxedAudioMetadata { language: "eng".into(), title: None }),
+            thumbnail: None,
+        }
+    }
+
+    #[test]
+    fn test_build_demux_commands() {
+        let meta = manifest(vec![
+            video("main.mp4", VideoCodec::H264, Some(AudioCodec::AAC), Some(128_000)),
+            video("video0_av1.mp4", VideoCodec::AV1, Some(AudioCodec::Opus), Some(96_000)),
+            video("video0_vp9.mp4", VideoCodec::VP9, Some(AudioCodec::Opus), Some(128_000)),
+        ]);
+        let (commands, new_meta) = build_demux_commands(&FfmpegConfig::default(), &meta, Path::new("/out")).unwrap();
+        assert_eq!(commands.len(), 3);
+        // the 128k tracks are the best, and between those two opus beats aac
+        let audio_output = commands.iter()
+            .position(|x| x.get_args().any(|x| x == "/out/demuxed.ogg"))
+            .unwrap();
+        assert_eq!(audio_output, 2);
+        assert_eq!(new_meta.audio_files.len(), 1);
+        assert_eq!(new_meta.audio_files[0].codec, AudioCodec::Opus);
+        assert!(new_meta.muxed_audio.is_none());
+        assert!(new_meta.video_files.iter().all(|x| x.audio_codec.is_none()));
+        assert_eq!(new_meta.video_files[0].filename, "main_demuxed.mp4");
+        assert_eq!(new_meta.video_files[1].filename, "video0_av1_demuxed.webm");
+        assert_eq!(new_meta.video_files[1].container, VideoContainer::WEBM);
+    }
+
+    #[test]
+    fn test_build_demux_commands_errors() {
+        let config = FfmpegConfig::default();
+        assert_eq!(build_demux_commands(&config, &manifest(vec![]), Path::new("/out")).unwrap_err(), DemuxError::NoVideoFiles);
+
+        let mut meta = manifest(vec![video("main.mp4", VideoCodec::H264, Some(AudioCodec::AAC), None)]);
+        meta.muxed_audio = None;
+        assert_eq!(build_demux_commands(&config, &meta, Path::new("/out")).unwrap_err(), DemuxError::MissingMuxedAudioMetadata);
+
+        let meta = manifest(vec![
+            video("main.mp4", VideoCodec::H264, Some(AudioCodec::AAC), None),
+            video("main_demuxed.mp4", VideoCodec::H264, None, None),
+        ]);
+        assert_eq!(build_demux_commands(&config, &meta, Path::new("/out")).unwrap_err(), DemuxError::FilenameCollision("main_demuxed.mp4".into()));
+
+        // nothing to demux is fine
+        let meta = manifest(vec![video("main.mp4", VideoCodec::H264, None, None)]);
+        let (commands, _) = build_demux_commands(&config, &meta, Path::new("/out")).unwrap();
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn test_default_subtitle_track() {
+        use crate::ffprobe::TrackType::Subtitle;
+        let sub = |index, language: &str, default, forced| Track { language: Some(language.into()), default, forced, ..Track::example(index, Subtitle, "subrip") };
+        let pick = |tracks: &[Track], audio_language: Option<&str>| default_subtitle_track(tracks.iter(), audio_language.map(Into::into));
+
+        // forced beats default
+        assert_eq!(pick(&[sub(2, "eng", true, false), sub(3, "eng", false, true)], None), Some(3));
+        assert_eq!(pick(&[sub(2, "eng", false, false), sub(3, "jpn", true, false)], None), Some(3));
+        // with several marked, the audio's language wins, otherwise the first one does
+        let signs = [sub(2, "eng", false, true), sub(3, "jpn", false, true)];
+        assert_eq!(pick(&signs, Some("jpn")), Some(3));
+        assert_eq!(pick(&signs, Some("fre")), Some(2));
+        assert_eq!(pick(&signs, None), Some(2));
+        // but the language alone doesn't make a track the default
+        assert_eq!(pick(&[sub(2, "eng", false, false), sub(3, "jpn", true, false)], Some("eng")), Some(3));
+        assert_eq!(pick(&[sub(2, "eng", false, false)], Some("eng")), None);
+        assert_eq!(pick(&[], Some("eng")), None);
+    }
+
+    fn base<'a>() -> TranscodeArgs<'a> {
+        TranscodeArgs { title: "Example".into(), ..TranscodeArgs::default() }
+    }
+
+    fn trimmed_args<'a>(video: &'a Track, sub: &'a Track, encoder: &str) -> TranscodeArgs<'a> {
+        TranscodeArgs {
+            video_tracks: vec![TrackOptions::new(video, VideoCodec::H264, encoder.into())],
+            subtitle_tracks: vec![SubtitleOptions::new(sub)],
+            duration: 600.0,
+            trim: Trim { start: Some(60.0), end: Some(TrimEnd::At(90.0)) },
+            ..base()
+        }
+    }
+
+    #[test]
+    fn test_trim_placement() {
+        let video = Track::example(0, Video, "h264");
+        let sub = Track::example(1, Subtitle, "subrip");
+        let config = FfmpegConfig::default();
+
+        // re-encoding seeks the input, once, before -i
+        let (command, meta, _) = build_ffmpeg_command(&config, OsStr::new("in.mkv"), trimmed_args(&video, &sub, "libx264"), Path::new("/out"));
+        let args = args_of(&command);
+        let input = args.iter().position(|x| x == "-i").unwrap();
+        assert_eq!(args[input-4..input], ["-ss", "60", "-t", "30"]);
+        assert_eq!(args.iter().filter(|x| *x == "-ss").count(), 1);
+        assert_eq!(meta.duration, 30.0);
+
+        // copying seeks every output, subtitles included
+        let (command, meta, _) = build_ffmpeg_command(&config, OsStr::new("in.mkv"), trimmed_args(&video, &sub, "copy"), Path::new("/out"));
+        let args = args_of(&command);
+        let input = args.iter().position(|x| x == "-i").unwrap();
+        assert!(args[..input].iter().all(|x| x != "-ss"));
+        assert_eq!(args.iter().filter(|x| *x == "-ss").count(), 2);
+        let sub_output = args.iter().position(|x| x.ends_with(".vtt")).unwrap();
+        assert_eq!(args[sub_output-4..sub_output], ["-ss", "60", "-t", "30"]);
+        assert_eq!(meta.duration, 30.0);
+
+        // generated silence gets seeked along with the video it's muxed into, so there has to be
+        // enough of it to still last the whole 30 seconds
+        let audio = Track::example(2, Audio, "flac");
+        let mut args = trimmed_args(&video, &sub, "copy");
+        args.audio_tracks = vec![TrackOptions::new(&audio, AudioCodec::FLAC, "copy".into())];
+        args.add_muxed_silence = true;
+        args.force_demux_audio = true;
+        let (command, _, _) = build_ffmpeg_command(&config, OsStr::new("in.mkv"), args, Path::new("/out"));
+        let args = args_of(&command);
+        let silence = args.iter().position(|x| x.starts_with("anullsrc")).unwrap();
+        assert_eq!(args[silence-4..silence], ["lavfi", "-t", "90", "-i"]);
+    }
+
+    #[test]
+    fn test_job_ffmpeg_args() {
+        let video = Track::example(0, Video, "h264");
+        let sub = Track::example(1, Subtitle, "subrip");
+        let mut args = trimmed_args(&video, &sub, "copy");
+        args.extra_ffmpeg_args = vec!["-loglevel".into(), "warning".into()];
+        args.input_ffmpeg_args = vec!["-analyzeduration".into(), "100M".into()];
+        args.output_ffmpeg_args = vec!["-map_metadata".into(), "-1".into()];
+        args.video_tracks[0].extra_ffmpeg_args = vec!["-map_metadata".into(), "0".into()];
+        let (command, _, _) = build_concat_command(&FfmpegConfig::default(), Path::new("/tmp/list.txt"), args, Path::new("/out"));
+        let args = args_of(&command);
+        assert_eq!(args[..3], ["-hide_banner", "-loglevel", "warning"]);
+        let input = args.iter().position(|x| x == "-i").unwrap();
+        assert_eq!(args[input-6..input], ["-f", "concat", "-safe", "0", "-analyzeduration", "100M"]);
+        // every output gets the job's output args, and the track's own come after
+        let video_output = args.iter().position(|x| x.ends_with(".mp4")).unwrap();
+        assert_eq!(args[video_output-4..video_output], ["-map_metadata", "-1", "-map_metadata", "0"]);
+        let sub_output = args.iter().position(|x| x.ends_with(".vtt")).unwrap();
+        assert_eq!(args[sub_output-2..sub_output], ["-map_metadata", "-1"]);
+    }
+
+    #[test]
+    fn test_subtitle_sources() {
+        let video = Track::example(0, Video, "h264");
+        let pgs = Track::example(2, Subtitle, "hdmv_pgs_subtitle");
+        let sidecar = Track::example(0, Subtitle, "ass");
+        let config = FfmpegConfig::default();
+
+        let with_sidecar = |encoder| {
+            let mut args = trimmed_args(&video, &sidecar, encoder);
+            args.subtitle_tracks[0].file = Some("/in/Movie.jpn.ass".into());
+            args.burn_subtitles = Some(pgs.index);
+            args
+        };
+        // the sidecar gets its own input
+        let (command, meta, _) = build_ffmpeg_command(&config, OsStr::new("in.mkv"), with_sidecar("copy"), Path::new("/out"));
+        let args = args_of(&command);
+        assert!(args.windows(2).any(|x| x == ["-i", "/in/Movie.jpn.ass"]));
+        assert!(args.windows(2).any(|x| x == ["-map", "1:0"]));
+        assert_eq!(meta.text_files.len(), 1);
+        // copied video can't have anything burned in
+        assert!(!args.iter().any(|x| x == "-filter_complex"));
+
+        let (command, _, _) = build_ffmpeg_command(&config, OsStr::new("in.mkv"), with_sidecar("libx264"), Path::new("/out"));
+        let args = args_of(&command);
+        // with an input seek, the sidecar is seeked the same as the main input
+        let sidecar_input = args.iter().position(|x| x == "/in/Movie.jpn.ass").unwrap();
+        assert_eq!(args[sidecar_input-5..sidecar_input], ["-ss", "60", "-t", "30", "-i"]);
+        assert!(args.windows(2).any(|x| x == ["-filter_complex", "[0:0][0:2]overlay=eof_action=pass[burn0]"]));
+        assert!(args.windows(2).any(|x| x == ["-map", "[burn0]"]));
+        assert!(!args.windows(2).any(|x| x == ["-map", "0:0"]));
+    }
+
+    #[test]
+    fn test_build_concat_command() {
+        let video = Track::example(0, Video, "h264");
+        let sub = Track::example(1, Subtitle, "subrip");
+        let mut args = trimmed_args(&video, &sub, "copy");
+        args.trim = Trim::default();
+        let (command, _, _) = build_concat_command(&FfmpegConfig::default(), Path::new("/tmp/list.txt"), args, Path::new("/out"));
+        let args = args_of(&command);
+        let input = args.iter().position(|x| x == "-i").unwrap();
+        assert_eq!(args[input-4..input+2], ["-f", "concat", "-safe", "0", "-i", "/tmp/list.txt"]);
+    }
+
+    #[test]
+    fn test_thumbnail_input_args() {
+        let input_args = ["-analyzeduration".into(), "100M".into()];
+        let trim = Trim { start: Some(60.0), end: None };
+        let (command, filename) = build_thumbnail_command(&FfmpegConfig::default(), OsStr::new("in.mkv"), &input_args, 0, 600.0, &trim, Path::new("/out"));
+        let args = args_of(&command);
+        let input = args.iter().position(|x| x == "-i").unwrap();
+        assert_eq!(args[input-4..input+2], ["-ss", "114", "-analyzeduration", "100M", "-i", "in.mkv"]);
+        assert_eq!(filename, THUMBNAIL_FILENAME);
+    }
+
+    fn audio_only_args<'a>(audio: &'a Track, codec: AudioCodec) -> TranscodeArgs<'a> {
+        TranscodeArgs {
+            audio_tracks: vec![TrackOptions::new(audio, codec, "copy".into())],
+            duration: 240.0,
+            ..base()
+        }
+    }
+
+    #[test]
+    fn test_audio_only() {
+        let config = FfmpegConfig::default();
+        for (codec, content_type) in [(AudioCodec::FLAC, "audio/ogg"), (AudioCodec::MP3, "audio/mpeg"), (AudioCodec::Opus, "audio/ogg"), (AudioCodec::AAC, "audio/aac")] {
+            let audio = Track::example(0, Audio, codec.as_ref());
+            let (_, meta, _) = build_ffmpeg_command(&config, OsStr::new("in.flac"), audio_only_args(&audio, codec), Path::new("/out"));
+            let cytube = meta.to_cytube("https://example.com/");
+            assert_eq!(cytube.validate(), vec![]);
+            assert_eq!(cytube.sources.len(), 1);
+            assert_eq!(cytube.sources[0].content_type, content_type);
+            assert!(cytube.audio_tracks.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_audio_visual() {
+        let mut cover = Track::example(0, Video, "mjpeg");
+        cover.attached_pic = true;
+        let audio = Track::example(1, Audio, "flac");
+        let mut args = audio_only_args(&audio, AudioCodec::FLAC);
+        args.visual = Some(AudioVisual {
+            background: VisualBackground::CoverArt(cover.index),
+            waveform: true,
+            codec: VideoCodec::VP9,
+            encoder: "libvpx-vp9".into(),
+        });
+        let (command, meta, _) = build_ffmpeg_command(&FfmpegConfig::default(), OsStr::new("in.flac"), args, Path::new("/out"));
+        let args = args_of(&command);
+        let filter = &args[args.iter().position(|x| x == "-filter_complex").unwrap() + 1];
+        assert!(filter.starts_with("[0:0]loop="));
+        assert!(filter.contains("[0:1]showwaves="));
+        assert!(args.windows(2).any(|x| x == ["-map", "[visual]"]));
+        // with something to look at, it's an ordinary video with the audio muxed in
+        assert_eq!(meta.video_files.len(), 1);
+        assert_eq!(meta.video_files[0].resolution_v, 720);
+        assert_eq!(meta.video_files[0].audio_codec, Some(AudioCodec::FLAC));
+        let cytube = meta.to_cytube("https://example.com/");
+        assert_eq!(cytube.validate(), vec![]);
+        assert!(cytube.audio_tracks.is_empty());
+    }
+
+    #[test]
+    fn test_metadata_overrides() {
+        let mut audio = Track::example(1, Audio, "aac");
+        audio.language = Some("jpn".into());
+        let sub = Track::example(2, Subtitle, "subrip");
+        let mut args = audio_only_args(&audio, AudioCodec::AAC);
+        args.audio_tracks[0].overrides = MetadataOverrides { language: Some("eng".into()), title: Some("English dub".into()) };
+        let mut sub_options = SubtitleOptions::new(&sub);
+        sub_options.overrides.language = Some("eng".into());
+        args.subtitle_tracks.push(sub_options);
+        let (command, meta, _) = build_ffmpeg_command(&FfmpegConfig::default(), OsStr::new("in.mkv"), args, Path::new("/out"));
+        let args = args_of(&command);
+        assert!(args.windows(2).any(|x| x == ["-metadata:s:a:0", "language=eng"]));
+        assert!(args.windows(2).any(|x| x == ["-metadata:s:a:0", "title=English dub"]));
+        assert!(args.windows(2).any(|x| x == ["-metadata:s:s:0", "language=eng"]));
+        assert_eq!(meta.audio_files[0].filename, "audio_1_eng.aac");
+        assert_eq!(meta.audio_files[0].language, "eng");
+        assert_eq!(meta.audio_files[0].title.as_deref(), Some("English dub"));
+        assert_eq!(meta.text_files[0].filename, "sub_2_eng.vtt");
+        assert_eq!(meta.text_files[0].language, Some("eng".into()));
+    }
+
+    fn no_capabilities() -> crate::codecs::Capabilities {
+        crate::codecs::Capabilities {
+            video_encoders: vec![],
+            audio_encoders: vec![],
+            decoders: vec![],
+            demuxers: vec![],
+            info: Default::default(),
+            features: vec![],
         }
+    }
 
-        meta.audio_files.push(audio_track);
+    fn with_language(mut track: Track, language: &str) -> Track {
+        track.language = Some(language.into());
+        track
     }
 
-    (commands, meta)
+    #[test]
+    fn test_defaults_policy() {
+        use crate::defaults::Standard;
+        let capabilities = no_capabilities();
+        let mut commentary = with_language(Track::example(4, Audio, "aac"), "jpn");
+        commentary.title = Some("Director's Commentary".into());
+        let probe = FFprobeResult {
+            tracks: vec![
+                Track::example(0, Video, "h264"),
+                with_language(Track::example(1, Audio, "aac"), "eng"),
+                with_language(Track::example(2, Audio, "ac3"), "jpn"),
+                with_language(Track::example(3, Audio, "opus"), "jpn"),
+                commentary,
+                with_language(Track::example(5, Subtitle, "subrip"), "jpn"),
+                with_language(Track::example(6, Subtitle, "ass"), "eng"),
+                with_language(Track::example(7, Subtitle, "hdmv_pgs_subtitle"), "eng"),
+            ],
+            title: None,
+            duration: 60.0,
+            bitrate: 1000,
+            format: None,
+        };
+        let chosen = |args: &TranscodeArgs| (args.audio_tracks.iter().map(|x| x.track.index).collect::<Vec<_>>(),
+                                             args.subtitle_tracks.iter().map(|x| (x.track.index, x.default)).collect::<Vec<_>>());
+
+        // one per language, in file order.  the default policy keeps commentary, but it still
+        // loses to the opus track it's tied with
+        let args = get_defaults(&probe, Path::new("in.mkv"), &capabilities, &DefaultsPolicy::default(), &Standard);
+        assert_eq!(chosen(&args), (vec![1, 3], vec![(5, false), (6, false)]));
+
+        let policy = DefaultsPolicy {
+            audio_languages: vec!["jpn".into(), "eng".into()],
+            subtitle_languages: vec!["eng".into()],
+            keep_commentary: false,
+            max_audio_tracks: Some(1),
+            max_subtitle_tracks: None,
+        };
+        let args = get_defaults(&probe, Path::new("in.mkv"), &capabilities, &policy, &Standard);
+        // the opus track fits in an MP4 next to the H.264 and the AC-3 doesn't; the English
+        // subtitles go on because the audio is Japanese
+        assert_eq!(chosen(&args), (vec![3], vec![(6, true)]));
+
+        // none of the preferred languages, so it's as if there were no preferences
+        let policy = DefaultsPolicy { audio_languages: vec!["fre".into()], ..Default::default() };
+        assert_eq!(chosen(&get_defaults(&probe, Path::new("in.mkv"), &capabilities, &policy, &Standard)).0, vec![1, 3]);
+
+        // but with no other Japanese track, the commentary is better than nothing
+        let mut probe = probe;
+        probe.tracks.retain(|x| !matches!(x.index, 2 | 3));
+        assert_eq!(chosen(&get_defaults(&probe, Path::new("in.mkv"), &capabilities, &DefaultsPolicy::default(), &Standard)).0, vec![1, 4]);
+    }
+
+    #[test]
+    fn test_defaults_strategies() {
+        use crate::defaults::{find_strategy, Av1, Compatible, CopyAll};
+        use crate::codecs::get_encoder_names;
+        // straight out of `ffmpeg -codecs`.  the native aac encoder doesn't get listed, since it
+        // has the codec's name
+        const CODECS: &str = "
+ DEV.L. av1                  Alliance for Open Media AV1 (decoders: libdav1d av1 ) (encoders: libaom-av1 )
+ DEA.L. aac                  AAC (Advanced Audio Coding) (decoders: aac aac_fixed )
+ DEA.L. opus                 Opus (Opus Interactive Audio Codec) (decoders: opus libopus ) (encoders: opus libopus )
+";
+        let mut capabilities = no_capabilities();
+        capabilities.video_encoders = get_encoder_names(CODECS, VideoCodec::iter().collect());
+        capabilities.audio_encoders = get_encoder_names(CODECS, AudioCodec::iter().collect());
+        let probe = FFprobeResult {
+            tracks: vec![
+                Track::example(0, Video, "h264"),
+                with_language(Track::example(1, Audio, "opus"), "eng"),
+                with_language(Track::example(2, Audio, "ac3"), "eng"),
+                with_language(Track::example(3, Audio, "aac"), "jpn"),
+            ],
+            title: None,
+            duration: 60.0,
+            bitrate: 1000,
+            format: None,
+        };
+        let codecs = |args: &TranscodeArgs| (
+            args.video_tracks.iter().map(|x| (x.codec, x.encoder.clone())).collect::<Vec<_>>(),
+            args.audio_tracks.iter().map(|x| (x.track.index, x.codec, x.encoder.clone())).collect::<Vec<_>>(),
+        );
+        let defaults = |name| get_defaults(&probe, Path::new("in.mkv"), &capabilities, &DefaultsPolicy::default(), find_strategy(name).unwrap());
+
+        assert_eq!(codecs(&defaults("standard")), (
+            vec![(VideoCodec::H264, "copy".into())],
+            vec![(1, AudioCodec::Opus, "copy".into()), (3, AudioCodec::AAC, "copy".into())],
+        ));
+        assert_eq!(codecs(&defaults("av1")), (
+            vec![(VideoCodec::AV1, "libaom-av1".into())],
+            vec![(1, AudioCodec::Opus, "copy".into()), (3, AudioCodec::Opus, "libopus".into())],
+        ));
+        assert_eq!(codecs(&defaults("compatible")).1, vec![(1, AudioCodec::AAC, "aac".into()), (3, AudioCodec::AAC, "copy".into())]);
+        // the AC-3 track gets kept too, and since browsers can't play it it gets encoded
+        assert_eq!(codecs(&get_defaults(&probe, Path::new("in.mkv"), &capabilities, &DefaultsPolicy::default(), &CopyAll)).1, vec![
+            (1, AudioCodec::Opus, "copy".into()), (2, AudioCodec::AAC, "aac".into()), (3, AudioCodec::AAC, "copy".into()),
+        ]);
+        // without libx264 or an AAC encoder, compatible does what it can with what there is
+        let hevc = Track::example(0, Video, "hevc");
+        assert_eq!(Compatible.video_encoder(&hevc, &capabilities), (VideoCodec::AV1, "libaom-av1".into()));
+        assert_eq!(Compatible.audio_encoder(&probe.tracks[1], Some(VideoCodec::H264), &no_capabilities()), (AudioCodec::Opus, "copy".into()));
+        assert_eq!(Compatible.audio_encoder(&probe.tracks[2], Some(VideoCodec::H264), &no_capabilities()), (AudioCodec::AAC, "aac".into()));
+        // and av1 without libopus
+        assert_eq!(Av1.audio_encoder(&probe.tracks[3], Some(VideoCodec::AV1), &no_capabilities()), (AudioCodec::AAC, "copy".into()));
+        assert_eq!(Av1.audio_encoder(&probe.tracks[2], Some(VideoCodec::AV1), &no_capabilities()), (AudioCodec::Opus, "libopus".into()));
+        assert!(find_strategy("fastest").is_none());
+    }
 }